@@ -8,6 +8,8 @@ use std::time::Duration;
 use crossbeam::scope;
 use crossbeam::sync::MsQueue;
 use crossbeam::sync::SegQueue;
+use crossbeam::sync::ArrayQueue;
+use crossbeam::sync::{TreiberStack, EliminationStack};
 
 use extra_impls::mpsc_queue::Queue as MpscQueue;
 
@@ -47,6 +49,16 @@ impl<T> Queue<T> for SegQueue<T> {
     fn try_pop(&self) -> Option<T> { self.try_pop() }
 }
 
+impl<T> Queue<T> for ArrayQueue<T> {
+    fn push(&self, t: T) {
+        let mut t = t;
+        while let Err(back) = self.try_push(t) {
+            t = back;
+        }
+    }
+    fn try_pop(&self) -> Option<T> { self.try_pop() }
+}
+
 impl<T> Queue<T> for MpscQueue<T> {
     fn push(&self, t: T) { self.push(t) }
     fn try_pop(&self) -> Option<T> {
@@ -67,6 +79,21 @@ impl<T> Queue<T> for Mutex<VecDeque<T>> {
     fn try_pop(&self) -> Option<T> { self.lock().unwrap().pop_front() }
 }
 
+trait Stack<T> {
+    fn push(&self, t: T);
+    fn try_pop(&self) -> Option<T>;
+}
+
+impl<T> Stack<T> for TreiberStack<T> {
+    fn push(&self, t: T) { self.push(t) }
+    fn try_pop(&self) -> Option<T> { self.try_pop() }
+}
+
+impl<T> Stack<T> for EliminationStack<T> {
+    fn push(&self, t: T) { self.push(t) }
+    fn try_pop(&self) -> Option<T> { self.try_pop() }
+}
+
 fn bench_queue_mpsc<Q: Queue<u64> + Sync>(q: Q) -> f64 {
     let d = time(|| {
         scope(|scope| {
@@ -126,6 +153,38 @@ fn bench_queue_mpmc<Q: Queue<bool> + Sync>(q: Q) -> f64 {
     nanos(d) / ((COUNT * THREADS) as f64)
 }
 
+// A stack pops the most recent push first, so an end-of-stream sentinel
+// would come out early: the poppers count items instead.
+fn bench_stack_mpmc<S: Stack<u64> + Sync>(s: S) -> f64 {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::Relaxed;
+
+    let pop_count = AtomicUsize::new(0);
+
+    let d = time(|| {
+        scope(|scope| {
+            for _i in 0..THREADS {
+                let sr = &s;
+                let pcr = &pop_count;
+                scope.spawn(move || {
+                    for x in 0..COUNT {
+                        sr.push(x);
+                    }
+                });
+                scope.spawn(move || {
+                    while pcr.load(Relaxed) < (COUNT * THREADS) as usize {
+                        if sr.try_pop().is_some() {
+                            pcr.fetch_add(1, Relaxed);
+                        }
+                    }
+                });
+            }
+        });
+    });
+
+    nanos(d) / ((COUNT * THREADS) as f64)
+}
+
 fn bench_chan_mpsc() -> f64 {
     let (tx, rx) = channel();
 
@@ -155,9 +214,13 @@ fn main() {
     println!("chan mpsc: {}", bench_chan_mpsc());
     println!("mpsc mpsc: {}", bench_queue_mpsc(MpscQueue::new()));
     println!("Seg mpsc: {}", bench_queue_mpsc(SegQueue::new()));
+    println!("Array mpsc: {}", bench_queue_mpsc(ArrayQueue::new(1024)));
 
     println!("MSQ mpmc: {}", bench_queue_mpmc(MsQueue::new()));
     println!("Seg mpmc: {}", bench_queue_mpmc(SegQueue::new()));
+    println!("Array mpmc: {}", bench_queue_mpmc(ArrayQueue::new(1024)));
+    println!("Treiber mpmc: {}", bench_stack_mpmc(TreiberStack::new()));
+    println!("Elimination mpmc: {}", bench_stack_mpmc(EliminationStack::new()));
 
 //    println!("queue_mpsc: {}", bench_queue_mpsc());
 //    println!("queue_mpmc: {}", bench_queue_mpmc());
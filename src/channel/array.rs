@@ -0,0 +1,141 @@
+// The bounded flavor: an `ArrayQueue` plus the senders waiting for room and
+// the receivers waiting for messages.
+
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::SeqCst;
+use std::time::Instant;
+
+use channel::context::{Context, ABORTED, FIRST_OPER};
+use channel::error::{TrySendError, SendTimeoutError, TryRecvError, RecvTimeoutError};
+use channel::waker::Waker;
+use sync::ArrayQueue;
+
+#[derive(Debug)]
+pub struct Channel<T> {
+    queue: ArrayQueue<T>,
+    senders: Waker,
+    receivers: Waker,
+    disconnected: AtomicBool,
+}
+
+impl<T> Channel<T> {
+    pub fn new(cap: usize) -> Channel<T> {
+        Channel {
+            queue: ArrayQueue::new(cap),
+            senders: Waker::new(),
+            receivers: Waker::new(),
+            disconnected: AtomicBool::new(false),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.queue.capacity()
+    }
+
+    pub fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
+        if self.disconnected.load(SeqCst) {
+            return Err(TrySendError::Disconnected(t));
+        }
+        match self.queue.try_push(t) {
+            Ok(()) => {
+                self.receivers.notify();
+                Ok(())
+            }
+            Err(t) => Err(TrySendError::Full(t)),
+        }
+    }
+
+    pub fn send(&self, mut t: T, deadline: Option<Instant>) -> Result<(), SendTimeoutError<T>> {
+        loop {
+            match self.try_send(t) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Disconnected(t)) => return Err(SendTimeoutError::Disconnected(t)),
+                Err(TrySendError::Full(back)) => t = back,
+            }
+            if let Some(deadline) = deadline {
+                if Instant::now() >= deadline {
+                    return Err(SendTimeoutError::Timeout(t));
+                }
+            }
+
+            let cx = Context::new();
+            self.senders.register(FIRST_OPER, &cx);
+            if self.can_send() {
+                let _ = cx.try_select(ABORTED);
+            }
+            if cx.wait_until(deadline) != FIRST_OPER {
+                self.senders.unregister(FIRST_OPER, &cx);
+            }
+        }
+    }
+
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let t = match self.queue.try_pop() {
+            Some(t) => t,
+            None if self.disconnected.load(SeqCst) => {
+                // messages sent just before the disconnect are still delivered
+                try!(self.queue.try_pop().ok_or(TryRecvError::Disconnected))
+            }
+            None => return Err(TryRecvError::Empty),
+        };
+        self.senders.notify();
+        Ok(t)
+    }
+
+    pub fn recv(&self, deadline: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        loop {
+            match self.try_recv() {
+                Ok(t) => return Ok(t),
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Empty) => {}
+            }
+            if let Some(deadline) = deadline {
+                if Instant::now() >= deadline {
+                    return Err(RecvTimeoutError::Timeout);
+                }
+            }
+
+            let cx = Context::new();
+            self.receivers.register(FIRST_OPER, &cx);
+            if self.can_recv() {
+                let _ = cx.try_select(ABORTED);
+            }
+            if cx.wait_until(deadline) != FIRST_OPER {
+                self.receivers.unregister(FIRST_OPER, &cx);
+            }
+        }
+    }
+
+    /// Would `try_send` return something other than `Full`?
+    pub fn can_send(&self) -> bool {
+        !self.queue.is_full() || self.disconnected.load(SeqCst)
+    }
+
+    /// Would `try_recv` return something other than `Empty`?
+    pub fn can_recv(&self) -> bool {
+        !self.queue.is_empty() || self.disconnected.load(SeqCst)
+    }
+
+    pub fn register_send(&self, oper: usize, cx: &Context) {
+        self.senders.register(oper, cx);
+    }
+
+    pub fn unregister_send(&self, oper: usize, cx: &Context) {
+        self.senders.unregister(oper, cx);
+    }
+
+    pub fn register_recv(&self, oper: usize, cx: &Context) {
+        self.receivers.register(oper, cx);
+    }
+
+    pub fn unregister_recv(&self, oper: usize, cx: &Context) {
+        self.receivers.unregister(oper, cx);
+    }
+
+    pub fn disconnect(&self) {
+        if !self.disconnected.swap(true, SeqCst) {
+            self.senders.disconnect();
+            self.receivers.disconnect();
+        }
+    }
+}
@@ -0,0 +1,96 @@
+// The state a blocked operation shares with the threads that may wake it.
+//
+// A blocked thread registers its `Context` with one or more channels, then
+// parks. Whoever wants to wake it must first *select* the context, atomically
+// moving it out of the `WAITING` state; only the winner of that race may act
+// on the blocked thread (e.g. hand it a message), and everyone else has to
+// leave it alone. The blocked thread itself selects `ABORTED` when it gives up.
+
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::{AcqRel, Acquire};
+use std::thread::{self, Thread};
+use std::time::Instant;
+
+/// Nothing has been selected yet.
+pub const WAITING: usize = 0;
+
+/// The blocked thread gave up, usually because its deadline passed.
+pub const ABORTED: usize = 1;
+
+/// A channel the thread was blocked on got disconnected.
+pub const DISCONNECTED: usize = 2;
+
+/// The smallest id that names an operation; a plain blocking `send` or `recv`
+/// registers under this one.
+pub const FIRST_OPER: usize = 3;
+
+#[derive(Clone, Debug)]
+pub struct Context {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    select: AtomicUsize,
+    thread: Thread,
+}
+
+impl Context {
+    /// Create a fresh context for the current thread.
+    pub fn new() -> Context {
+        Context {
+            inner: Arc::new(Inner {
+                select: AtomicUsize::new(WAITING),
+                thread: thread::current(),
+            }),
+        }
+    }
+
+    /// Attempt to select `sel`, returning what was already selected on failure.
+    pub fn try_select(&self, sel: usize) -> Result<(), usize> {
+        self.inner.select.compare_exchange(WAITING, sel, AcqRel, Acquire).map(|_| ())
+    }
+
+    /// What has been selected so far, `WAITING` if nothing.
+    pub fn selected(&self) -> usize {
+        self.inner.select.load(Acquire)
+    }
+
+    /// Are `self` and `other` handles to the same context?
+    pub fn same(&self, other: &Context) -> bool {
+        &*self.inner as *const Inner == &*other.inner as *const Inner
+    }
+
+    /// Wake up the thread owning this context.
+    pub fn unpark(&self) {
+        self.inner.thread.unpark();
+    }
+
+    /// Park until something gets selected, or until `deadline` passes, in
+    /// which case this attempts to select `ABORTED`.
+    ///
+    /// Returns the final selection.
+    pub fn wait_until(&self, deadline: Option<Instant>) -> usize {
+        loop {
+            let sel = self.selected();
+            if sel != WAITING {
+                return sel;
+            }
+
+            match deadline {
+                None => thread::park(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return match self.try_select(ABORTED) {
+                            Ok(()) => ABORTED,
+                            Err(sel) => sel,
+                        };
+                    }
+                    thread::park_timeout(deadline - now);
+                }
+            }
+        }
+    }
+}
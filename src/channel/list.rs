@@ -0,0 +1,99 @@
+// The unbounded flavor: a `SegQueue` plus the receivers waiting on it.
+// Senders never block.
+
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::SeqCst;
+use std::time::Instant;
+
+use channel::context::{Context, ABORTED, FIRST_OPER};
+use channel::error::{TrySendError, SendTimeoutError, TryRecvError, RecvTimeoutError};
+use channel::waker::Waker;
+use sync::SegQueue;
+
+#[derive(Debug)]
+pub struct Channel<T> {
+    queue: SegQueue<T>,
+    receivers: Waker,
+    disconnected: AtomicBool,
+}
+
+impl<T> Channel<T> {
+    pub fn new() -> Channel<T> {
+        Channel {
+            queue: SegQueue::new(),
+            receivers: Waker::new(),
+            disconnected: AtomicBool::new(false),
+        }
+    }
+
+    pub fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
+        if self.disconnected.load(SeqCst) {
+            return Err(TrySendError::Disconnected(t));
+        }
+        self.queue.push(t);
+        self.receivers.notify();
+        Ok(())
+    }
+
+    pub fn send(&self, t: T, _deadline: Option<Instant>) -> Result<(), SendTimeoutError<T>> {
+        self.try_send(t).map_err(|err| match err {
+            TrySendError::Disconnected(t) => SendTimeoutError::Disconnected(t),
+            TrySendError::Full(_) => unreachable!(),
+        })
+    }
+
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        if let Some(t) = self.queue.try_pop() {
+            return Ok(t);
+        }
+        if self.disconnected.load(SeqCst) {
+            // messages sent just before the disconnect are still delivered
+            self.queue.try_pop().ok_or(TryRecvError::Disconnected)
+        } else {
+            Err(TryRecvError::Empty)
+        }
+    }
+
+    pub fn recv(&self, deadline: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        loop {
+            match self.try_recv() {
+                Ok(t) => return Ok(t),
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Empty) => {}
+            }
+            if let Some(deadline) = deadline {
+                if Instant::now() >= deadline {
+                    return Err(RecvTimeoutError::Timeout);
+                }
+            }
+
+            let cx = Context::new();
+            self.receivers.register(FIRST_OPER, &cx);
+            if self.can_recv() {
+                let _ = cx.try_select(ABORTED);
+            }
+            if cx.wait_until(deadline) != FIRST_OPER {
+                self.receivers.unregister(FIRST_OPER, &cx);
+            }
+        }
+    }
+
+    /// Would `try_recv` return something other than `Empty`?
+    pub fn can_recv(&self) -> bool {
+        !self.queue.is_empty() || self.disconnected.load(SeqCst)
+    }
+
+    pub fn register_recv(&self, oper: usize, cx: &Context) {
+        self.receivers.register(oper, cx);
+    }
+
+    pub fn unregister_recv(&self, oper: usize, cx: &Context) {
+        self.receivers.unregister(oper, cx);
+    }
+
+    pub fn disconnect(&self) {
+        if !self.disconnected.swap(true, SeqCst) {
+            self.receivers.disconnect();
+        }
+    }
+}
@@ -0,0 +1,612 @@
+//! Multi-producer, multi-consumer channels.
+//!
+//! A channel is created as a pair of a `Sender` and a `Receiver`. Both
+//! halves can be cloned and shared between any number of threads; a message
+//! sent is received by exactly one of the receivers.
+//!
+//! There are three flavors of channels:
+//!
+//! - `unbounded()` creates a channel that can hold any number of messages, so
+//!   sending never blocks.
+//! - `bounded(cap)` creates a channel that holds at most `cap` messages;
+//!   sending into a full channel blocks until a receiver makes room.
+//! - `bounded(0)` creates a *rendezvous* channel, which holds no messages at
+//!   all: every send blocks until a receiver takes the message from it.
+//!
+//! When every `Sender` is dropped, the channel becomes *disconnected*:
+//! receivers still get the messages already in it, after which receiving
+//! fails instead of blocking. Likewise, sending fails once every `Receiver`
+//! is dropped, handing the message back.
+//!
+//! To wait on several operations at once, possibly on different channels,
+//! use the `select!` macro or its dynamic counterpart, `Select`. Popping from
+//! an `MsQueue` or a `SegQueue` can take part in a selection as well.
+//!
+//! # Example
+//!
+//! ```
+//! use crossbeam::channel;
+//! use std::thread;
+//!
+//! let (tx, rx) = channel::bounded(4);
+//!
+//! let producers = (0..4).map(|i| {
+//!     let tx = tx.clone();
+//!     thread::spawn(move || tx.send(i).unwrap())
+//! }).collect::<Vec<_>>();
+//! drop(tx);
+//!
+//! for p in producers {
+//!     p.join().unwrap();
+//! }
+//!
+//! // the iterator ends once every sender is gone and the channel is empty
+//! let mut all = rx.iter().collect::<Vec<_>>();
+//! all.sort();
+//! assert_eq!(all, [0, 1, 2, 3]);
+//! ```
+
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::{AcqRel, Relaxed};
+use std::time::{Duration, Instant};
+
+pub use self::error::{SendError, TrySendError, SendTimeoutError};
+pub use self::error::{RecvError, TryRecvError, RecvTimeoutError};
+pub use self::select::{Select, SelectQueue};
+
+mod array;
+pub(crate) mod context;
+mod error;
+mod list;
+#[macro_use]
+mod select;
+pub(crate) mod waker;
+mod zero;
+
+/// Create a channel of unbounded capacity.
+pub fn unbounded<T>() -> (Sender<T>, Receiver<T>) {
+    new_pair(Flavor::List(list::Channel::new()))
+}
+
+/// Create a channel that holds at most `cap` messages.
+///
+/// A capacity of zero creates a rendezvous channel, in which each send waits
+/// for a receive to take its message.
+pub fn bounded<T>(cap: usize) -> (Sender<T>, Receiver<T>) {
+    if cap == 0 {
+        new_pair(Flavor::Zero(zero::Channel::new()))
+    } else {
+        new_pair(Flavor::Array(array::Channel::new(cap)))
+    }
+}
+
+fn new_pair<T>(flavor: Flavor<T>) -> (Sender<T>, Receiver<T>) {
+    let chan = Arc::new(Channel {
+        flavor: flavor,
+        senders: AtomicUsize::new(1),
+        receivers: AtomicUsize::new(1),
+    });
+    (Sender { chan: chan.clone() }, Receiver { chan: chan })
+}
+
+/// The state shared by all the senders and receivers of a channel.
+#[derive(Debug)]
+struct Channel<T> {
+    flavor: Flavor<T>,
+
+    /// Number of live `Sender`s; the channel disconnects when it drops to 0.
+    senders: AtomicUsize,
+
+    /// Number of live `Receiver`s; the channel disconnects when it drops to 0.
+    receivers: AtomicUsize,
+}
+
+#[derive(Debug)]
+enum Flavor<T> {
+    List(list::Channel<T>),
+    Array(array::Channel<T>),
+    Zero(zero::Channel<T>),
+}
+
+impl<T> Flavor<T> {
+    fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
+        match *self {
+            Flavor::List(ref chan) => chan.try_send(t),
+            Flavor::Array(ref chan) => chan.try_send(t),
+            Flavor::Zero(ref chan) => chan.try_send(t),
+        }
+    }
+
+    fn send(&self, t: T, deadline: Option<Instant>) -> Result<(), SendTimeoutError<T>> {
+        match *self {
+            Flavor::List(ref chan) => chan.send(t, deadline),
+            Flavor::Array(ref chan) => chan.send(t, deadline),
+            Flavor::Zero(ref chan) => chan.send(t, deadline),
+        }
+    }
+
+    fn try_recv(&self) -> Result<T, TryRecvError> {
+        match *self {
+            Flavor::List(ref chan) => chan.try_recv(),
+            Flavor::Array(ref chan) => chan.try_recv(),
+            Flavor::Zero(ref chan) => chan.try_recv(),
+        }
+    }
+
+    fn recv(&self, deadline: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        match *self {
+            Flavor::List(ref chan) => chan.recv(deadline),
+            Flavor::Array(ref chan) => chan.recv(deadline),
+            Flavor::Zero(ref chan) => chan.recv(deadline),
+        }
+    }
+
+    fn capacity(&self) -> Option<usize> {
+        match *self {
+            Flavor::List(_) => None,
+            Flavor::Array(ref chan) => Some(chan.capacity()),
+            Flavor::Zero(_) => Some(0),
+        }
+    }
+
+    fn disconnect(&self) {
+        match *self {
+            Flavor::List(ref chan) => chan.disconnect(),
+            Flavor::Array(ref chan) => chan.disconnect(),
+            Flavor::Zero(ref chan) => chan.disconnect(),
+        }
+    }
+}
+
+/// The sending half of a channel.
+pub struct Sender<T> {
+    chan: Arc<Channel<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Attempt to send `t` without blocking.
+    ///
+    /// Fails if the channel is full (for a rendezvous channel: if no receiver
+    /// is waiting), or if it is disconnected.
+    pub fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
+        self.chan.flavor.try_send(t)
+    }
+
+    /// Send `t`, blocking while the channel is full.
+    ///
+    /// Fails only if the channel is disconnected.
+    pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+        self.chan.flavor.send(t, None).map_err(|err| match err {
+            SendTimeoutError::Disconnected(t) => SendError(t),
+            SendTimeoutError::Timeout(_) => unreachable!(),
+        })
+    }
+
+    /// Send `t`, blocking for at most `timeout` while the channel is full.
+    pub fn send_timeout(&self, t: T, timeout: Duration) -> Result<(), SendTimeoutError<T>> {
+        self.chan.flavor.send(t, Some(Instant::now() + timeout))
+    }
+
+    /// The maximum number of messages the channel can hold, or `None` if it
+    /// is unbounded.
+    pub fn capacity(&self) -> Option<usize> {
+        self.chan.flavor.capacity()
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.chan.senders.fetch_add(1, Relaxed);
+        Sender { chan: self.chan.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.chan.senders.fetch_sub(1, AcqRel) == 1 {
+            self.chan.flavor.disconnect();
+        }
+    }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Sender {{ ... }}")
+    }
+}
+
+/// The receiving half of a channel.
+pub struct Receiver<T> {
+    chan: Arc<Channel<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Attempt to receive a message without blocking.
+    ///
+    /// Fails if the channel is empty (for a rendezvous channel: if no sender
+    /// is waiting), or if it is empty and disconnected.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        self.chan.flavor.try_recv()
+    }
+
+    /// Receive a message, blocking while the channel is empty.
+    ///
+    /// Fails only if the channel is empty and disconnected.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        self.chan.flavor.recv(None).map_err(|err| match err {
+            RecvTimeoutError::Disconnected => RecvError,
+            RecvTimeoutError::Timeout => unreachable!(),
+        })
+    }
+
+    /// Receive a message, blocking for at most `timeout` while the channel is
+    /// empty.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.chan.flavor.recv(Some(Instant::now() + timeout))
+    }
+
+    /// The maximum number of messages the channel can hold, or `None` if it
+    /// is unbounded.
+    pub fn capacity(&self) -> Option<usize> {
+        self.chan.flavor.capacity()
+    }
+
+    /// A blocking iterator over received messages, ending when the channel
+    /// is disconnected.
+    pub fn iter<'a>(&'a self) -> Iter<'a, T> {
+        Iter { rx: self }
+    }
+
+    /// A non-blocking iterator over the messages currently available.
+    pub fn try_iter<'a>(&'a self) -> TryIter<'a, T> {
+        TryIter { rx: self }
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Receiver<T> {
+        self.chan.receivers.fetch_add(1, Relaxed);
+        Receiver { chan: self.chan.clone() }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        if self.chan.receivers.fetch_sub(1, AcqRel) == 1 {
+            self.chan.flavor.disconnect();
+        }
+    }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Receiver {{ ... }}")
+    }
+}
+
+/// Blocking iterator over messages; see `Receiver::iter`.
+#[derive(Debug)]
+pub struct Iter<'a, T: 'a> {
+    rx: &'a Receiver<T>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = T;
+    fn next(&mut self) -> Option<T> {
+        self.rx.recv().ok()
+    }
+}
+
+/// Non-blocking iterator over messages; see `Receiver::try_iter`.
+#[derive(Debug)]
+pub struct TryIter<'a, T: 'a> {
+    rx: &'a Receiver<T>,
+}
+
+impl<'a, T> Iterator for TryIter<'a, T> {
+    type Item = T;
+    fn next(&mut self) -> Option<T> {
+        self.rx.try_recv().ok()
+    }
+}
+
+/// Owning blocking iterator over messages.
+#[derive(Debug)]
+pub struct IntoIter<T> {
+    rx: Receiver<T>,
+}
+
+impl<T> Iterator for IntoIter<T> {
+    type Item = T;
+    fn next(&mut self) -> Option<T> {
+        self.rx.recv().ok()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a Receiver<T> {
+    type Item = T;
+    type IntoIter = Iter<'a, T>;
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
+impl<T> IntoIterator for Receiver<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+    fn into_iter(self) -> IntoIter<T> {
+        IntoIter { rx: self }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use scope;
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    /// One channel of each flavor.
+    fn all_flavors() -> Vec<(Sender<usize>, Receiver<usize>)> {
+        vec![unbounded(), bounded(1), bounded(5), bounded(0)]
+    }
+
+    #[test]
+    fn smoke() {
+        for (tx, rx) in vec![unbounded(), bounded(1)] {
+            tx.send(7).unwrap();
+            assert_eq!(rx.try_recv(), Ok(7));
+            tx.send(8).unwrap();
+            assert_eq!(rx.recv(), Ok(8));
+            assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+            assert_eq!(rx.recv_timeout(ms(10)), Err(RecvTimeoutError::Timeout));
+        }
+    }
+
+    #[test]
+    fn capacity() {
+        assert_eq!(unbounded::<()>().0.capacity(), None);
+        for cap in 0..5 {
+            let (tx, rx) = bounded::<()>(cap);
+            assert_eq!(tx.capacity(), Some(cap));
+            assert_eq!(rx.capacity(), Some(cap));
+        }
+    }
+
+    #[test]
+    fn bounded_full() {
+        let (tx, rx) = bounded(2);
+        assert_eq!(tx.try_send(1), Ok(()));
+        assert_eq!(tx.try_send(2), Ok(()));
+        assert_eq!(tx.try_send(3), Err(TrySendError::Full(3)));
+        assert_eq!(tx.send_timeout(3, ms(10)), Err(SendTimeoutError::Timeout(3)));
+        assert_eq!(rx.recv(), Ok(1));
+        assert_eq!(tx.try_send(3), Ok(()));
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), [2, 3]);
+    }
+
+    #[test]
+    fn bounded_send_blocks() {
+        let (tx, rx) = bounded(1);
+        tx.send(1).unwrap();
+
+        scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(ms(50));
+                assert_eq!(rx.recv(), Ok(1));
+            });
+
+            let start = Instant::now();
+            tx.send(2).unwrap();
+            assert!(start.elapsed() >= ms(40));
+        });
+        assert_eq!(rx.recv(), Ok(2));
+    }
+
+    #[test]
+    fn rendezvous() {
+        let (tx, rx) = bounded(0);
+        assert_eq!(tx.try_send(1), Err(TrySendError::Full(1)));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        assert_eq!(tx.send_timeout(1, ms(10)), Err(SendTimeoutError::Timeout(1)));
+        assert_eq!(rx.recv_timeout(ms(10)), Err(RecvTimeoutError::Timeout));
+
+        scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(ms(50));
+                assert_eq!(rx.recv(), Ok(1));
+                assert_eq!(rx.recv(), Ok(2));
+            });
+
+            // the send only completes once the receiver takes the message
+            let start = Instant::now();
+            tx.send(1).unwrap();
+            assert!(start.elapsed() >= ms(40));
+            tx.send(2).unwrap();
+        });
+    }
+
+    #[test]
+    fn rendezvous_try_with_waiting_peer() {
+        let (tx, rx) = bounded(0);
+
+        scope(|scope| {
+            scope.spawn(|| {
+                assert_eq!(rx.recv(), Ok(1));
+            });
+            loop {
+                match tx.try_send(1) {
+                    Ok(()) => break,
+                    Err(TrySendError::Full(_)) => thread::yield_now(),
+                    Err(TrySendError::Disconnected(_)) => panic!(),
+                }
+            }
+        });
+
+        scope(|scope| {
+            scope.spawn(|| {
+                tx.send(2).unwrap();
+            });
+            loop {
+                match rx.try_recv() {
+                    Ok(t) => {
+                        assert_eq!(t, 2);
+                        break;
+                    }
+                    Err(TryRecvError::Empty) => thread::yield_now(),
+                    Err(TryRecvError::Disconnected) => panic!(),
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn disconnect_senders() {
+        for (tx, rx) in all_flavors() {
+            let tx2 = tx.clone();
+            if tx.capacity() != Some(0) {
+                tx.send(1).unwrap();
+            }
+            drop(tx);
+            drop(tx2);
+            if rx.capacity() != Some(0) {
+                // messages sent before the disconnect are still received
+                assert_eq!(rx.recv(), Ok(1));
+            }
+            assert_eq!(rx.recv(), Err(RecvError));
+            assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+            assert_eq!(rx.recv_timeout(ms(10)), Err(RecvTimeoutError::Disconnected));
+        }
+    }
+
+    #[test]
+    fn disconnect_receivers() {
+        for (tx, rx) in all_flavors() {
+            let rx2 = rx.clone();
+            drop(rx);
+            drop(rx2);
+            assert_eq!(tx.send(1), Err(SendError(1)));
+            assert_eq!(tx.try_send(2), Err(TrySendError::Disconnected(2)));
+            assert_eq!(tx.send_timeout(3, ms(10)), Err(SendTimeoutError::Disconnected(3)));
+        }
+    }
+
+    #[test]
+    fn disconnect_wakes_blocked() {
+        for (tx, rx) in all_flavors() {
+            scope(|scope| {
+                scope.spawn(|| {
+                    assert_eq!(rx.recv(), Err(RecvError));
+                });
+                thread::sleep(ms(50));
+                drop(tx);
+            });
+        }
+
+        for (tx, rx) in vec![bounded(1), bounded(0)] {
+            if tx.capacity() != Some(0) {
+                tx.send(0).unwrap();
+            }
+            scope(|scope| {
+                scope.spawn(|| {
+                    assert_eq!(tx.send(1), Err(SendError(1)));
+                });
+                thread::sleep(ms(50));
+                drop(rx);
+            });
+        }
+    }
+
+    #[test]
+    fn recv_timeout_then_send() {
+        for (tx, rx) in all_flavors() {
+            scope(|scope| {
+                scope.spawn(|| {
+                    assert_eq!(rx.recv_timeout(ms(10)), Err(RecvTimeoutError::Timeout));
+                    assert_eq!(rx.recv_timeout(ms(5000)), Ok(1));
+                });
+                thread::sleep(ms(50));
+                tx.send(1).unwrap();
+            });
+        }
+    }
+
+    #[test]
+    fn iter_until_disconnected() {
+        for (tx, rx) in all_flavors() {
+            scope(|scope| {
+                scope.spawn(move || {
+                    for i in 0..100 {
+                        tx.send(i).unwrap();
+                    }
+                });
+                assert_eq!(rx.iter().collect::<Vec<_>>(), (0..100).collect::<Vec<_>>());
+            });
+        }
+    }
+
+    #[test]
+    fn mpmc() {
+        const THREADS: usize = 4;
+        const COUNT: usize = 10000;
+
+        for (tx, rx) in all_flavors() {
+            let sum = AtomicUsize::new(0);
+            scope(|scope| {
+                for _ in 0..THREADS {
+                    let tx = tx.clone();
+                    scope.spawn(move || {
+                        for i in 0..COUNT {
+                            tx.send(i).unwrap();
+                        }
+                    });
+                    let rx = rx.clone();
+                    let sum = &sum;
+                    scope.spawn(move || {
+                        for i in rx {
+                            sum.fetch_add(i, SeqCst);
+                        }
+                    });
+                }
+                drop(tx);
+                drop(rx);
+            });
+            assert_eq!(sum.load(SeqCst), THREADS * COUNT * (COUNT - 1) / 2);
+        }
+    }
+
+    #[test]
+    fn drop_pending_messages() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        struct Elem;
+        impl Drop for Elem {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, SeqCst);
+            }
+        }
+
+        let (tx, rx) = unbounded();
+        for _ in 0..50 {
+            tx.send(Elem).unwrap();
+        }
+        let (tx2, rx2) = bounded(10);
+        for _ in 0..10 {
+            tx2.send(Elem).unwrap();
+        }
+        drop(rx);
+        drop(rx2);
+        assert_eq!(DROPS.load(SeqCst), 0);
+        drop(tx);
+        drop(tx2);
+        assert_eq!(DROPS.load(SeqCst), 60);
+    }
+}
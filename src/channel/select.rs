@@ -0,0 +1,968 @@
+// Waiting on several channel operations at once.
+//
+// `Select::wait` first tries every operation, starting from a random one so
+// that no operation is favored when several are ready. If none succeeds, the
+// thread registers a single `Context` with every channel involved (each
+// operation under its own id) and parks. A channel wakes it by selecting the
+// id of the operation that became possible; for a queue-backed channel or a
+// `SegQueue` that operation is then retried, while for a rendezvous channel
+// the peer has already moved the message through the operation's packet, and
+// for an `MsQueue` the push has filled in the operation's pop request.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use channel::{Flavor, Receiver, Sender};
+use channel::context::{Context, ABORTED, FIRST_OPER};
+use channel::error::{SendError, TrySendError, RecvError, TryRecvError};
+use channel::zero::Packet;
+use sync::{MsQueue, SegQueue};
+use sync::ms_queue::PopRequest;
+use util::random;
+
+/// Waits on several channel operations, completing exactly one of them.
+///
+/// Each operation comes with a closure that gets run on its result if that
+/// operation is the one completed; `wait` returns what that closure returns.
+/// When several operations are ready, one is picked at random.
+///
+/// The `select!` macro provides a more convenient syntax for a fixed set of
+/// operations.
+///
+/// # Example
+///
+/// ```
+/// use crossbeam::channel::{self, Select};
+///
+/// let (tx1, rx1) = channel::unbounded();
+/// let (tx2, rx2) = channel::unbounded();
+/// tx2.send("hello").unwrap();
+///
+/// let rxs = vec![rx1, rx2];
+/// let mut sel = Select::new();
+/// for (i, rx) in rxs.iter().enumerate() {
+///     sel.recv(rx, move |msg| (i, msg.unwrap()));
+/// }
+/// assert_eq!(sel.wait(), (1, "hello"));
+/// # drop(tx1);
+/// ```
+pub struct Select<'a, R> {
+    ops: Vec<Box<dyn Operation<R> + 'a>>,
+    default: Option<Box<dyn FnMut() -> R + 'a>>,
+    timeout: Option<(Duration, Box<dyn FnMut() -> R + 'a>)>,
+}
+
+impl<'a, R> Select<'a, R> {
+    /// Create an empty selection.
+    pub fn new() -> Select<'a, R> {
+        Select {
+            ops: Vec::new(),
+            default: None,
+            timeout: None,
+        }
+    }
+
+    /// Add an operation receiving from `rx`; `f` gets the outcome of `recv`.
+    pub fn recv<T, F>(&mut self, rx: &'a Receiver<T>, f: F) -> &mut Select<'a, R>
+        where F: FnOnce(Result<T, RecvError>) -> R + 'a
+    {
+        self.ops.push(Box::new(RecvOp { rx: rx, f: Some(f), packet: None }));
+        self
+    }
+
+    /// Add an operation sending `t` through `tx`; `f` gets the outcome of
+    /// `send`.
+    ///
+    /// If another operation is completed instead, `t` is dropped along with
+    /// the selection.
+    pub fn send<T, F>(&mut self, tx: &'a Sender<T>, t: T, f: F) -> &mut Select<'a, R>
+        where F: FnOnce(Result<(), SendError<T>>) -> R + 'a
+    {
+        self.ops.push(Box::new(SendOp { tx: tx, msg: Some(t), f: Some(f), packet: None }));
+        self
+    }
+
+    /// Add an operation popping from `queue`; `f` gets the element.
+    pub fn pop<T, Q, F>(&mut self, queue: &'a Q, f: F) -> &mut Select<'a, R>
+        where T: 'a, Q: SelectQueue<T>, F: FnOnce(T) -> R + 'a
+    {
+        self.ops.push(Box::new(PopOp { queue: queue.queue_ref(), f: Some(f), request: None }));
+        self
+    }
+
+    /// Run `f` instead of blocking when no operation is ready right away.
+    pub fn default<F>(&mut self, f: F) -> &mut Select<'a, R>
+        where F: FnOnce() -> R + 'a
+    {
+        self.default = Some(once(f));
+        self
+    }
+
+    /// Run `f` if no operation completes within `timeout` of calling `wait`.
+    pub fn timeout<F>(&mut self, timeout: Duration, f: F) -> &mut Select<'a, R>
+        where F: FnOnce() -> R + 'a
+    {
+        self.timeout = Some((timeout, once(f)));
+        self
+    }
+
+    /// Block until one of the operations completes (or the default or
+    /// timeout case applies), returning the result of its closure.
+    pub fn wait(mut self) -> R {
+        let deadline = self.timeout.as_ref().map(|&(timeout, _)| Instant::now() + timeout);
+
+        loop {
+            let n = self.ops.len();
+            let start = if n > 0 { random(n) } else { 0 };
+            for i in (start..n).chain(0..start) {
+                if let Some(r) = self.ops[i].try_op() {
+                    return r;
+                }
+            }
+
+            if let Some(mut f) = self.default.take() {
+                return f();
+            }
+            if let Some(deadline) = deadline {
+                if Instant::now() >= deadline {
+                    return (self.timeout.take().unwrap().1)();
+                }
+            }
+
+            let cx = Context::new();
+            let mut registered = 0;
+            for (i, op) in self.ops.iter_mut().enumerate() {
+                registered += 1;
+                if op.register(FIRST_OPER + i, &cx) {
+                    // no point in blocking; just retry
+                    let _ = cx.try_select(ABORTED);
+                    break;
+                }
+            }
+
+            let sel = cx.wait_until(deadline);
+
+            // whoever selected an operation has already removed its
+            // registration; remove the others
+            for (i, op) in self.ops[..registered].iter_mut().enumerate() {
+                if FIRST_OPER + i != sel {
+                    op.unregister(FIRST_OPER + i, &cx);
+                }
+            }
+
+            if sel >= FIRST_OPER {
+                if let Some(r) = self.ops[sel - FIRST_OPER].accept() {
+                    return r;
+                }
+            }
+        }
+    }
+}
+
+/// Turn a `FnOnce` into a boxed `FnMut` that must be called at most once.
+fn once<'a, R, F: FnOnce() -> R + 'a>(f: F) -> Box<dyn FnMut() -> R + 'a> {
+    let mut f = Some(f);
+    Box::new(move || (f.take().unwrap())())
+}
+
+/// One operation of a `Select`.
+trait Operation<R> {
+    /// Attempt the operation without blocking, running its closure if it
+    /// completes (successfully or not).
+    fn try_op(&mut self) -> Option<R>;
+
+    /// Register `cx` with the channel under `oper`. Returns whether the
+    /// operation may have become possible in the meantime.
+    fn register(&mut self, oper: usize, cx: &Context) -> bool;
+
+    /// Undo `register`, for an operation that was not selected.
+    fn unregister(&mut self, oper: usize, cx: &Context);
+
+    /// Finish the operation after the channel selected it.
+    fn accept(&mut self) -> Option<R>;
+}
+
+struct RecvOp<'a, T: 'a, F> {
+    rx: &'a Receiver<T>,
+    f: Option<F>,
+
+    /// For a rendezvous channel, the packet a sender fills while registered.
+    packet: Option<Arc<Packet<T>>>,
+}
+
+impl<'a, T, F, R> Operation<R> for RecvOp<'a, T, F>
+    where F: FnOnce(Result<T, RecvError>) -> R
+{
+    fn try_op(&mut self) -> Option<R> {
+        let res = match self.rx.try_recv() {
+            Ok(t) => Ok(t),
+            Err(TryRecvError::Disconnected) => Err(RecvError),
+            Err(TryRecvError::Empty) => return None,
+        };
+        Some((self.f.take().unwrap())(res))
+    }
+
+    fn register(&mut self, oper: usize, cx: &Context) -> bool {
+        match self.rx.chan.flavor {
+            Flavor::List(ref chan) => {
+                chan.register_recv(oper, cx);
+                chan.can_recv()
+            }
+            Flavor::Array(ref chan) => {
+                chan.register_recv(oper, cx);
+                chan.can_recv()
+            }
+            Flavor::Zero(ref chan) => {
+                self.packet = Some(chan.register_recv(oper, cx));
+                chan.can_recv(cx)
+            }
+        }
+    }
+
+    fn unregister(&mut self, oper: usize, cx: &Context) {
+        match self.rx.chan.flavor {
+            Flavor::List(ref chan) => chan.unregister_recv(oper, cx),
+            Flavor::Array(ref chan) => chan.unregister_recv(oper, cx),
+            Flavor::Zero(ref chan) => {
+                chan.unregister_recv(oper, cx);
+                self.packet = None;
+            }
+        }
+    }
+
+    fn accept(&mut self) -> Option<R> {
+        match self.packet.take() {
+            Some(packet) => {
+                packet.wait_ready();
+                let t = packet.take().unwrap();
+                Some((self.f.take().unwrap())(Ok(t)))
+            }
+            None => self.try_op(),
+        }
+    }
+}
+
+struct SendOp<'a, T: 'a, F> {
+    tx: &'a Sender<T>,
+    msg: Option<T>,
+    f: Option<F>,
+
+    /// For a rendezvous channel, the packet holding the message while
+    /// registered.
+    packet: Option<Arc<Packet<T>>>,
+}
+
+impl<'a, T, F, R> Operation<R> for SendOp<'a, T, F>
+    where F: FnOnce(Result<(), SendError<T>>) -> R
+{
+    fn try_op(&mut self) -> Option<R> {
+        let res = match self.tx.try_send(self.msg.take().unwrap()) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Disconnected(t)) => Err(SendError(t)),
+            Err(TrySendError::Full(t)) => {
+                self.msg = Some(t);
+                return None;
+            }
+        };
+        Some((self.f.take().unwrap())(res))
+    }
+
+    fn register(&mut self, oper: usize, cx: &Context) -> bool {
+        match self.tx.chan.flavor {
+            Flavor::List(_) => true,
+            Flavor::Array(ref chan) => {
+                chan.register_send(oper, cx);
+                chan.can_send()
+            }
+            Flavor::Zero(ref chan) => {
+                let t = self.msg.take().unwrap();
+                self.packet = Some(chan.register_send(oper, cx, t));
+                chan.can_send(cx)
+            }
+        }
+    }
+
+    fn unregister(&mut self, oper: usize, cx: &Context) {
+        match self.tx.chan.flavor {
+            Flavor::List(_) => {}
+            Flavor::Array(ref chan) => chan.unregister_send(oper, cx),
+            Flavor::Zero(ref chan) => {
+                chan.unregister_send(oper, cx);
+                // nobody took the message, so it is still in the packet
+                self.msg = self.packet.take().unwrap().take();
+            }
+        }
+    }
+
+    fn accept(&mut self) -> Option<R> {
+        match self.packet.take() {
+            Some(packet) => {
+                packet.wait_ready();
+                Some((self.f.take().unwrap())(Ok(())))
+            }
+            None => self.try_op(),
+        }
+    }
+}
+
+/// A queue whose `pop` can take part in a `Select`.
+///
+/// This is implemented by `MsQueue` and `SegQueue`, and cannot be implemented
+/// outside of this crate.
+pub trait SelectQueue<T> {
+    #[doc(hidden)]
+    fn queue_ref<'a>(&'a self) -> QueueRef<'a, T>;
+}
+
+#[doc(hidden)]
+#[derive(Debug)]
+pub enum QueueRef<'a, T: 'a> {
+    Ms(&'a MsQueue<T>),
+    Seg(&'a SegQueue<T>),
+}
+
+impl<T> SelectQueue<T> for MsQueue<T> {
+    fn queue_ref<'a>(&'a self) -> QueueRef<'a, T> {
+        QueueRef::Ms(self)
+    }
+}
+
+impl<T> SelectQueue<T> for SegQueue<T> {
+    fn queue_ref<'a>(&'a self) -> QueueRef<'a, T> {
+        QueueRef::Seg(self)
+    }
+}
+
+struct PopOp<'a, T: 'a, F> {
+    queue: QueueRef<'a, T>,
+    f: Option<F>,
+
+    /// For an `MsQueue`, the request a push fills in while registered.
+    request: Option<PopRequest<'a, T>>,
+}
+
+impl<'a, T, F, R> Operation<R> for PopOp<'a, T, F>
+    where F: FnOnce(T) -> R
+{
+    fn try_op(&mut self) -> Option<R> {
+        let t = match self.queue {
+            QueueRef::Ms(q) => q.try_pop(),
+            QueueRef::Seg(q) => q.try_pop(),
+        };
+        t.map(|t| (self.f.take().unwrap())(t))
+    }
+
+    fn register(&mut self, oper: usize, cx: &Context) -> bool {
+        match self.queue {
+            QueueRef::Ms(q) => {
+                self.request = q.register_pop(oper, cx);
+                self.request.is_none()
+            }
+            QueueRef::Seg(q) => q.register_pop(oper, cx),
+        }
+    }
+
+    fn unregister(&mut self, oper: usize, cx: &Context) {
+        match self.queue {
+            QueueRef::Ms(_) => {
+                if let Some(request) = self.request.take() {
+                    request.cancel();
+                }
+            }
+            QueueRef::Seg(q) => q.unregister_pop(oper, cx),
+        }
+    }
+
+    fn accept(&mut self) -> Option<R> {
+        let t = match self.queue {
+            QueueRef::Ms(_) => Some(self.request.take().unwrap().wait()),
+            QueueRef::Seg(q) => q.accept_pop(),
+        };
+        t.map(|t| (self.f.take().unwrap())(t))
+    }
+}
+
+/// Wait on several channel operations, completing exactly one of them.
+///
+/// Each arm is one of:
+///
+/// - `recv(rx) -> res => body`, receiving from `rx`; `res` is bound to the
+///   `Result` that `rx.recv()` would return.
+/// - `send(tx, msg) -> res => body`, sending `msg` through `tx`; `res` is bound
+///   to the `Result` that `tx.send(msg)` would return.
+/// - `pop(queue) -> elem => body`, popping from an `MsQueue` or a `SegQueue`;
+///   `elem` is bound to the element.
+/// - `default => body`, taken when no operation is ready right away.
+/// - `timeout(duration) => body`, taken when no operation completes within
+///   `duration`.
+///
+/// When several operations are ready, one is picked at random. The bodies
+/// run in the enclosing function, so `return`, `break` and `?` work as usual,
+/// but the patterns binding `res` must be irrefutable. All `send` messages are
+/// evaluated up front; those of the sends not completed are dropped.
+///
+/// This is a wrapper around `channel::Select`.
+///
+/// # Example
+///
+/// ```
+/// #[macro_use]
+/// extern crate crossbeam;
+///
+/// use crossbeam::channel;
+/// use std::time::Duration;
+///
+/// fn main() {
+///     let (tx1, rx1) = channel::unbounded::<i32>();
+///     let (tx2, rx2) = channel::bounded(1);
+///
+///     let n = select! {
+///         recv(rx1) -> msg => msg.unwrap(),
+///         send(tx2, 10) -> res => {
+///             res.unwrap();
+///             0
+///         }
+///         timeout(Duration::from_secs(1)) => panic!("timed out"),
+///     };
+///     assert_eq!(n, 0);
+///     assert_eq!(rx2.recv(), Ok(10));
+///     # drop(tx1);
+/// }
+/// ```
+#[macro_export]
+macro_rules! select {
+    // Parse the arms into a list of `(kind(args), pattern, { body })`.
+    (@parse [$($arms:tt)*]) => {
+        select!(@build [$($arms)*])
+    };
+    (@parse [$($arms:tt)*] recv($r:expr) -> $p:pat => { $($body:tt)* } , $($rest:tt)*) => {
+        select!(@parse [$($arms)* (recv($r), $p, { $($body)* })] $($rest)*)
+    };
+    (@parse [$($arms:tt)*] recv($r:expr) -> $p:pat => { $($body:tt)* } $($rest:tt)*) => {
+        select!(@parse [$($arms)* (recv($r), $p, { $($body)* })] $($rest)*)
+    };
+    (@parse [$($arms:tt)*] recv($r:expr) -> $p:pat => $body:expr , $($rest:tt)*) => {
+        select!(@parse [$($arms)* (recv($r), $p, { $body })] $($rest)*)
+    };
+    (@parse [$($arms:tt)*] recv($r:expr) -> $p:pat => $body:expr) => {
+        select!(@parse [$($arms)* (recv($r), $p, { $body })])
+    };
+    (@parse [$($arms:tt)*] send($s:expr, $m:expr) -> $p:pat => { $($body:tt)* } , $($rest:tt)*) => {
+        select!(@parse [$($arms)* (send($s, $m), $p, { $($body)* })] $($rest)*)
+    };
+    (@parse [$($arms:tt)*] send($s:expr, $m:expr) -> $p:pat => { $($body:tt)* } $($rest:tt)*) => {
+        select!(@parse [$($arms)* (send($s, $m), $p, { $($body)* })] $($rest)*)
+    };
+    (@parse [$($arms:tt)*] send($s:expr, $m:expr) -> $p:pat => $body:expr , $($rest:tt)*) => {
+        select!(@parse [$($arms)* (send($s, $m), $p, { $body })] $($rest)*)
+    };
+    (@parse [$($arms:tt)*] send($s:expr, $m:expr) -> $p:pat => $body:expr) => {
+        select!(@parse [$($arms)* (send($s, $m), $p, { $body })])
+    };
+    (@parse [$($arms:tt)*] pop($q:expr) -> $p:pat => { $($body:tt)* } , $($rest:tt)*) => {
+        select!(@parse [$($arms)* (pop($q), $p, { $($body)* })] $($rest)*)
+    };
+    (@parse [$($arms:tt)*] pop($q:expr) -> $p:pat => { $($body:tt)* } $($rest:tt)*) => {
+        select!(@parse [$($arms)* (pop($q), $p, { $($body)* })] $($rest)*)
+    };
+    (@parse [$($arms:tt)*] pop($q:expr) -> $p:pat => $body:expr , $($rest:tt)*) => {
+        select!(@parse [$($arms)* (pop($q), $p, { $body })] $($rest)*)
+    };
+    (@parse [$($arms:tt)*] pop($q:expr) -> $p:pat => $body:expr) => {
+        select!(@parse [$($arms)* (pop($q), $p, { $body })])
+    };
+    (@parse [$($arms:tt)*] default => { $($body:tt)* } , $($rest:tt)*) => {
+        select!(@parse [$($arms)* (default(), _, { $($body)* })] $($rest)*)
+    };
+    (@parse [$($arms:tt)*] default => { $($body:tt)* } $($rest:tt)*) => {
+        select!(@parse [$($arms)* (default(), _, { $($body)* })] $($rest)*)
+    };
+    (@parse [$($arms:tt)*] default => $body:expr , $($rest:tt)*) => {
+        select!(@parse [$($arms)* (default(), _, { $body })] $($rest)*)
+    };
+    (@parse [$($arms:tt)*] default => $body:expr) => {
+        select!(@parse [$($arms)* (default(), _, { $body })])
+    };
+    (@parse [$($arms:tt)*] timeout($d:expr) => { $($body:tt)* } , $($rest:tt)*) => {
+        select!(@parse [$($arms)* (timeout($d), _, { $($body)* })] $($rest)*)
+    };
+    (@parse [$($arms:tt)*] timeout($d:expr) => { $($body:tt)* } $($rest:tt)*) => {
+        select!(@parse [$($arms)* (timeout($d), _, { $($body)* })] $($rest)*)
+    };
+    (@parse [$($arms:tt)*] timeout($d:expr) => $body:expr , $($rest:tt)*) => {
+        select!(@parse [$($arms)* (timeout($d), _, { $body })] $($rest)*)
+    };
+    (@parse [$($arms:tt)*] timeout($d:expr) => $body:expr) => {
+        select!(@parse [$($arms)* (timeout($d), _, { $body })])
+    };
+
+    // Add the arms to a `Select` one by one. Each arm gets its own `res`
+    // slot (hygiene keeps them apart) in which its closure leaves the result,
+    // and the closure returns the arm's index.
+    (@build [$($arms:tt)*]) => {{
+        let mut sel = $crate::channel::Select::new();
+        select!(@chain sel 0usize [$($arms)*] [])
+    }};
+    (@chain $sel:ident $i:tt [] [$($done:tt)*]) => {{
+        let idx = $sel.wait();
+        select!(@dispatch idx [$($done)*])
+    }};
+    (@chain $sel:ident $i:tt [(recv($r:expr), $p:pat, $body:tt) $($rest:tt)*] [$($done:tt)*]) => {{
+        let mut res = None;
+        $sel.recv(&$r, |r| { res = Some(r); $i });
+        select!(@chain $sel ($i + 1) [$($rest)*] [$($done)* ($i, res, $p, $body)])
+    }};
+    (@chain $sel:ident $i:tt [(send($s:expr, $m:expr), $p:pat, $body:tt) $($rest:tt)*] [$($done:tt)*]) => {{
+        let mut res = None;
+        $sel.send(&$s, $m, |r| { res = Some(r); $i });
+        select!(@chain $sel ($i + 1) [$($rest)*] [$($done)* ($i, res, $p, $body)])
+    }};
+    (@chain $sel:ident $i:tt [(pop($q:expr), $p:pat, $body:tt) $($rest:tt)*] [$($done:tt)*]) => {{
+        let mut res = None;
+        $sel.pop(&$q, |t| { res = Some(t); $i });
+        select!(@chain $sel ($i + 1) [$($rest)*] [$($done)* ($i, res, $p, $body)])
+    }};
+    (@chain $sel:ident $i:tt [(default(), $p:pat, $body:tt) $($rest:tt)*] [$($done:tt)*]) => {{
+        let mut res = None;
+        $sel.default(|| { res = Some(()); $i });
+        select!(@chain $sel ($i + 1) [$($rest)*] [$($done)* ($i, res, $p, $body)])
+    }};
+    (@chain $sel:ident $i:tt [(timeout($d:expr), $p:pat, $body:tt) $($rest:tt)*] [$($done:tt)*]) => {{
+        let mut res = None;
+        $sel.timeout($d, || { res = Some(()); $i });
+        select!(@chain $sel ($i + 1) [$($rest)*] [$($done)* ($i, res, $p, $body)])
+    }};
+
+    // Run the body of the arm that was selected.
+    (@dispatch $idx:ident [$(($i:expr, $res:ident, $p:pat, $body:tt))*]) => {
+        $(
+            if $idx == $i {
+                let $p = $res.take().unwrap();
+                $body
+            } else
+        )* {
+            unreachable!()
+        }
+    };
+
+    ($($arms:tt)*) => {
+        select!(@parse [] $($arms)*)
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use channel::{self, Select, RecvError, SendError};
+    use scope;
+    use sync::{MsQueue, SegQueue};
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn ready_recv() {
+        let (tx1, rx1) = channel::unbounded();
+        let (_tx2, rx2) = channel::unbounded::<i32>();
+        tx1.send(1).unwrap();
+
+        let res = select! {
+            recv(rx1) -> msg => msg,
+            recv(rx2) -> _ => panic!(),
+        };
+        assert_eq!(res, Ok(1));
+    }
+
+    #[test]
+    fn blocking_recv() {
+        let (tx1, rx1) = channel::unbounded::<i32>();
+        let (tx2, rx2) = channel::bounded(0);
+
+        scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(ms(50));
+                tx2.send(2).unwrap();
+            });
+
+            select! {
+                recv(rx1) -> _ => panic!(),
+                recv(rx2) -> msg => assert_eq!(msg, Ok(2)),
+            }
+        });
+        drop(tx1);
+    }
+
+    #[test]
+    fn send_and_recv() {
+        for cap in vec![0, 1] {
+            let (tx, rx) = channel::bounded(cap);
+
+            scope(|scope| {
+                scope.spawn(|| {
+                    for i in 0..100 {
+                        select! {
+                            send(tx, i) -> res => res.unwrap(),
+                        }
+                    }
+                });
+                for i in 0..100 {
+                    select! {
+                        recv(rx) -> msg => assert_eq!(msg, Ok(i)),
+                    }
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn both_sides_rendezvous() {
+        // two threads each waiting to either send or receive on the same
+        // rendezvous channel must pair up with each other
+        let (tx, rx) = channel::bounded(0);
+
+        scope(|scope| {
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    for _ in 0..100 {
+                        select! {
+                            send(tx, 1) -> res => res.unwrap(),
+                            recv(rx) -> msg => assert_eq!(msg, Ok(1)),
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn default_arm() {
+        let (tx, rx) = channel::bounded(1);
+
+        let res = select! {
+            recv(rx) -> _ => 1,
+            default => 2,
+        };
+        assert_eq!(res, 2);
+
+        tx.send(()).unwrap();
+        let res = select! {
+            recv(rx) -> _ => 1,
+            default => 2,
+        };
+        assert_eq!(res, 1);
+
+        tx.send(()).unwrap();
+        let res = select! {
+            send(tx, ()) -> _ => 1,
+            default => 2,
+        };
+        assert_eq!(res, 2);
+    }
+
+    #[test]
+    fn timeout_arm() {
+        let (_tx, rx) = channel::unbounded::<i32>();
+
+        let start = Instant::now();
+        let res = select! {
+            recv(rx) -> _ => 1,
+            timeout(ms(50)) => 2,
+        };
+        assert_eq!(res, 2);
+        assert!(start.elapsed() >= ms(50));
+
+        let (tx, rx) = channel::unbounded();
+        scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(ms(20));
+                tx.send(1).unwrap();
+            });
+            let res = select! {
+                recv(rx) -> msg => msg.unwrap(),
+                timeout(ms(5000)) => 2,
+            };
+            assert_eq!(res, 1);
+        });
+    }
+
+    #[test]
+    fn disconnected() {
+        let (tx1, rx1) = channel::unbounded::<i32>();
+        let (tx2, rx2) = channel::unbounded::<i32>();
+
+        scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(ms(50));
+                drop(tx2);
+            });
+            select! {
+                recv(rx1) -> _ => panic!(),
+                recv(rx2) -> msg => assert_eq!(msg, Err(RecvError)),
+            }
+        });
+
+        drop(rx1);
+        let res = select! {
+            send(tx1, 5) -> res => res,
+        };
+        assert_eq!(res, Err(SendError(5)));
+    }
+
+    #[test]
+    fn control_flow() {
+        let (tx, rx) = channel::unbounded();
+        for i in 0..3 {
+            tx.send(i).unwrap();
+        }
+        drop(tx);
+
+        let mut got = vec![];
+        loop {
+            select! {
+                recv(rx) -> msg => match msg {
+                    Ok(i) => got.push(i),
+                    Err(_) => break,
+                },
+            }
+        }
+        assert_eq!(got, [0, 1, 2]);
+    }
+
+    #[test]
+    fn fairness() {
+        const COUNT: usize = 10000;
+
+        let (tx1, rx1) = channel::unbounded();
+        let (tx2, rx2) = channel::unbounded();
+        for _ in 0..COUNT {
+            tx1.send(()).unwrap();
+            tx2.send(()).unwrap();
+        }
+
+        let mut hits = [0; 2];
+        for _ in 0..COUNT {
+            select! {
+                recv(rx1) -> _ => hits[0] += 1,
+                recv(rx2) -> _ => hits[1] += 1,
+            }
+        }
+        assert!(hits.iter().all(|&h| h >= COUNT / 4));
+    }
+
+    #[test]
+    fn dynamic() {
+        let chans = (0..5).map(|_| channel::bounded::<usize>(1)).collect::<Vec<_>>();
+
+        scope(|scope| {
+            for (i, &(ref tx, _)) in chans.iter().enumerate() {
+                scope.spawn(move || {
+                    thread::sleep(ms(10 * i as u64));
+                    tx.send(i).unwrap();
+                });
+            }
+
+            let mut seen = vec![false; 5];
+            for _ in 0..5 {
+                let mut sel = Select::new();
+                for &(_, ref rx) in &chans {
+                    sel.recv(rx, |msg| msg.unwrap());
+                }
+                let i = sel.wait();
+                assert!(!seen[i]);
+                seen[i] = true;
+            }
+        });
+    }
+
+    #[test]
+    fn mpmc() {
+        const THREADS: usize = 4;
+        const COUNT: usize = 5000;
+
+        let (tx1, rx1) = channel::bounded(0);
+        let (tx2, rx2) = channel::bounded(3);
+
+        scope(|scope| {
+            for _ in 0..THREADS {
+                scope.spawn(|| {
+                    for i in 0..COUNT {
+                        select! {
+                            send(tx1, i) -> res => res.unwrap(),
+                            send(tx2, i) -> res => res.unwrap(),
+                        }
+                    }
+                });
+                scope.spawn(|| {
+                    for _ in 0..COUNT {
+                        select! {
+                            recv(rx1) -> msg => { msg.unwrap(); }
+                            recv(rx2) -> msg => { msg.unwrap(); }
+                        }
+                    }
+                });
+            }
+        });
+
+        assert!(rx1.try_recv().is_err());
+        assert!(rx2.try_recv().is_err());
+    }
+
+    #[test]
+    fn ready_pop() {
+        let (_tx, rx) = channel::unbounded::<i32>();
+        let q1 = MsQueue::new();
+        let q2 = SegQueue::new();
+        q1.push(1);
+        q2.push(2);
+
+        let mut got = vec![];
+        for _ in 0..2 {
+            got.push(select! {
+                recv(rx) -> _ => panic!(),
+                pop(q1) -> t => t,
+                pop(q2) -> t => t,
+            });
+        }
+        got.sort();
+        assert_eq!(got, [1, 2]);
+    }
+
+    #[test]
+    fn blocking_pop() {
+        let (_tx, rx) = channel::unbounded::<i32>();
+        let q1 = MsQueue::new();
+        let q2 = SegQueue::new();
+
+        scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(ms(50));
+                q1.push(1);
+                thread::sleep(ms(50));
+                q2.push(2);
+            });
+
+            for i in 1..3 {
+                let t = select! {
+                    recv(rx) -> _ => panic!(),
+                    pop(q1) -> t => t,
+                    pop(q2) -> t => t,
+                };
+                assert_eq!(t, i);
+            }
+        });
+    }
+
+    #[test]
+    fn pop_timeout_arm() {
+        let q1 = MsQueue::<i32>::new();
+        let q2 = SegQueue::<i32>::new();
+        let res = select! {
+            pop(q1) -> _ => 1,
+            pop(q2) -> _ => 2,
+            timeout(ms(20)) => 3,
+        };
+        assert_eq!(res, 3);
+
+        // the abandoned requests must not swallow later elements
+        q1.push(1);
+        q2.push(2);
+        assert_eq!(q1.try_pop(), Some(1));
+        assert_eq!(q2.try_pop(), Some(2));
+    }
+
+    #[test]
+    fn idle_queue_doesnt_grow() {
+        let (tx, rx) = channel::unbounded();
+        let q = MsQueue::<i32>::new();
+        for i in 0..100 {
+            let res = select! {
+                recv(rx) -> msg => msg.unwrap(),
+                pop(q) -> _ => panic!(),
+                timeout(ms(1)) => {
+                    tx.send(i).unwrap();
+                    -1
+                },
+            };
+            assert_eq!(res, -1);
+            assert_eq!(rx.try_recv(), Ok(i));
+            assert_eq!(q.nodes(), 0);
+        }
+    }
+
+    #[test]
+    fn channel_beats_queue() {
+        let (tx, rx) = channel::bounded(0);
+        let q = MsQueue::new();
+
+        scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(ms(50));
+                tx.send(1).unwrap();
+            });
+            let res = select! {
+                recv(rx) -> msg => msg.unwrap(),
+                pop(q) -> _ => panic!(),
+            };
+            assert_eq!(res, 1);
+        });
+
+        // the request left in the queue is skipped by the next push
+        q.push(2);
+        assert_eq!(q.try_pop(), Some(2));
+    }
+
+    // Producers feed a channel and a queue; consumers select over both, and
+    // every element must come out exactly once.
+    fn queue_and_channel<Q, P>(q: &Q, push: P)
+        where Q: ::channel::SelectQueue<usize> + Sync, P: Fn(&Q, usize) + Sync
+    {
+        const THREADS: usize = 4;
+        const COUNT: usize = 5000;
+
+        let (tx, rx) = channel::bounded(1);
+        let sum = ::std::sync::atomic::AtomicUsize::new(0);
+
+        scope(|scope| {
+            for _ in 0..THREADS {
+                scope.spawn(|| {
+                    for i in 0..COUNT {
+                        if i % 2 == 0 {
+                            tx.send(i).unwrap();
+                        } else {
+                            push(q, i);
+                        }
+                    }
+                });
+                scope.spawn(|| {
+                    for _ in 0..COUNT {
+                        let x = select! {
+                            recv(rx) -> msg => msg.unwrap(),
+                            pop(*q) -> t => t,
+                        };
+                        sum.fetch_add(x, ::std::sync::atomic::Ordering::Relaxed);
+                    }
+                });
+            }
+        });
+
+        assert!(rx.try_recv().is_err());
+        assert_eq!(sum.into_inner(), THREADS * COUNT * (COUNT - 1) / 2);
+    }
+
+    #[test]
+    fn ms_queue_and_channel() {
+        let q = MsQueue::new();
+        queue_and_channel(&q, |q, i| q.push(i));
+        assert!(q.try_pop().is_none());
+    }
+
+    #[test]
+    fn seg_queue_and_channel() {
+        let q = SegQueue::new();
+        queue_and_channel(&q, |q, i| q.push(i));
+        assert!(q.try_pop().is_none());
+    }
+}
@@ -0,0 +1,83 @@
+// The list of operations blocked on one side of a queue-backed channel.
+//
+// Notifying only selects a waiting operation and unparks its thread; the woken
+// thread then retries its operation against the queue, and goes back to sleep
+// if it loses the race for the message (or the free slot) to another thread.
+
+use std::sync::Mutex;
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::atomic::Ordering::SeqCst;
+
+use channel::context::{Context, DISCONNECTED};
+
+#[derive(Debug)]
+pub struct Waker {
+    entries: Mutex<Vec<Entry>>,
+
+    /// Mirrors `entries.is_empty()`, so that notifying nobody takes no lock.
+    is_empty: AtomicBool,
+}
+
+#[derive(Debug)]
+struct Entry {
+    oper: usize,
+    cx: Context,
+}
+
+impl Waker {
+    pub fn new() -> Waker {
+        Waker {
+            entries: Mutex::new(Vec::new()),
+            is_empty: AtomicBool::new(true),
+        }
+    }
+
+    /// Register `cx` as blocked on operation `oper`.
+    ///
+    /// The caller must check whether the operation has become possible after
+    /// registering and before parking, lest it miss a notification.
+    pub fn register(&self, oper: usize, cx: &Context) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.push(Entry { oper: oper, cx: cx.clone() });
+        self.is_empty.store(false, SeqCst);
+        drop(entries);
+
+        // pairs with the fence in `notify`: either the caller's subsequent
+        // check sees the new state, or the notifier sees this entry
+        atomic::fence(SeqCst);
+    }
+
+    /// Remove the registration of `cx` for `oper`, if it is still there.
+    pub fn unregister(&self, oper: usize, cx: &Context) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|e| e.oper != oper || !e.cx.same(cx));
+        self.is_empty.store(entries.is_empty(), SeqCst);
+    }
+
+    /// Wake up one blocked operation, if any.
+    pub fn notify(&self) {
+        atomic::fence(SeqCst);
+        if self.is_empty.load(SeqCst) {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        let pos = entries.iter().position(|e| e.cx.try_select(e.oper).is_ok());
+        if let Some(i) = pos {
+            entries.remove(i).cx.unpark();
+        }
+        self.is_empty.store(entries.is_empty(), SeqCst);
+    }
+
+    /// Wake up every blocked operation, telling it the channel is
+    /// disconnected.
+    pub fn disconnect(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        for e in entries.drain(..) {
+            if e.cx.try_select(DISCONNECTED).is_ok() {
+                e.cx.unpark();
+            }
+        }
+        self.is_empty.store(true, SeqCst);
+    }
+}
@@ -0,0 +1,264 @@
+// The zero-capacity flavor, where every send waits for a receive to take the
+// message directly from it (and vice versa).
+//
+// A blocked operation leaves an entry with a `Packet` in the channel. The
+// thread pairing up with it first selects the entry's context, then moves the
+// message through the packet and marks it ready; the blocked thread wakes up
+// and waits for the packet to become ready before completing.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::{Acquire, Release};
+use std::thread;
+use std::time::Instant;
+
+use channel::context::{Context, ABORTED, DISCONNECTED, FIRST_OPER, WAITING};
+use channel::error::{TrySendError, SendTimeoutError, TryRecvError, RecvTimeoutError};
+
+/// The slot through which a message passes between two paired threads.
+#[derive(Debug)]
+pub struct Packet<T> {
+    msg: Mutex<Option<T>>,
+    ready: AtomicBool,
+}
+
+impl<T> Packet<T> {
+    fn new(msg: Option<T>) -> Packet<T> {
+        Packet {
+            msg: Mutex::new(msg),
+            ready: AtomicBool::new(false),
+        }
+    }
+
+    /// Wait for the paired thread to finish with the packet. The wait is short,
+    /// as pairing selects the context right before filling the packet.
+    pub fn wait_ready(&self) {
+        while !self.ready.load(Acquire) {
+            thread::yield_now();
+        }
+    }
+
+    /// Take the message out of the packet, if there is one.
+    pub fn take(&self) -> Option<T> {
+        self.msg.lock().unwrap().take()
+    }
+}
+
+#[derive(Debug)]
+struct Entry<T> {
+    oper: usize,
+    cx: Context,
+    packet: Arc<Packet<T>>,
+}
+
+#[derive(Debug)]
+struct Inner<T> {
+    senders: VecDeque<Entry<T>>,
+    receivers: VecDeque<Entry<T>>,
+    disconnected: bool,
+}
+
+#[derive(Debug)]
+pub struct Channel<T> {
+    inner: Mutex<Inner<T>>,
+}
+
+/// Take the first entry whose context can still be selected.
+fn pair<T>(entries: &mut VecDeque<Entry<T>>) -> Option<Entry<T>> {
+    entries.iter()
+        .position(|e| e.cx.try_select(e.oper).is_ok())
+        .and_then(|i| entries.remove(i))
+}
+
+/// Could `e` still be paired with, by someone other than `cx`? Entries whose
+/// context was already selected elsewhere linger until their owner removes
+/// them.
+fn is_waiting<T>(e: &Entry<T>, cx: &Context) -> bool {
+    !e.cx.same(cx) && e.cx.selected() == WAITING
+}
+
+/// Hand `t` over to the paired receiver `e`.
+fn give<T>(e: Entry<T>, t: T) {
+    *e.packet.msg.lock().unwrap() = Some(t);
+    e.packet.ready.store(true, Release);
+    e.cx.unpark();
+}
+
+/// Take the message from the paired sender `e`.
+fn take<T>(e: Entry<T>) -> T {
+    let t = e.packet.take().unwrap();
+    e.packet.ready.store(true, Release);
+    e.cx.unpark();
+    t
+}
+
+/// Remove the entry registered by `cx` for `oper`, if it is still there.
+fn remove<T>(entries: &mut VecDeque<Entry<T>>, oper: usize, cx: &Context) {
+    if let Some(i) = entries.iter().position(|e| e.oper == oper && e.cx.same(cx)) {
+        entries.remove(i);
+    }
+}
+
+impl<T> Channel<T> {
+    pub fn new() -> Channel<T> {
+        Channel {
+            inner: Mutex::new(Inner {
+                senders: VecDeque::new(),
+                receivers: VecDeque::new(),
+                disconnected: false,
+            }),
+        }
+    }
+
+    pub fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(e) = pair(&mut inner.receivers) {
+            give(e, t);
+            Ok(())
+        } else if inner.disconnected {
+            Err(TrySendError::Disconnected(t))
+        } else {
+            Err(TrySendError::Full(t))
+        }
+    }
+
+    pub fn send(&self, t: T, deadline: Option<Instant>) -> Result<(), SendTimeoutError<T>> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(e) = pair(&mut inner.receivers) {
+            give(e, t);
+            return Ok(());
+        }
+        if inner.disconnected {
+            return Err(SendTimeoutError::Disconnected(t));
+        }
+
+        let cx = Context::new();
+        let packet = Arc::new(Packet::new(Some(t)));
+        inner.senders.push_back(Entry {
+            oper: FIRST_OPER,
+            cx: cx.clone(),
+            packet: packet.clone(),
+        });
+        drop(inner);
+
+        match cx.wait_until(deadline) {
+            FIRST_OPER => {
+                packet.wait_ready();
+                Ok(())
+            }
+            sel => {
+                remove(&mut self.inner.lock().unwrap().senders, FIRST_OPER, &cx);
+                let t = packet.take().unwrap();
+                if sel == ABORTED {
+                    Err(SendTimeoutError::Timeout(t))
+                } else {
+                    debug_assert_eq!(sel, DISCONNECTED);
+                    Err(SendTimeoutError::Disconnected(t))
+                }
+            }
+        }
+    }
+
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(e) = pair(&mut inner.senders) {
+            Ok(take(e))
+        } else if inner.disconnected {
+            Err(TryRecvError::Disconnected)
+        } else {
+            Err(TryRecvError::Empty)
+        }
+    }
+
+    pub fn recv(&self, deadline: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(e) = pair(&mut inner.senders) {
+            return Ok(take(e));
+        }
+        if inner.disconnected {
+            return Err(RecvTimeoutError::Disconnected);
+        }
+
+        let cx = Context::new();
+        let packet = Arc::new(Packet::new(None));
+        inner.receivers.push_back(Entry {
+            oper: FIRST_OPER,
+            cx: cx.clone(),
+            packet: packet.clone(),
+        });
+        drop(inner);
+
+        match cx.wait_until(deadline) {
+            FIRST_OPER => {
+                packet.wait_ready();
+                Ok(packet.take().unwrap())
+            }
+            sel => {
+                remove(&mut self.inner.lock().unwrap().receivers, FIRST_OPER, &cx);
+                if sel == ABORTED {
+                    Err(RecvTimeoutError::Timeout)
+                } else {
+                    debug_assert_eq!(sel, DISCONNECTED);
+                    Err(RecvTimeoutError::Disconnected)
+                }
+            }
+        }
+    }
+
+    /// Register `cx` as waiting to send `t` under `oper`, without blocking.
+    pub fn register_send(&self, oper: usize, cx: &Context, t: T) -> Arc<Packet<T>> {
+        let packet = Arc::new(Packet::new(Some(t)));
+        self.inner.lock().unwrap().senders.push_back(Entry {
+            oper: oper,
+            cx: cx.clone(),
+            packet: packet.clone(),
+        });
+        packet
+    }
+
+    /// Register `cx` as waiting to receive under `oper`, without blocking.
+    pub fn register_recv(&self, oper: usize, cx: &Context) -> Arc<Packet<T>> {
+        let packet = Arc::new(Packet::new(None));
+        self.inner.lock().unwrap().receivers.push_back(Entry {
+            oper: oper,
+            cx: cx.clone(),
+            packet: packet.clone(),
+        });
+        packet
+    }
+
+    pub fn unregister_send(&self, oper: usize, cx: &Context) {
+        remove(&mut self.inner.lock().unwrap().senders, oper, cx);
+    }
+
+    pub fn unregister_recv(&self, oper: usize, cx: &Context) {
+        remove(&mut self.inner.lock().unwrap().receivers, oper, cx);
+    }
+
+    /// Is some other context waiting to receive, or is the channel
+    /// disconnected?
+    pub fn can_send(&self, cx: &Context) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.disconnected || inner.receivers.iter().any(|e| is_waiting(e, cx))
+    }
+
+    /// Is some other context waiting to send, or is the channel disconnected?
+    pub fn can_recv(&self, cx: &Context) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.disconnected || inner.senders.iter().any(|e| is_waiting(e, cx))
+    }
+
+    pub fn disconnect(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.disconnected {
+            inner.disconnected = true;
+            let inner = &mut *inner;
+            for e in inner.senders.drain(..).chain(inner.receivers.drain(..)) {
+                if e.cx.try_select(DISCONNECTED).is_ok() {
+                    e.cx.unpark();
+                }
+            }
+        }
+    }
+}
@@ -18,6 +18,13 @@
 //! that set to include more advanced/niche primitives, as well as userspace
 //! alternatives. These live in the `sync` module.
 //!
+//! - **Channels**. Multi-producer, multi-consumer channels, in unbounded,
+//! bounded and rendezvous flavors. These live in the `channel` module.
+//!
+//! - **Thread pool**. A work-stealing thread pool with fork-join and scoped
+//! jobs, built on the `chase_lev` deques. This lives in the `pool` module,
+//! and the `par` module builds parallel slice operations on top of it.
+//!
 //! - **Scoped thread API**. Finally, the crate provides a "scoped" thread API,
 //! making it possible to spawn threads that share stack data with their
 //! parents. This functionality is exported at the top-level.
@@ -31,9 +38,14 @@ use std::thread;
 
 pub use scoped::{scope, Scope, ScopedJoinHandle};
 
+#[macro_use]
+pub mod channel;
 pub mod mem;
+pub mod par;
+pub mod pool;
 pub mod sync;
 mod scoped;
+mod util;
 
 #[doc(hidden)]
 trait FnBox {
@@ -48,7 +60,7 @@ impl<F: FnOnce()> FnBox for F {
 pub unsafe fn spawn_unsafe<'a, F>(f: F) -> thread::JoinHandle<()> where F: FnOnce() + Send + 'a {
     use std::mem;
 
-    let closure: Box<FnBox + 'a> = Box::new(f);
-    let closure: Box<FnBox + Send> = mem::transmute(closure);
+    let closure: Box<dyn FnBox + 'a> = Box::new(f);
+    let closure: Box<dyn FnBox + Send> = mem::transmute(closure);
     thread::spawn(move || closure.call_box())
 }
@@ -3,12 +3,17 @@ use std::mem;
 use std::ptr;
 use std::sync::atomic::{self, Ordering};
 
-use super::{Owned, Shared, Guard};
+use super::{Owned, Shared, Guard, decompose_tagged, low_bits};
 
 /// Like `std::sync::atomic::AtomicPtr`.
 ///
 /// Provides atomic access to a (nullable) pointer of type `T`, interfacing with
 /// the `Owned` and `Shared` types.
+///
+/// The pointer may carry a tag in the low bits left free by the alignment of
+/// `T`, which is stored, loaded and compared along with the address. Tags are
+/// set through `Owned::with_tag` and `Shared::with_tag`, or updated in place
+/// with `fetch_or` and `fetch_and`.
 #[derive(Debug)]
 pub struct Atomic<T> {
     ptr: atomic::AtomicPtr<T>,
@@ -18,16 +23,26 @@ pub struct Atomic<T> {
 unsafe impl<T: Sync> Send for Atomic<T> {}
 unsafe impl<T: Sync> Sync for Atomic<T> {}
 
+// The strongest ordering a failed compare-exchange may use, given the one for
+// success.
+fn failure_ordering(ord: Ordering) -> Ordering {
+    match ord {
+        Ordering::Release => Ordering::Relaxed,
+        Ordering::AcqRel => Ordering::Acquire,
+        ord => ord,
+    }
+}
+
 fn opt_shared_into_raw<T>(val: Option<Shared<T>>) -> *mut T {
-    val.map(|p| p.as_raw()).unwrap_or(ptr::null_mut())
+    val.map(|p| p.as_tagged_raw()).unwrap_or(ptr::null_mut())
 }
 
 fn opt_owned_as_raw<T>(val: &Option<Owned<T>>) -> *mut T {
-    val.as_ref().map(Owned::as_raw).unwrap_or(ptr::null_mut())
+    val.as_ref().map(Owned::as_tagged_raw).unwrap_or(ptr::null_mut())
 }
 
 fn opt_owned_into_raw<T>(val: Option<Owned<T>>) -> *mut T {
-    let ptr = val.as_ref().map(Owned::as_raw).unwrap_or(ptr::null_mut());
+    let ptr = val.as_ref().map(Owned::as_tagged_raw).unwrap_or(ptr::null_mut());
     mem::forget(val);
     ptr
 }
@@ -73,6 +88,21 @@ impl<T> Atomic<T> {
         unsafe { Shared::from_raw(self.ptr.load(ord)) }
     }
 
+    /// Do an atomic load with the given memory ordering, also returning the
+    /// tag.
+    ///
+    /// Unlike `load`, the tag is available even when the pointer is null.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ord` is `Release` or `AcqRel`.
+    pub fn load_tagged<'a>(&self, ord: Ordering, _: &'a Guard)
+                           -> (Option<Shared<'a, T>>, usize)
+    {
+        let raw = self.ptr.load(ord);
+        unsafe { (Shared::from_raw(raw), decompose_tagged(raw).1) }
+    }
+
     /// Do an atomic store with the given memory ordering.
     ///
     /// Transfers ownership of the given `Owned` pointer, if any. Since no
@@ -126,9 +156,9 @@ impl<T> Atomic<T> {
     pub fn cas(&self, old: Option<Shared<T>>, new: Option<Owned<T>>, ord: Ordering)
                -> Result<(), Option<Owned<T>>>
     {
-        if self.ptr.compare_and_swap(opt_shared_into_raw(old),
+        if self.ptr.compare_exchange(opt_shared_into_raw(old),
                                      opt_owned_as_raw(&new),
-                                     ord) == opt_shared_into_raw(old)
+                                     ord, failure_ordering(ord)).is_ok()
         {
             mem::forget(new);
             Ok(())
@@ -146,8 +176,8 @@ impl<T> Atomic<T> {
                            ord: Ordering, _: &'a Guard)
                            -> Result<Shared<'a, T>, Owned<T>>
     {
-        if self.ptr.compare_and_swap(opt_shared_into_raw(old), new.as_raw(), ord)
-            == opt_shared_into_raw(old)
+        if self.ptr.compare_exchange(opt_shared_into_raw(old), new.as_tagged_raw(),
+                                     ord, failure_ordering(ord)).is_ok()
         {
             Ok(unsafe { Shared::from_owned(new) })
         } else {
@@ -162,9 +192,9 @@ impl<T> Atomic<T> {
     pub fn cas_shared(&self, old: Option<Shared<T>>, new: Option<Shared<T>>, ord: Ordering)
                       -> bool
     {
-        self.ptr.compare_and_swap(opt_shared_into_raw(old),
+        self.ptr.compare_exchange(opt_shared_into_raw(old),
                                   opt_shared_into_raw(new),
-                                  ord) == opt_shared_into_raw(old)
+                                  ord, failure_ordering(ord)).is_ok()
     }
 
     /// Do an atomic swap with an `Owned` pointer with the given memory ordering.
@@ -178,4 +208,41 @@ impl<T> Atomic<T> {
                            -> Option<Shared<'a, T>> {
         unsafe { Shared::from_raw(self.ptr.swap(opt_shared_into_raw(new), ord)) }
     }
+
+    /// Atomically update the tag by a bitwise "or" with `tag`, using the
+    /// given memory ordering.
+    ///
+    /// Returns the previous pointer and its tag. Bits of `tag` beyond the ones
+    /// available for `T` are ignored.
+    pub fn fetch_or<'a>(&self, tag: usize, ord: Ordering, g: &'a Guard)
+                        -> (Option<Shared<'a, T>>, usize)
+    {
+        self.fetch_update_tag(|t| t | tag, ord, g)
+    }
+
+    /// Atomically update the tag by a bitwise "and" with `tag`, using the
+    /// given memory ordering.
+    ///
+    /// Returns the previous pointer and its tag. The address itself is never
+    /// affected, whatever the value of `tag`.
+    pub fn fetch_and<'a>(&self, tag: usize, ord: Ordering, g: &'a Guard)
+                         -> (Option<Shared<'a, T>>, usize)
+    {
+        self.fetch_update_tag(|t| t & tag, ord, g)
+    }
+
+    fn fetch_update_tag<'a, F>(&self, f: F, ord: Ordering, _: &'a Guard)
+                               -> (Option<Shared<'a, T>>, usize)
+        where F: Fn(usize) -> usize
+    {
+        let mut cur = self.ptr.load(Ordering::Relaxed);
+        loop {
+            let (raw, tag) = decompose_tagged(cur);
+            let new = (raw as usize | (f(tag) & low_bits::<T>())) as *mut T;
+            match self.ptr.compare_exchange(cur, new, ord, failure_ordering(ord)) {
+                Ok(_) => return unsafe { (Shared::from_raw(cur), tag) },
+                Err(prev) => cur = prev,
+            }
+        }
+    }
 }
@@ -0,0 +1,134 @@
+// Instantiable epoch collectors, each with its own epoch, participant list and
+// garbage bags.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use mem::epoch::{watchdog, StalledPin};
+use mem::epoch::global::EpochState;
+use mem::epoch::local::{self, LocalHandle};
+
+/// An epoch-based garbage collector, independent of the default one used by
+/// `epoch::pin()`.
+///
+/// Data structures managed through different collectors never wait on each
+/// other: a thread staying pinned in one collector does not hold up
+/// reclamation in another. Dropping the last clone of a collector (and every
+/// handle registered with it) frees all of its remaining garbage.
+///
+/// # Example
+///
+/// ```
+/// use crossbeam::mem::epoch::Collector;
+///
+/// let collector = Collector::new();
+/// let handle = collector.register();
+///
+/// let guard = handle.pin();
+/// // ... operate on data structures managed by `collector` ...
+/// drop(guard);
+/// ```
+#[derive(Clone, Debug)]
+pub struct Collector {
+    global: Arc<EpochState>,
+}
+
+impl Collector {
+    /// Create a new collector.
+    pub fn new() -> Collector {
+        Collector { global: Arc::new(EpochState::new()) }
+    }
+
+    /// Register the current thread with the collector.
+    ///
+    /// The returned handle is used to pin the collector's epoch. Each thread
+    /// should register once and keep its handle around, much like the
+    /// thread-local handle backing `epoch::pin()`.
+    pub fn register(&self) -> LocalHandle {
+        local::register(&self.global, Some(self.global.clone()))
+    }
+
+    /// Install a stalled-pin watchdog for this collector.
+    ///
+    /// See `epoch::set_stall_watchdog` for details.
+    pub fn set_stall_watchdog<F>(&self, threshold: Duration, callback: F)
+        where F: Fn(&[StalledPin]) + Send + Sync + 'static
+    {
+        let target = watchdog::Target::Collector(Arc::downgrade(&self.global));
+        watchdog::install(&self.global, target, threshold, Box::new(callback))
+    }
+
+    /// Remove this collector's stalled-pin watchdog, if any.
+    pub fn clear_stall_watchdog(&self) {
+        watchdog::uninstall(&self.global)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::Ordering::Relaxed;
+
+    use scope;
+    use super::*;
+    use mem::epoch::{self, Atomic, Owned};
+
+    #[test]
+    fn pin_reentrant() {
+        let collector = Collector::new();
+        let handle = collector.register();
+        assert!(!handle.is_pinned());
+        {
+            let _g1 = handle.pin();
+            assert!(handle.is_pinned());
+            {
+                let _g2 = handle.pin();
+                assert!(handle.is_pinned());
+            }
+            assert!(handle.is_pinned());
+        }
+        assert!(!handle.is_pinned());
+    }
+
+    #[test]
+    fn independent_of_default() {
+        let collector = Collector::new();
+        let handle = collector.register();
+        let _g = handle.pin();
+        assert!(handle.is_pinned());
+        epoch::local::with_handle(|h| assert!(!h.is_pinned()));
+    }
+
+    #[test]
+    fn guard_outlives_handle() {
+        let collector = Collector::new();
+        let handle = collector.register();
+        let guard = handle.pin();
+        drop(handle);
+        drop(collector);
+
+        let a = Atomic::new(17);
+        let shared = a.swap(None, Relaxed, &guard).unwrap();
+        unsafe { guard.unlinked(shared); }
+        drop(guard);
+    }
+
+    #[test]
+    fn unlink_many_threads() {
+        let collector = Collector::new();
+        scope(|s| {
+            for _ in 0..4 {
+                let collector = collector.clone();
+                s.spawn(move || {
+                    let handle = collector.register();
+                    let a = Atomic::null();
+                    for i in 0..10000 {
+                        let guard = handle.pin();
+                        a.store(Some(Owned::new(i)), Relaxed);
+                        let shared = a.swap(None, Relaxed, &guard).unwrap();
+                        unsafe { guard.unlinked(shared); }
+                    }
+                });
+            }
+        });
+    }
+}
@@ -8,14 +8,16 @@
 
 use std::ptr;
 use std::mem;
-use std::sync::atomic::AtomicPtr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize};
 use std::sync::atomic::Ordering::{Relaxed, Release, Acquire};
 
 use mem::ZerosValid;
 
 /// One item of garbage.
 ///
-/// Stores enough information to do a deallocation.
+/// Stores enough information to do a deallocation, possibly running the
+/// destructor of the pointed-to value first. Deferred closures are stored the
+/// same way, with `free` calling the boxed closure.
 #[derive(Debug)]
 struct Item {
     ptr: *mut u8,
@@ -24,38 +26,73 @@ struct Item {
 
 /// A single, thread-local bag of garbage.
 #[derive(Debug)]
-pub struct Bag(Vec<Item>);
+pub struct Bag {
+    items: Vec<Item>,
+    /// Total size of the values referenced by `items`
+    bytes: usize,
+}
 
 impl Bag {
-    fn new() -> Bag {
-        Bag(vec![])
+    pub fn new() -> Bag {
+        Bag { items: vec![], bytes: 0 }
     }
 
     fn insert<T>(&mut self, elem: *mut T) {
         let size = mem::size_of::<T>();
         if size > 0 {
-            self.0.push(Item {
+            self.items.push(Item {
                 ptr: elem as *mut u8,
                 free: free::<T>,
-            })
+            });
+            self.bytes += size;
         }
         unsafe fn free<T>(t: *mut u8) {
             drop(Vec::from_raw_parts(t as *mut T, 0, 1));
         }
     }
 
-    fn len(&self) -> usize {
-        self.0.len()
+    pub fn insert_drop<T>(&mut self, elem: *mut T) {
+        // unlike `insert`, zero-sized values are kept: their destructors may
+        // still have effects.
+        self.items.push(Item {
+            ptr: elem as *mut u8,
+            free: free_drop::<T>,
+        });
+        self.bytes += mem::size_of::<T>();
+        unsafe fn free_drop<T>(t: *mut u8) {
+            drop(Box::from_raw(t as *mut T));
+        }
+    }
+
+    fn insert_fn<F: FnOnce() + Send + 'static>(&mut self, f: F) {
+        self.items.push(Item {
+            ptr: Box::into_raw(Box::new(f)) as *mut u8,
+            free: call::<F>,
+        });
+        self.bytes += mem::size_of::<F>();
+        unsafe fn call<F: FnOnce()>(f: *mut u8) {
+            let f = Box::from_raw(f as *mut F);
+            (*f)()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
     }
 
-    /// Deallocate all garbage in the bag
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Deallocate all garbage in the bag, running destructors where requested
     pub unsafe fn collect(&mut self) {
-        let mut data = mem::replace(&mut self.0, Vec::new());
+        let mut data = mem::replace(&mut self.items, Vec::new());
+        self.bytes = 0;
         for item in data.iter() {
             (item.free)(item.ptr);
         }
         data.truncate(0);
-        self.0 = data;
+        self.items = data;
     }
 }
 
@@ -87,6 +124,14 @@ impl Local {
         self.new.insert(elem)
     }
 
+    pub fn insert_drop<T>(&mut self, elem: *mut T) {
+        self.new.insert_drop(elem)
+    }
+
+    pub fn insert_fn<F: FnOnce() + Send + 'static>(&mut self, f: F) {
+        self.new.insert_fn(f)
+    }
+
     /// Collect one epoch of garbage, rotating the local garbage bags.
     pub unsafe fn collect(&mut self) {
         let ret = self.old.collect();
@@ -98,6 +143,10 @@ impl Local {
     pub fn size(&self) -> usize {
         self.old.len() + self.cur.len() + self.new.len()
     }
+
+    pub fn bytes(&self) -> usize {
+        self.old.bytes() + self.cur.bytes() + self.new.bytes()
+    }
 }
 
 /// A concurrent garbage bag, currently based on Treiber's stack.
@@ -106,6 +155,10 @@ impl Local {
 #[derive(Debug)]
 pub struct ConcBag {
     head: AtomicPtr<Node>,
+    /// Number of garbage items across all bags, for statistics only
+    items: AtomicUsize,
+    /// Total size of the garbage across all bags, for statistics only
+    bytes: AtomicUsize,
 }
 
 unsafe impl ZerosValid for ConcBag {}
@@ -118,12 +171,15 @@ struct Node {
 
 impl ConcBag {
     pub fn insert(&self, t: Bag){
+        if t.len() == 0 { return }
+        self.items.fetch_add(t.len(), Relaxed);
+        self.bytes.fetch_add(t.bytes(), Relaxed);
         let n = Box::into_raw(Box::new(
             Node { data: t, next: AtomicPtr::new(ptr::null_mut()) }));
         loop {
             let head = self.head.load(Acquire);
             unsafe { (*n).next.store(head, Relaxed) };
-            if self.head.compare_and_swap(head, n, Release) == head { break }
+            if self.head.compare_exchange(head, n, Release, Relaxed).is_ok() { break }
         }
     }
 
@@ -136,9 +192,21 @@ impl ConcBag {
 
             while head != ptr::null_mut() {
                 let mut n = Box::from_raw(head);
+                self.items.fetch_sub(n.data.len(), Relaxed);
+                self.bytes.fetch_sub(n.data.bytes(), Relaxed);
                 n.data.collect();
                 head = n.next.load(Relaxed);
             }
         }
     }
+
+    /// Number of garbage items currently in the bag.
+    pub fn len(&self) -> usize {
+        self.items.load(Relaxed)
+    }
+
+    /// Total size of the garbage currently in the bag, in bytes.
+    pub fn bytes(&self) -> usize {
+        self.bytes.load(Relaxed)
+    }
 }
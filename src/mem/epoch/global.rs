@@ -1,11 +1,14 @@
-// Definition of global epoch state. The `get` function is the way to
-// access this data externally (until const fn is stabilized...).
+// Definition of global epoch state. Each `Collector` owns one of these; the
+// `get` function is the way to access the default, process-wide instance
+// (until const fn is stabilized...).
 
-use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicPtr, AtomicUsize};
+use std::sync::atomic::Ordering::Relaxed;
 
-use mem::CachePadded;
+use mem::{CachePadded, ZerosValid};
 use mem::epoch::garbage;
 use mem::epoch::participants::Participants;
+use mem::epoch::watchdog::Watchdog;
 
 /// Global epoch state
 #[derive(Debug)]
@@ -19,17 +22,54 @@ pub struct EpochState {
 
     /// Participant list
     pub participants: Participants,
+
+    /// Outcomes of attempts to advance the epoch
+    pub advances: CachePadded<Advances>,
+
+    /// Stalled-pin watchdog, if installed
+    pub watchdog: AtomicPtr<Watchdog>,
+}
+
+/// Counters of attempts to advance the global epoch, for statistics only.
+#[derive(Debug)]
+pub struct Advances {
+    /// Attempts that moved the epoch forward
+    pub succeeded: AtomicUsize,
+    /// Attempts that were blocked by a participant pinned in an older epoch
+    pub failed: AtomicUsize,
 }
 
+unsafe impl ZerosValid for Advances {}
+
 unsafe impl Send for EpochState {}
 unsafe impl Sync for EpochState {}
 
+impl Drop for EpochState {
+    fn drop(&mut self) {
+        // Only reached once every participant has been retired (each one
+        // keeps its owning state alive), so nothing can still be observing
+        // the remaining garbage.
+        unsafe {
+            for bag in self.garbage.iter() {
+                bag.collect();
+            }
+            self.participants.free_all();
+
+            let watchdog = self.watchdog.load(Relaxed);
+            if !watchdog.is_null() {
+                drop(Box::from_raw(watchdog));
+            }
+        }
+    }
+}
+
 pub use self::imp::get;
 
 #[cfg(not(feature = "nightly"))]
 mod imp {
     use std::mem;
-    use std::sync::atomic::{self, AtomicUsize};
+    use std::ptr;
+    use std::sync::atomic::{AtomicPtr, AtomicUsize};
     use std::sync::atomic::Ordering::Relaxed;
 
     use super::EpochState;
@@ -37,18 +77,20 @@ mod imp {
     use mem::epoch::participants::Participants;
 
     impl EpochState {
-        fn new() -> EpochState {
+        pub fn new() -> EpochState {
             EpochState {
                 epoch: CachePadded::zeroed(),
                 garbage: [CachePadded::zeroed(),
                           CachePadded::zeroed(),
                           CachePadded::zeroed()],
                 participants: Participants::new(),
+                advances: CachePadded::zeroed(),
+                watchdog: AtomicPtr::new(ptr::null_mut()),
             }
         }
     }
 
-    static EPOCH: AtomicUsize = atomic::ATOMIC_USIZE_INIT;
+    static EPOCH: AtomicUsize = AtomicUsize::new(0);
 
     pub fn get() -> &'static EpochState {
         let mut addr = EPOCH.load(Relaxed);
@@ -57,12 +99,13 @@ mod imp {
             let boxed = Box::new(EpochState::new());
             let raw = Box::into_raw(boxed);
 
-            addr = EPOCH.compare_and_swap(0, raw as usize, Relaxed);
-            if addr != 0 {
-                let boxed = unsafe { Box::from_raw(raw) };
-                mem::drop(boxed);
-            } else {
-                addr = raw as usize;
+            match EPOCH.compare_exchange(0, raw as usize, Relaxed, Relaxed) {
+                Ok(_) => addr = raw as usize,
+                Err(prev) => {
+                    let boxed = unsafe { Box::from_raw(raw) };
+                    mem::drop(boxed);
+                    addr = prev;
+                }
             }
         }
 
@@ -74,18 +117,22 @@ mod imp {
 
 #[cfg(feature = "nightly")]
 mod imp {
+    use std::sync::atomic::AtomicPtr;
+
     use super::EpochState;
     use mem::CachePadded;
     use mem::epoch::participants::Participants;
 
     impl EpochState {
-        const fn new() -> EpochState {
+        pub const fn new() -> EpochState {
             EpochState {
                 epoch: CachePadded::zeroed(),
                 garbage: [CachePadded::zeroed(),
                           CachePadded::zeroed(),
                           CachePadded::zeroed()],
                 participants: Participants::new(),
+                advances: CachePadded::zeroed(),
+                watchdog: AtomicPtr::new(0 as *mut _),
             }
         }
     }
@@ -1,6 +1,8 @@
 use std::marker;
 
 use super::{local, Shared};
+use super::global::EpochState;
+use super::participant::Participant;
 
 /// An RAII-style guard for pinning the current epoch.
 ///
@@ -9,6 +11,8 @@ use super::{local, Shared};
 #[must_use]
 #[derive(Debug)]
 pub struct Guard {
+    participant: *const Participant,
+    global: *const EpochState,
     _marker: marker::PhantomData<*mut ()>, // !Send and !Sync
 }
 
@@ -20,37 +24,90 @@ pub struct Guard {
 /// the first guard requires a barrier. Thus, in cases where you expect to
 /// perform several lock-free operations in quick succession, you may consider
 /// pinning around the entire set of operations.
+///
+/// This pins the default, process-wide collector; see `Collector` for
+/// instantiating separate ones.
 pub fn pin() -> Guard {
-    local::with_participant(|p| {
-        let entered = p.enter();
+    local::with_handle(|h| h.pin())
+}
+
+/// Enter a critical section of `participant`, returning the guard that exits
+/// it again.
+///
+/// The participant must be owned by the current thread and belong to `global`.
+pub unsafe fn enter(participant: &Participant, global: &EpochState) -> Guard {
+    let entered = participant.enter(global);
 
-        let g = Guard {
-            _marker: marker::PhantomData,
-        };
+    let g = Guard {
+        participant: participant,
+        global: global,
+        _marker: marker::PhantomData,
+    };
 
-        if entered && p.should_gc() {
-            p.try_collect(&g);
-        }
+    if entered && participant.should_gc() {
+        participant.try_collect(global, &g);
+    }
 
-        g
-    })
+    g
 }
 
 impl Guard {
+    fn participant(&self) -> &Participant {
+        unsafe { &*self.participant }
+    }
+
+    fn global(&self) -> &EpochState {
+        unsafe { &*self.global }
+    }
+
     /// Assert that the value is no longer reachable from a lock-free data
     /// structure and should be collected when sufficient epochs have passed.
+    ///
+    /// Only the memory is freed; the value's destructor is *not* run. Move
+    /// out of the value with `ptr::read` first if it owns resources, or use
+    /// `defer_drop` instead.
     pub unsafe fn unlinked<T>(&self, val: Shared<T>) {
-        local::with_participant(|p| p.reclaim(val.as_raw()))
+        self.participant().reclaim(val.as_raw())
+    }
+
+    /// Assert that the value is no longer reachable from a lock-free data
+    /// structure and should be dropped when sufficient epochs have passed.
+    ///
+    /// Unlike `unlinked`, this runs the value's destructor before freeing it,
+    /// so the value must not have been moved out of. The destructor may run
+    /// on any thread participating in the epoch scheme.
+    pub unsafe fn defer_drop<T>(&self, val: Shared<T>) {
+        self.participant().reclaim_drop(val.as_raw())
+    }
+
+    /// Like `defer_drop`, for a heap value that is not itself linked through
+    /// an `Atomic` but only reachable through one, such as a box pointed to by
+    /// a node.
+    pub(crate) unsafe fn defer_drop_raw<T>(&self, val: *mut T) {
+        self.participant().reclaim_drop(val)
+    }
+
+    /// Run `f` once sufficient epochs have passed that no thread pinned
+    /// right now can still be pinned.
+    ///
+    /// This is the general form of `unlinked` and `defer_drop`: it can be used
+    /// to decrement an external reference count, return a buffer to a pool,
+    /// and the like. The closure may run on any thread participating in the
+    /// epoch scheme, including after the current thread has exited.
+    pub fn defer<F>(&self, f: F) where F: FnOnce() + Send + 'static {
+        self.participant().defer(f)
     }
 
     /// Move the thread-local garbage into the global set of garbage.
     pub fn migrate_garbage(&self) {
-        local::with_participant(|p| p.migrate_garbage())
+        self.participant().migrate_garbage(self.global())
     }
 }
 
 impl Drop for Guard {
     fn drop(&mut self) {
-        local::with_participant(|p| p.exit());
+        let p = self.participant();
+        p.exit();
+        unsafe { p.maybe_retire(self.global()) }
     }
 }
@@ -1,38 +1,63 @@
 // Manage the thread-local state, providing access to a `Participant` record.
 
-use std::sync::atomic::Ordering::Relaxed;
+use std::sync::Arc;
 
+use mem::epoch::{guard, stats, Guard, Stats};
 use mem::epoch::participant::Participant;
-use mem::epoch::global;
+use mem::epoch::global::{self, EpochState};
 
+/// A thread's registration with a `Collector`.
+///
+/// Pinning through the handle enters the collector's epoch. When the handle is
+/// dropped, its remaining garbage is handed over to the collector; the
+/// underlying record is retired once any outstanding `Guard` is gone too.
 #[derive(Debug)]
-struct LocalEpoch {
+pub struct LocalHandle {
     participant: *const Participant,
+    global: *const EpochState,
 }
 
-impl LocalEpoch {
-    fn new() -> LocalEpoch {
-        LocalEpoch { participant: global::get().participants.enroll() }
+/// Enroll the current thread with `global`, keeping `owner` alive for as long
+/// as the resulting record is in use.
+pub fn register(global: &EpochState, owner: Option<Arc<EpochState>>) -> LocalHandle {
+    LocalHandle {
+        participant: global.participants.enroll(owner),
+        global: global,
+    }
+}
+
+impl LocalHandle {
+    /// Pin the collector's epoch.
+    ///
+    /// Behaves like `epoch::pin()`, but for the collector this handle was
+    /// registered with.
+    pub fn pin(&self) -> Guard {
+        unsafe { guard::enter(&*self.participant, &*self.global) }
+    }
+
+    /// Is this handle currently pinned?
+    pub fn is_pinned(&self) -> bool {
+        unsafe { (*self.participant).is_pinned() }
     }
 
-    fn get(&self) -> &Participant {
-        unsafe { &*self.participant }
+    /// Take a snapshot of the state of the collector.
+    ///
+    /// Behaves like `epoch::stats()`, but for the collector this handle was
+    /// registered with.
+    pub fn stats(&self) -> Stats {
+        let guard = self.pin();
+        stats::gather(unsafe { &*self.global }, &guard)
     }
 }
 
-// FIXME: avoid leaking when all threads have exited
-impl Drop for LocalEpoch {
+impl Drop for LocalHandle {
     fn drop(&mut self) {
-        let p = self.get();
-        p.enter();
-        p.migrate_garbage();
-        p.exit();
-        p.active.store(false, Relaxed);
+        unsafe { (*self.participant).detach(&*self.global) }
     }
 }
 
-thread_local!(static LOCAL_EPOCH: LocalEpoch = LocalEpoch::new() );
+thread_local!(static LOCAL_EPOCH: LocalHandle = register(global::get(), None) );
 
-pub fn with_participant<F, T>(f: F) -> T where F: FnOnce(&Participant) -> T {
-    LOCAL_EPOCH.with(|e| f(e.get()))
+pub fn with_handle<F, T>(f: F) -> T where F: FnOnce(&LocalHandle) -> T {
+    LOCAL_EPOCH.with(|h| f(h))
 }
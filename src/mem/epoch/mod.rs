@@ -43,6 +43,11 @@
 //!
 //! Each of these types provides further documentation on usage.
 //!
+//! By default, all of the above share a single, process-wide epoch. A
+//! `Collector` provides a separate epoch, participant list and set of garbage
+//! bags, which threads use through a `LocalHandle` obtained from
+//! `Collector::register`.
+//!
 //! # Example
 //!
 //! ```
@@ -123,109 +128,250 @@
 //!     }
 //! }
 //! ```
+//!
+//! `sync::ListSet` is a more involved example, which also uses the tag bits of
+//! `Atomic` to mark nodes for deletion.
 
 // FIXME: document implementation details
 
 mod atomic;
+mod collector;
 mod garbage;
 mod global;
 mod guard;
 mod local;
 mod participant;
 mod participants;
+mod stats;
+mod watchdog;
 
 pub use self::atomic::Atomic;
+pub use self::collector::Collector;
 pub use self::guard::{pin, Guard};
+pub use self::local::LocalHandle;
+pub use self::stats::{stats, BagStats, Stats};
+pub use self::watchdog::{set_stall_watchdog, clear_stall_watchdog, StalledPin};
 
+use std::fmt;
+use std::marker::PhantomData;
 use std::ops::{Deref, DerefMut};
-use std::ptr;
 use std::mem;
 
+/// The mask of the low bits of a `*mut T` that are always zero due to
+/// alignment, and can therefore carry a tag.
+fn low_bits<T>() -> usize {
+    mem::align_of::<T>() - 1
+}
+
+/// Split a possibly-tagged pointer into the actual pointer and its tag.
+fn decompose_tagged<T>(raw: *mut T) -> (*mut T, usize) {
+    let raw = raw as usize;
+    ((raw & !low_bits::<T>()) as *mut T, raw & low_bits::<T>())
+}
+
 /// Like `Box<T>`: an owned, heap-allocated data value of type `T`.
-#[derive(Debug)]
+///
+/// An `Owned` pointer may carry a small tag in the unused low bits of its
+/// address; see `with_tag`.
 pub struct Owned<T> {
-    data: Box<T>,
+    // the address of a `Box<T>`, with the tag in its low bits
+    data: usize,
+    _marker: PhantomData<Box<T>>,
 }
 
 impl<T> Owned<T> {
     /// Move `t` to a new heap allocation.
     pub fn new(t: T) -> Owned<T> {
-        Owned { data: Box::new(t) }
+        Owned {
+            data: Box::into_raw(Box::new(t)) as usize,
+            _marker: PhantomData,
+        }
     }
 
     fn as_raw(&self) -> *mut T {
-        self.deref() as *const _ as *mut _
+        (self.data & !low_bits::<T>()) as *mut T
+    }
+
+    /// The raw pointer, including the tag.
+    fn as_tagged_raw(&self) -> *mut T {
+        self.data as *mut T
     }
 
     /// Move data out of the owned box, deallocating the box.
     pub fn into_inner(self) -> T {
-        *self.data
+        let data = unsafe { Box::from_raw(self.as_raw()) };
+        mem::forget(self);
+        *data
+    }
+
+    /// Give up ownership, turning this into a `Shared` pointer valid while
+    /// `_guard` is.
+    ///
+    /// The allocation is leaked unless it ends up linked into a data structure
+    /// that later unlinks it.
+    pub fn into_shared<'a>(self, _guard: &'a Guard) -> Shared<'a, T> {
+        unsafe { Shared::from_owned(self) }
+    }
+
+    /// The tag carried by this pointer.
+    pub fn tag(&self) -> usize {
+        self.data & low_bits::<T>()
+    }
+
+    /// Replace the tag carried by this pointer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `tag` doesn't fit in the low bits left free by the alignment
+    /// of `T`, i.e. unless `tag < align_of::<T>()`.
+    pub fn with_tag(self, tag: usize) -> Owned<T> {
+        assert!(tag <= low_bits::<T>(), "tag does not fit in the alignment of `T`");
+        let data = (self.data & !low_bits::<T>()) | tag;
+        mem::forget(self);
+        Owned {
+            data: data,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T> Drop for Owned<T> {
+    fn drop(&mut self) {
+        unsafe { drop(Box::from_raw(self.as_raw())) }
     }
 }
 
 impl<T> Deref for Owned<T> {
     type Target = T;
     fn deref(&self) -> &T {
-        &self.data
+        unsafe { &*self.as_raw() }
     }
 }
 
 impl<T> DerefMut for Owned<T> {
     fn deref_mut(&mut self) -> &mut T {
-        &mut self.data
+        unsafe { &mut *self.as_raw() }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Owned<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Owned")
+         .field("data", self.deref())
+         .field("tag", &self.tag())
+         .finish()
     }
 }
 
-#[derive(PartialEq, Eq)]
 /// Like `&'a T`: a shared reference valid for lifetime `'a`.
-#[derive(Debug)]
+///
+/// A `Shared` pointer may carry a small tag in the unused low bits of its
+/// address; see `with_tag`.
+///
+/// Since tagged pointers were added, a `Shared<'a, T>` dereferences to `T`
+/// itself rather than to a `&'a T`, so `**shared` is now written `*shared`;
+/// `as_ref` gives a reference that outlives the `Shared`. Likewise, `==` now
+/// compares addresses and tags, not the values pointed to.
 pub struct Shared<'a, T: 'a> {
-    data: &'a T,
+    // the address, with the tag in its low bits
+    data: usize,
+    _marker: PhantomData<&'a T>,
 }
 
 impl<'a, T> Copy for Shared<'a, T> {}
 impl<'a, T> Clone for Shared<'a, T> {
     fn clone(&self) -> Shared<'a, T> {
-        Shared { data: self.data }
+        *self
+    }
+}
+
+impl<'a, T> PartialEq for Shared<'a, T> {
+    fn eq(&self, other: &Shared<'a, T>) -> bool {
+        self.data == other.data
     }
 }
 
+impl<'a, T> Eq for Shared<'a, T> {}
+
 impl<'a, T> Deref for Shared<'a, T> {
-    type Target = &'a T;
-    fn deref(&self) -> &&'a T {
-        &self.data
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.as_ref()
+    }
+}
+
+impl<'a, T: fmt::Debug> fmt::Debug for Shared<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Shared")
+         .field("data", self.as_ref())
+         .field("tag", &self.tag())
+         .finish()
     }
 }
 
 impl<'a, T> Shared<'a, T> {
     unsafe fn from_raw(raw: *mut T) -> Option<Shared<'a, T>> {
-        if raw == ptr::null_mut() { None }
-        else {
+        if decompose_tagged(raw).0.is_null() {
+            None
+        } else {
             Some(Shared {
-                data: mem::transmute::<*mut T, &T>(raw)
+                data: raw as usize,
+                _marker: PhantomData,
             })
         }
     }
 
-    unsafe fn from_ref(r: &T) -> Shared<'a, T> {
-        Shared { data: mem::transmute(r) }
-    }
-
     unsafe fn from_owned(owned: Owned<T>) -> Shared<'a, T> {
-        let ret = Shared::from_ref(owned.deref());
+        let data = owned.data;
         mem::forget(owned);
-        ret
+        Shared {
+            data: data,
+            _marker: PhantomData,
+        }
     }
 
+    /// The raw pointer, without the tag.
     pub fn as_raw(&self) -> *mut T {
-        self.data as *const _ as *mut _
+        (self.data & !low_bits::<T>()) as *mut T
+    }
+
+    /// The raw pointer, including the tag.
+    fn as_tagged_raw(&self) -> *mut T {
+        self.data as *mut T
+    }
+
+    /// The reference this pointer stands for, valid for all of `'a`.
+    ///
+    /// Unlike going through `Deref`, the result outlives the `Shared` itself.
+    pub fn as_ref(&self) -> &'a T {
+        unsafe { &*self.as_raw() }
+    }
+
+    /// The tag carried by this pointer.
+    pub fn tag(&self) -> usize {
+        self.data & low_bits::<T>()
+    }
+
+    /// The same pointer, carrying a different tag.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `tag` doesn't fit in the low bits left free by the alignment
+    /// of `T`, i.e. unless `tag < align_of::<T>()`.
+    pub fn with_tag(&self, tag: usize) -> Shared<'a, T> {
+        assert!(tag <= low_bits::<T>(), "tag does not fit in the alignment of `T`");
+        Shared {
+            data: (self.data & !low_bits::<T>()) | tag,
+            _marker: PhantomData,
+        }
     }
 }
 
 
 #[cfg(test)]
 mod test {
-    use std::sync::atomic::Ordering;
+    use std::mem;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use super::*;
     use mem::epoch;
 
@@ -255,11 +401,187 @@ mod test {
         }
     }
 
+    #[test]
+    fn defer_drop_runs_destructors() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        struct Test(Box<usize>);
+        impl Drop for Test {
+            fn drop(&mut self) {
+                DROPS.fetch_add(*self.0, Ordering::SeqCst);
+            }
+        }
+
+        let collector = Collector::new();
+        let handle = collector.register();
+        {
+            let g = handle.pin();
+            let x = Atomic::null();
+            for _ in 0..100 {
+                x.store(Some(Owned::new(Test(Box::new(1)))), Ordering::Relaxed);
+                let shared = x.swap(None, Ordering::Relaxed, &g).unwrap();
+                unsafe { g.defer_drop(shared); }
+            }
+            assert_eq!(DROPS.load(Ordering::SeqCst), 0);
+        }
+        drop(handle);
+        drop(collector);
+        assert_eq!(DROPS.load(Ordering::SeqCst), 100);
+    }
+
+    #[test]
+    fn unlinked_does_not_drop() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        struct Test;
+        impl Drop for Test {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let collector = Collector::new();
+        let handle = collector.register();
+        {
+            let g = handle.pin();
+            let x = Atomic::new(Test);
+            let shared = x.swap(None, Ordering::Relaxed, &g).unwrap();
+            unsafe { g.unlinked(shared); }
+        }
+        drop(handle);
+        drop(collector);
+        assert_eq!(DROPS.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn defer_drop_zero_sized() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        struct Test;
+        impl Drop for Test {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let collector = Collector::new();
+        let handle = collector.register();
+        {
+            let g = handle.pin();
+            let x = Atomic::new(Test);
+            let shared = x.swap(None, Ordering::Relaxed, &g).unwrap();
+            unsafe { g.defer_drop(shared); }
+        }
+        drop(handle);
+        drop(collector);
+        assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn defer_runs_closures() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        let collector = Collector::new();
+        let handle = collector.register();
+        {
+            let g = handle.pin();
+            for i in 0..100 {
+                g.defer(move || { CALLS.fetch_add(i, Ordering::SeqCst); });
+            }
+            assert_eq!(CALLS.load(Ordering::SeqCst), 0);
+        }
+        drop(handle);
+        drop(collector);
+        assert_eq!(CALLS.load(Ordering::SeqCst), (0..100).sum());
+    }
+
+    #[test]
+    fn defer_survives_thread_exit() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let collector = Collector::new();
+
+        let threads = (0..4).map(|_| {
+            let calls = calls.clone();
+            let collector = collector.clone();
+            thread::spawn(move || {
+                let handle = collector.register();
+                let g = handle.pin();
+                for _ in 0..10 {
+                    let calls = calls.clone();
+                    g.defer(move || { calls.fetch_add(1, Ordering::SeqCst); });
+                }
+            })
+        }).collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        // the garbage of the exited threads now lives in the global bags
+        drop(collector);
+        assert_eq!(calls.load(Ordering::SeqCst), 40);
+    }
+
+    #[test]
+    fn tag_bits() {
+        // the tag lives in the pointer itself
+        assert_eq!(mem::size_of::<Owned<u64>>(), mem::size_of::<usize>());
+        assert_eq!(mem::size_of::<Shared<u64>>(), mem::size_of::<usize>());
+
+        let g = pin();
+        let x: Atomic<u64> = Atomic::null();
+        x.store(Some(Owned::new(7).with_tag(3)), Ordering::Relaxed);
+
+        let p = x.load(Ordering::Relaxed, &g).unwrap();
+        assert_eq!(p.tag(), 3);
+        assert_eq!(*p, 7);
+        assert_eq!(p.with_tag(0).tag(), 0);
+        assert!(p != p.with_tag(1));
+        assert_eq!(p.as_raw(), p.with_tag(1).as_raw());
+
+        // CAS compares tags too
+        assert!(!x.cas_shared(Some(p.with_tag(0)), Some(p.with_tag(1)), Ordering::Relaxed));
+        assert!(x.cas_shared(Some(p), Some(p.with_tag(1)), Ordering::Relaxed));
+        assert_eq!(x.load(Ordering::Relaxed, &g).unwrap().tag(), 1);
+
+        let (prev, tag) = x.fetch_or(6, Ordering::Relaxed, &g);
+        assert_eq!(tag, 1);
+        assert_eq!(prev.unwrap().as_raw(), p.as_raw());
+        assert_eq!(x.load(Ordering::Relaxed, &g).unwrap().tag(), 7);
+
+        let (_, tag) = x.fetch_and(2, Ordering::Relaxed, &g);
+        assert_eq!(tag, 7);
+        let q = x.load(Ordering::Relaxed, &g).unwrap();
+        assert_eq!(q.tag(), 2);
+        assert_eq!(*q, 7);
+
+        unsafe { g.defer_drop(q); }
+    }
+
+    #[test]
+    fn tagged_null() {
+        let g = pin();
+        let x: Atomic<u64> = Atomic::null();
+        x.fetch_or(1, Ordering::Relaxed, &g);
+        assert_eq!(x.load_tagged(Ordering::Relaxed, &g), (None, 1));
+
+        // a tagged null is not the same as a plain null
+        assert!(x.cas(None, Some(Owned::new(1)), Ordering::Relaxed).is_err());
+        assert!(!x.cas_shared(None, None, Ordering::Relaxed));
+        x.fetch_and(0, Ordering::Relaxed, &g);
+        assert!(x.cas_shared(None, None, Ordering::Relaxed));
+    }
+
+    #[test]
+    #[should_panic]
+    fn tag_too_large() {
+        Owned::new(7u64).with_tag(mem::align_of::<u64>());
+    }
+
     #[test]
     fn test_new() {
         let guard = epoch::pin();
         let my_atomic = Atomic::new(42);
 
-        assert_eq!(**my_atomic.load(Ordering::Relaxed, &guard).unwrap(), 42);
+        assert_eq!(*my_atomic.load(Ordering::Relaxed, &guard).unwrap(), 42);
     }
 }
@@ -4,10 +4,13 @@
 use std::mem;
 use std::cell::UnsafeCell;
 use std::fmt;
+use std::sync::Arc;
 use std::sync::atomic::{self, AtomicUsize, AtomicBool};
 use std::sync::atomic::Ordering::{Relaxed, Acquire, Release, SeqCst};
+use std::thread::{self, Thread};
 
-use mem::epoch::{Atomic, Guard, garbage, global};
+use mem::epoch::{Atomic, Guard, garbage, watchdog};
+use mem::epoch::global::EpochState;
 use mem::epoch::participants::ParticipantNode;
 
 /// Thread-local data for epoch participation.
@@ -19,13 +22,35 @@ pub struct Participant {
     /// reentrant use of epoch management.
     in_critical: AtomicUsize,
 
+    /// When the outermost critical section was last entered while a watchdog
+    /// was installed, as given by `watchdog::now_millis() + 1`, or `0` if
+    /// never. Left alone otherwise, so it may be stale.
+    pinned_at: AtomicUsize,
+
+    /// The thread this participant belongs to.
+    thread: Thread,
+
     /// Thread-local garbage tracking
     garbage: UnsafeCell<garbage::Local>,
 
+    /// Number of items and bytes in `garbage` as of the last collection or
+    /// migration, mirrored here so that other threads can read them for
+    /// statistics.
+    garbage_items: AtomicUsize,
+    garbage_bytes: AtomicUsize,
+
     /// Is the thread still active? Becomes `false` when the thread exits. This
     /// is ultimately used to free `Participant` records.
     pub active: AtomicBool,
 
+    /// Has the `LocalHandle` for this record been dropped? The record is
+    /// retired once this is set and no guard is left.
+    detached: AtomicBool,
+
+    /// The epoch state this record belongs to, kept alive until the record is
+    /// retired. `None` for the default collector, which is never freed.
+    owner: UnsafeCell<Option<Arc<EpochState>>>,
+
     /// The participant list is coded intrusively; here's the `next` pointer.
     pub next: Atomic<ParticipantNode>,
 }
@@ -41,12 +66,18 @@ unsafe impl Sync for Participant {}
 const GC_THRESH: usize = 32;
 
 impl Participant {
-    pub fn new() -> Participant {
+    pub fn new(owner: Option<Arc<EpochState>>) -> Participant {
         Participant {
             epoch: AtomicUsize::new(0),
             in_critical: AtomicUsize::new(0),
+            pinned_at: AtomicUsize::new(0),
+            thread: thread::current(),
             active: AtomicBool::new(true),
+            detached: AtomicBool::new(false),
+            owner: UnsafeCell::new(owner),
             garbage: UnsafeCell::new(garbage::Local::new()),
+            garbage_items: AtomicUsize::new(0),
+            garbage_bytes: AtomicUsize::new(0),
             next: Atomic::null(),
         }
     }
@@ -57,22 +88,34 @@ impl Participant {
     ///
     /// Returns `true` is this is the first entry on the stack (as opposed to a
     /// re-entrant call).
-    pub fn enter(&self) -> bool {
+    pub fn enter(&self, global: &EpochState) -> bool {
         let new_count = self.in_critical.load(Relaxed) + 1;
         self.in_critical.store(new_count, Relaxed);
         if new_count > 1 { return false }
 
         atomic::fence(SeqCst);
 
-        let global_epoch = global::get().epoch.load(Relaxed);
+        if watchdog::is_enabled(global) {
+            self.record_pin_time();
+        }
+
+        let global_epoch = global.epoch.load(Relaxed);
         if global_epoch != self.epoch.load(Relaxed) {
             self.epoch.store(global_epoch, Relaxed);
             unsafe { (*self.garbage.get()).collect(); }
+            self.publish_garbage_stats();
         }
 
         true
     }
 
+    // Kept out of line: the watchdog is off by default, and `enter` is hot.
+    #[cold]
+    #[inline(never)]
+    fn record_pin_time(&self) {
+        self.pinned_at.store(watchdog::now_millis() + 1, Relaxed);
+    }
+
     /// Exit the current (nested) critical section.
     pub fn exit(&self) {
         let new_count = self.in_critical.load(Relaxed) - 1;
@@ -86,40 +129,124 @@ impl Participant {
         (*self.garbage.get()).insert(data);
     }
 
+    /// Begin the reclamation process for a piece of data, dropping it (rather
+    /// than merely deallocating it) once it is safe to do so.
+    pub unsafe fn reclaim_drop<T>(&self, data: *mut T) {
+        (*self.garbage.get()).insert_drop(data);
+    }
+
+    /// Schedule `f` to run once no thread can observe the current state of
+    /// the epoch anymore.
+    pub fn defer<F: FnOnce() + Send + 'static>(&self, f: F) {
+        unsafe { (*self.garbage.get()).insert_fn(f) }
+    }
+
+    // Only called when collecting or migrating, to keep `reclaim` and friends
+    // cheap; statistics lag behind by the garbage added since.
+    fn publish_garbage_stats(&self) {
+        let local = unsafe { &*self.garbage.get() };
+        self.garbage_items.store(local.size(), Relaxed);
+        self.garbage_bytes.store(local.bytes(), Relaxed);
+    }
+
     /// Attempt to collect garbage by moving the global epoch forward.
     ///
     /// Returns `true` on success.
-    pub fn try_collect(&self, guard: &Guard) -> bool {
-        let cur_epoch = global::get().epoch.load(SeqCst);
+    pub fn try_collect(&self, global: &EpochState, guard: &Guard) -> bool {
+        let cur_epoch = global.epoch.load(SeqCst);
 
-        for p in global::get().participants.iter(guard) {
-            if p.in_critical.load(Relaxed) > 0 && p.epoch.load(Relaxed) != cur_epoch {
-                return false
-            }
+        if global.participants.iter(guard).any(|p| p.blocks(cur_epoch)) {
+            global.advances.failed.fetch_add(1, Relaxed);
+            return false
         }
 
         let new_epoch = cur_epoch.wrapping_add(1);
         atomic::fence(Acquire);
-        if global::get().epoch.compare_and_swap(cur_epoch, new_epoch, SeqCst) != cur_epoch {
+        if global.epoch.compare_exchange(cur_epoch, new_epoch, SeqCst, SeqCst).is_err() {
             return false
         }
 
         unsafe {
             (*self.garbage.get()).collect();
-            global::get().garbage[new_epoch.wrapping_add(1) % 3].collect();
+            global.garbage[new_epoch.wrapping_add(1) % 3].collect();
         }
+        self.publish_garbage_stats();
+        global.advances.succeeded.fetch_add(1, Relaxed);
         self.epoch.store(new_epoch, Release);
 
         true
     }
 
     /// Move the current thread-local garbage into the global garbage bags.
-    pub fn migrate_garbage(&self) {
+    pub fn migrate_garbage(&self, global: &EpochState) {
         let cur_epoch = self.epoch.load(Relaxed);
         let local = unsafe { mem::replace(&mut *self.garbage.get(), garbage::Local::new()) };
-        global::get().garbage[cur_epoch.wrapping_sub(1) % 3].insert(local.old);
-        global::get().garbage[cur_epoch % 3].insert(local.cur);
-        global::get().garbage[global::get().epoch.load(Relaxed) % 3].insert(local.new);
+        global.garbage[cur_epoch.wrapping_sub(1) % 3].insert(local.old);
+        global.garbage[cur_epoch % 3].insert(local.cur);
+        global.garbage[global.epoch.load(Relaxed) % 3].insert(local.new);
+        self.publish_garbage_stats();
+    }
+
+    /// The number of items and bytes of garbage this participant is storing.
+    ///
+    /// Unlike `garbage_size`, this may be called from any thread, but the
+    /// result is only updated when the participant collects or migrates its
+    /// garbage.
+    pub fn pending_garbage(&self) -> (usize, usize) {
+        (self.garbage_items.load(Relaxed), self.garbage_bytes.load(Relaxed))
+    }
+
+    /// Is this participant pinned in an epoch other than `epoch`, thereby
+    /// preventing the global epoch from advancing past it?
+    pub fn blocks(&self, epoch: usize) -> bool {
+        self.in_critical.load(Relaxed) > 0 && self.epoch.load(Relaxed) != epoch
+    }
+
+    /// The thread this participant belongs to.
+    pub fn thread(&self) -> &Thread {
+        &self.thread
+    }
+
+    /// When the participant last entered a critical section while a watchdog
+    /// was installed, in `watchdog::now_millis()` time, if ever.
+    pub fn pinned_at(&self) -> Option<usize> {
+        match self.pinned_at.load(Relaxed) {
+            0 => None,
+            at => Some(at - 1),
+        }
+    }
+
+    /// Is this participant currently in a critical section?
+    pub fn is_pinned(&self) -> bool {
+        self.in_critical.load(Relaxed) > 0
+    }
+
+    /// Record that the handle owning this participant is gone, retiring the
+    /// participant right away unless a guard is still alive.
+    ///
+    /// Must be called from the thread owning the participant.
+    pub unsafe fn detach(&self, global: &EpochState) {
+        self.detached.store(true, Relaxed);
+        self.maybe_retire(global);
+    }
+
+    /// Retire the participant if it has been detached and is no longer
+    /// pinned. Called when the last guard goes away.
+    ///
+    /// Must be called from the thread owning the participant. After
+    /// retirement, neither `self` nor `global` may be used again.
+    pub unsafe fn maybe_retire(&self, global: &EpochState) {
+        if !self.detached.load(Relaxed) || self.is_pinned() { return }
+
+        self.enter(global);
+        self.migrate_garbage(global);
+        self.exit();
+
+        // Take the owner out before going inactive, then release it last:
+        // it may be the final reference to `global`, which owns this record.
+        let owner = (*self.owner.get()).take();
+        self.active.store(false, Release);
+        drop(owner);
     }
 
     /// How much garbage is this participant currently storing?
@@ -1,27 +1,39 @@
 // Manages the global participant list, which is an intrustive list in
 // which items are lazily removed on traversal (after being
 // "logically" deleted by becoming inactive.)
+//
+// New records are only ever pushed at the head, and only one traversal at a
+// time (the "cleaner") is allowed to unlink inactive records. With a single
+// remover, a record's `next` pointer can only change while the record itself
+// is still linked, so an unlinked record can never become reachable again and
+// is safe to hand to the epoch garbage.
 
 use std::mem;
 use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::{Relaxed, Acquire, Release};
 
-use mem::epoch::{Atomic, Owned, Guard};
+use mem::epoch::{Atomic, Owned, Shared, Guard};
+use mem::epoch::global::EpochState;
 use mem::epoch::participant::Participant;
 use mem::CachePadded;
 
 /// Global, threadsafe list of threads participating in epoch management.
 #[derive(Debug)]
 pub struct Participants {
-    head: Atomic<ParticipantNode>
+    head: Atomic<ParticipantNode>,
+
+    /// Is some traversal currently unlinking inactive records?
+    cleaning: AtomicBool,
 }
 
 #[derive(Debug)]
 pub struct ParticipantNode(CachePadded<Participant>);
 
 impl ParticipantNode {
-    pub fn new() -> ParticipantNode {
-        ParticipantNode(CachePadded::new(Participant::new()))
+    pub fn new(owner: Option<Arc<EpochState>>) -> ParticipantNode {
+        ParticipantNode(CachePadded::new(Participant::new(owner)))
     }
 }
 
@@ -41,18 +53,20 @@ impl DerefMut for ParticipantNode {
 impl Participants {
     #[cfg(not(feature = "nightly"))]
     pub fn new() -> Participants {
-        Participants { head: Atomic::null() }
+        Participants { head: Atomic::null(), cleaning: AtomicBool::new(false) }
     }
 
     #[cfg(feature = "nightly")]
     pub const fn new() -> Participants {
-        Participants { head: Atomic::null() }
+        Participants { head: Atomic::null(), cleaning: AtomicBool::new(false) }
     }
 
     /// Enroll a new thread in epoch management by adding a new `Particpant`
     /// record to the global list.
-    pub fn enroll(&self) -> *const Participant {
-        let mut participant = Owned::new(ParticipantNode::new());
+    ///
+    /// The `owner`, if any, is kept alive by the record until it is retired.
+    pub fn enroll(&self, owner: Option<Arc<EpochState>>) -> *const Participant {
+        let mut participant = Owned::new(ParticipantNode::new(owner));
 
         // we ultimately use epoch tracking to free Participant nodes, but we
         // can't actually enter an epoch here, so fake it; we know the node
@@ -64,8 +78,8 @@ impl Participants {
             participant.next.store_shared(head, Relaxed);
             match self.head.cas_and_ref(head, participant, Release, g) {
                 Ok(shared) => {
-                    let shared: &Participant = &shared;
-                    return shared;
+                    let node: &'static ParticipantNode = shared.as_ref();
+                    return &**node;
                 }
                 Err(owned) => {
                     participant = owned;
@@ -74,13 +88,80 @@ impl Participants {
         }
     }
 
+    /// Free every record in the list.
+    ///
+    /// Only safe when no thread can be using the list anymore, i.e. when the
+    /// owning `EpochState` is being destroyed.
+    pub unsafe fn free_all(&self) {
+        let fake_guard = ();
+        let g: &'static Guard = mem::transmute(&fake_guard);
+        let mut cur = self.head.swap(None, Relaxed, g);
+        while let Some(n) = cur {
+            cur = n.next.load(Relaxed, g);
+            drop(Box::from_raw(n.as_raw()));
+        }
+    }
+
+    /// Iterate over the active participants.
+    ///
+    /// If no other traversal is doing so already, inactive records passed
+    /// along the way are unlinked and handed to `g` for reclamation.
     pub fn iter<'a>(&'a self, g: &'a Guard) -> Iter<'a> {
+        let cleaner = if !self.cleaning.load(Relaxed) &&
+            !self.cleaning.swap(true, Acquire)
+        {
+            Some(&self.cleaning)
+        } else {
+            None
+        };
+
         Iter {
             guard: g,
             next: &self.head,
             needs_acq: true,
+            cleaner: cleaner,
+        }
+    }
+
+    /// Iterate over every linked record, active or not, without unlinking
+    /// anything.
+    pub fn records<'a>(&'a self, g: &'a Guard) -> Records<'a> {
+        Records {
+            guard: g,
+            cur: self.head.load(Acquire, g),
         }
     }
+
+    /// Number of records currently linked, whether active or not.
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        let fake_guard = ();
+        let g: &'static Guard = unsafe { mem::transmute(&fake_guard) };
+        let mut cur = self.head.load(Acquire, g);
+        let mut len = 0;
+        while let Some(n) = cur {
+            len += 1;
+            cur = n.next.load(Acquire, g);
+        }
+        len
+    }
+}
+
+#[derive(Debug)]
+pub struct Records<'a> {
+    guard: &'a Guard,
+    cur: Option<Shared<'a, ParticipantNode>>,
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = &'a Participant;
+    fn next(&mut self) -> Option<&'a Participant> {
+        self.cur.map(|n| {
+            self.cur = n.next.load(Acquire, self.guard);
+            let n: &'a ParticipantNode = n.as_ref();
+            &**n
+        })
+    }
 }
 
 #[derive(Debug)]
@@ -92,6 +173,18 @@ pub struct Iter<'a> {
     // an Acquire read is needed only for the first read, due to release
     // sequences
     needs_acq: bool,
+
+    // the list's cleaning flag, if this traversal holds it and may therefore
+    // unlink inactive nodes
+    cleaner: Option<&'a AtomicBool>,
+}
+
+impl<'a> Drop for Iter<'a> {
+    fn drop(&mut self) {
+        if let Some(flag) = self.cleaner {
+            flag.store(false, Release);
+        }
+    }
 }
 
 impl<'a> Iterator for Iter<'a> {
@@ -106,15 +199,82 @@ impl<'a> Iterator for Iter<'a> {
 
         while let Some(n) = cur {
             // attempt to clean up inactive nodes
-            if !n.active.load(Relaxed) {
-                cur = n.next.load(Relaxed, self.guard);
-                // TODO: actually reclaim inactive participants!
+            if !n.active.load(Acquire) {
+                let succ = n.next.load(Relaxed, self.guard);
+                // the CAS fails if a new node was just pushed at the head, or
+                // if an earlier inactive node was left in place; either way,
+                // this node is left for a later traversal.
+                if self.cleaner.is_some() &&
+                    self.next.cas_shared(Some(n), succ, Release)
+                {
+                    // dropped, not just freed: the record still owns its
+                    // `Thread` handle
+                    unsafe { self.guard.defer_drop(n); }
+                }
+                cur = succ;
             } else {
+                let n = n.as_ref();
                 self.next = &n.next;
-                return Some(&n)
+                return Some(n)
             }
         }
 
         None
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::thread;
+
+    use mem::epoch::global::EpochState;
+    use mem::epoch::local;
+
+    #[test]
+    fn reclaim_inactive() {
+        let global = Arc::new(EpochState::new());
+        let main = local::register(&global, Some(global.clone()));
+
+        for _ in 0..10 {
+            let h = local::register(&global, Some(global.clone()));
+            drop(h.pin());
+        }
+        assert_eq!(global.participants.len(), 11);
+
+        let g = main.pin();
+        assert_eq!(global.participants.iter(&g).count(), 1);
+        assert_eq!(global.participants.len(), 1);
+    }
+
+    #[test]
+    fn bounded_across_thread_spawns() {
+        const BATCH: usize = 100;
+        const SPAWNS: usize = 100000;
+
+        let global = Arc::new(EpochState::new());
+        let main = local::register(&global, Some(global.clone()));
+
+        for _ in 0..SPAWNS / BATCH {
+            let threads = (0..BATCH).map(|_| {
+                let global = global.clone();
+                thread::spawn(move || {
+                    let h = local::register(&global, Some(global.clone()));
+                    drop(h.pin());
+                })
+            }).collect::<Vec<_>>();
+            for t in threads {
+                t.join().unwrap();
+            }
+
+            // a full traversal unlinks every exited thread's record; pinning
+            // repeatedly lets the epoch advance and free them.
+            for _ in 0..3 {
+                let g = main.pin();
+                assert_eq!(global.participants.iter(&g).count(), 1);
+            }
+            assert!(global.participants.len() <= BATCH + 1);
+        }
+        assert_eq!(global.participants.len(), 1);
+    }
+}
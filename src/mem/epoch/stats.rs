@@ -0,0 +1,140 @@
+// Snapshots of the state of an epoch collector, for monitoring reclamation.
+
+use std::sync::atomic::Ordering::Relaxed;
+
+use mem::epoch::{global, Guard, pin};
+use mem::epoch::global::EpochState;
+
+/// Amount of garbage waiting to be reclaimed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BagStats {
+    /// Number of pending items (freed pointers, dropped values and deferred
+    /// closures).
+    pub items: usize,
+    /// Total size of the pending items, in bytes.
+    pub bytes: usize,
+}
+
+/// A snapshot of the state of an epoch collector.
+///
+/// The figures are gathered without stopping other threads, so they are only
+/// approximate when the collector is in concurrent use.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stats {
+    /// The current global epoch.
+    pub epoch: usize,
+    /// Number of threads currently participating.
+    pub active_participants: usize,
+    /// Number of records of exited threads that are still linked into the
+    /// participant list.
+    pub inactive_participants: usize,
+    /// Garbage held thread-locally by the active participants, as of the last
+    /// time each of them collected or migrated its garbage.
+    pub local: BagStats,
+    /// Garbage held in the global bags, indexed by epoch modulo 3. This is
+    /// where the garbage of exited threads ends up.
+    pub global: [BagStats; 3],
+    /// Number of times the global epoch has been advanced.
+    pub advances: usize,
+    /// Number of attempts to advance the global epoch that failed because a
+    /// thread was still pinned in an older epoch.
+    pub failed_advances: usize,
+}
+
+/// Take a snapshot of the state of the default collector.
+pub fn stats() -> Stats {
+    let guard = pin();
+    gather(global::get(), &guard)
+}
+
+/// Take a snapshot of `global`, which `guard` must be pinning.
+pub fn gather(global: &EpochState, guard: &Guard) -> Stats {
+    let mut stats = Stats {
+        epoch: global.epoch.load(Relaxed),
+        active_participants: 0,
+        inactive_participants: 0,
+        local: BagStats::default(),
+        global: [BagStats::default(); 3],
+        advances: global.advances.succeeded.load(Relaxed),
+        failed_advances: global.advances.failed.load(Relaxed),
+    };
+
+    for p in global.participants.records(guard) {
+        if p.active.load(Relaxed) {
+            let (items, bytes) = p.pending_garbage();
+            stats.active_participants += 1;
+            stats.local.items += items;
+            stats.local.bytes += bytes;
+        } else {
+            stats.inactive_participants += 1;
+        }
+    }
+
+    for (s, bag) in stats.global.iter_mut().zip(global.garbage.iter()) {
+        s.items = bag.len();
+        s.bytes = bag.bytes();
+    }
+
+    stats
+}
+
+#[cfg(test)]
+mod test {
+    use mem::epoch::{self, Collector};
+
+    #[test]
+    fn default_collector() {
+        let stats = epoch::stats();
+        assert!(stats.active_participants >= 1);
+    }
+
+    #[test]
+    fn pending_garbage() {
+        let collector = Collector::new();
+        let handle = collector.register();
+        {
+            let g = handle.pin();
+            for i in 0..10u64 {
+                g.defer(move || assert!(i < 10));
+            }
+        }
+
+        // local figures are only published when collecting or migrating
+        let stats = handle.stats();
+        assert_eq!(stats.active_participants, 1);
+        assert_eq!(stats.inactive_participants, 0);
+        assert_eq!(stats.local.items, 0);
+        assert_eq!(stats.global.iter().map(|b| b.items).sum::<usize>(), 0);
+
+        handle.pin().migrate_garbage();
+        let stats = handle.stats();
+        assert_eq!(stats.local.items, 0);
+        assert_eq!(stats.global.iter().map(|b| b.items).sum::<usize>(), 10);
+        assert_eq!(stats.global.iter().map(|b| b.bytes).sum::<usize>(), 80);
+    }
+
+    #[test]
+    fn advances() {
+        let collector = Collector::new();
+        let stalled = collector.register();
+        let handle = collector.register();
+
+        let _pinned = stalled.pin();
+        for _ in 0..2 {
+            // enough garbage that the next pin attempts to advance the epoch
+            let g = handle.pin();
+            for _ in 0..32 {
+                g.defer(|| ());
+            }
+        }
+
+        // the first attempt succeeds, as `stalled` is pinned in the current
+        // epoch; after that, it holds everything up, starting with the
+        // attempt made when `stats` pins.
+        let stats = handle.stats();
+        assert_eq!(stats.epoch, 1);
+        assert_eq!(stats.advances, 1);
+        assert_eq!(stats.failed_advances, 1);
+        assert_eq!(stats.active_participants, 2);
+    }
+}
@@ -0,0 +1,327 @@
+// Opt-in detection of threads that stay pinned long enough to hold up
+// reclamation.
+//
+// When a watchdog is installed, participants record when they enter their
+// outermost critical section, and a background thread samples the global
+// epoch a few times per threshold. Once the epoch has stood still for longer
+// than the threshold, the thread pins the collector itself and reports the
+// participants pinned in an older epoch to the user's callback. Sampling from
+// a thread of its own means a stall is reported even if nobody is trying to
+// advance the epoch anymore.
+
+use std::cmp;
+use std::fmt;
+use std::ptr;
+use std::sync::{Arc, Condvar, Mutex, Once, Weak};
+use std::sync::atomic::Ordering::{Relaxed, SeqCst};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+use mem::epoch::{global, local};
+use mem::epoch::garbage::Bag;
+use mem::epoch::global::EpochState;
+
+/// A thread that has been pinned in an old epoch, blocking reclamation.
+#[derive(Clone, Debug)]
+pub struct StalledPin {
+    /// The thread holding the guard.
+    pub thread: Thread,
+    /// How long the thread has been pinned, if known. This is `None` if the
+    /// thread was already pinned when the watchdog was installed.
+    pub pinned_for: Option<Duration>,
+}
+
+/// An installed watchdog.
+///
+/// This is only the handle kept in the `EpochState`; the reporting itself is
+/// done by a background thread, which exits once the handle is dropped.
+pub struct Watchdog {
+    control: Arc<Control>,
+}
+
+/// The user's report handler.
+pub type Callback = Box<dyn Fn(&[StalledPin]) + Send + Sync>;
+
+struct Control {
+    stopped: Mutex<bool>,
+    wake: Condvar,
+}
+
+/// The collector a watchdog thread samples.
+pub enum Target {
+    /// The default collector, which lives forever
+    Default,
+    /// A `Collector`; the thread exits once it is gone
+    Collector(Weak<EpochState>),
+}
+
+impl Watchdog {
+    fn stop(&self) {
+        *self.control.stopped.lock().unwrap() = true;
+        self.control.wake.notify_one();
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl fmt::Debug for Watchdog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Watchdog {{ ... }}")
+    }
+}
+
+/// Milliseconds since an arbitrary, process-wide starting point.
+pub fn now_millis() -> usize {
+    static INIT: Once = Once::new();
+    static mut START: Option<Instant> = None;
+
+    unsafe {
+        INIT.call_once(|| START = Some(Instant::now()));
+        duration_millis(START.unwrap().elapsed())
+    }
+}
+
+fn duration_millis(d: Duration) -> usize {
+    (d.as_secs() as usize).wrapping_mul(1000) + (d.subsec_nanos() / 1000000) as usize
+}
+
+/// Install `callback` as the stalled-pin watchdog of the default collector,
+/// replacing any previous one.
+///
+/// Once the global epoch has not advanced for `threshold`, `callback` is
+/// called with every thread still pinned in an older epoch; further reports
+/// follow at most once per `threshold` for as long as the stall lasts. The
+/// callback runs on a background thread owned by the watchdog, which is
+/// stopped by `clear_stall_watchdog` or by installing another watchdog.
+///
+/// Recording pin times costs a clock read on every outermost `pin()`, which is
+/// why the watchdog is off by default.
+pub fn set_stall_watchdog<F>(threshold: Duration, callback: F)
+    where F: Fn(&[StalledPin]) + Send + Sync + 'static
+{
+    install(global::get(), Target::Default, threshold, Box::new(callback))
+}
+
+/// Remove the stalled-pin watchdog of the default collector, if any.
+pub fn clear_stall_watchdog() {
+    uninstall(global::get())
+}
+
+pub fn install(global: &EpochState, target: Target, threshold: Duration,
+               callback: Callback)
+{
+    let control = Arc::new(Control {
+        stopped: Mutex::new(false),
+        wake: Condvar::new(),
+    });
+    let installed_at = now_millis();
+    let threshold = duration_millis(threshold);
+
+    {
+        let control = control.clone();
+        thread::Builder::new()
+            .name("crossbeam-epoch-watchdog".to_string())
+            .spawn(move || run(target, threshold, installed_at, callback, control))
+            .unwrap();
+    }
+
+    let watchdog = Box::new(Watchdog { control: control });
+    replace(global, Box::into_raw(watchdog));
+}
+
+pub fn uninstall(global: &EpochState) {
+    replace(global, ptr::null_mut())
+}
+
+fn replace(global: &EpochState, new: *mut Watchdog) {
+    let old = global.watchdog.swap(new, SeqCst);
+    if !old.is_null() {
+        // Stop reporting right away, but threads pinned right now may still be
+        // reading the old handle, so it is retired like any other garbage of
+        // the current epoch.
+        unsafe { (*old).stop() }
+        let mut bag = Bag::new();
+        bag.insert_drop(old);
+        global.garbage[global.epoch.load(SeqCst) % 3].insert(bag);
+    }
+}
+
+/// Is a watchdog installed, i.e. should pin times be recorded?
+#[inline]
+pub fn is_enabled(global: &EpochState) -> bool {
+    !global.watchdog.load(Relaxed).is_null()
+}
+
+// Body of the watchdog thread.
+fn run(target: Target, threshold: usize, installed_at: usize,
+       callback: Callback, control: Arc<Control>)
+{
+    // Sampling a few times per threshold bounds how late a stall is noticed.
+    let tick = Duration::from_millis(cmp::max(threshold / 4, 1) as u64);
+    let mut last_epoch = None;
+    let mut last_progress = installed_at;
+
+    loop {
+        {
+            let stopped = control.stopped.lock().unwrap();
+            if *stopped { return }
+            let (stopped, _) = control.wake.wait_timeout(stopped, tick).unwrap();
+            if *stopped { return }
+        }
+
+        let owner;
+        let global = match target {
+            Target::Default => global::get(),
+            Target::Collector(ref weak) => match weak.upgrade() {
+                Some(state) => {
+                    owner = state;
+                    &*owner
+                }
+                None => return,
+            },
+        };
+
+        let now = now_millis();
+        let epoch = global.epoch.load(SeqCst);
+        if last_epoch != Some(epoch) {
+            last_epoch = Some(epoch);
+            last_progress = now;
+            continue;
+        }
+        if now.wrapping_sub(last_progress) < threshold { continue }
+
+        let stalled = stalled_pins(global, epoch, now, installed_at);
+        if !stalled.is_empty() {
+            last_progress = now;
+            callback(&stalled);
+        }
+    }
+}
+
+// The participants pinned in an epoch other than `epoch`.
+//
+// The participant list can only be traversed while pinned, so the watchdog
+// thread briefly registers with the collector. `global` must stay alive until
+// this returns, which is why the record doesn't need to own it.
+fn stalled_pins(global: &EpochState, epoch: usize, now: usize, installed_at: usize)
+                -> Vec<StalledPin>
+{
+    let handle = local::register(global, None);
+    let guard = handle.pin();
+    global.participants.iter(&guard).filter(|p| p.blocks(epoch)).map(|p| {
+        // pin times recorded before the watchdog was installed may be stale
+        let pinned_at = p.pinned_at().and_then(|at| {
+            if at >= installed_at { Some(at) } else { None }
+        });
+        StalledPin {
+            thread: p.thread().clone(),
+            pinned_for: pinned_at.map(|at| Duration::from_millis(now.wrapping_sub(at) as u64)),
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Barrier, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use mem::epoch::Collector;
+
+    // Pin `handle` enough times, with enough garbage, to make several attempts
+    // at advancing the epoch.
+    fn churn(handle: &::mem::epoch::LocalHandle) {
+        for _ in 0..4 {
+            let g = handle.pin();
+            for _ in 0..32 {
+                g.defer(|| ());
+            }
+        }
+    }
+
+    #[test]
+    fn reports_stalled_thread() {
+        let collector = Collector::new();
+        let reports = Arc::new(Mutex::new(vec![]));
+        {
+            let reports = reports.clone();
+            collector.set_stall_watchdog(Duration::from_millis(0), move |stalled| {
+                reports.lock().unwrap().extend(stalled.iter().cloned());
+            });
+        }
+
+        let pinned = Arc::new(Barrier::new(2));
+        let release = Arc::new(Barrier::new(2));
+        let t = {
+            let collector = collector.clone();
+            let pinned = pinned.clone();
+            let release = release.clone();
+            thread::Builder::new().name("stalled".to_string()).spawn(move || {
+                let handle = collector.register();
+                let _g = handle.pin();
+                pinned.wait();
+                release.wait();
+            }).unwrap()
+        };
+
+        pinned.wait();
+        thread::sleep(Duration::from_millis(20));
+        churn(&collector.register());
+
+        // Nobody tries to advance the epoch from here on; the report has to
+        // come from the watchdog thread.
+        for _ in 0..1000 {
+            if !reports.lock().unwrap().is_empty() { break }
+            thread::sleep(Duration::from_millis(10));
+        }
+        release.wait();
+        t.join().unwrap();
+
+        let reports = reports.lock().unwrap();
+        assert!(!reports.is_empty());
+        for r in reports.iter() {
+            assert_eq!(r.thread.name(), Some("stalled"));
+            assert!(r.pinned_for.unwrap() >= Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn quiet_below_threshold() {
+        let collector = Collector::new();
+        let reports = Arc::new(Mutex::new(0));
+        {
+            let reports = reports.clone();
+            collector.set_stall_watchdog(Duration::from_secs(3600), move |_| {
+                *reports.lock().unwrap() += 1;
+            });
+        }
+
+        let stalled = collector.register();
+        let _g = stalled.pin();
+        churn(&collector.register());
+        assert_eq!(*reports.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn cleared() {
+        let collector = Collector::new();
+        let reports = Arc::new(Mutex::new(0));
+        {
+            let reports = reports.clone();
+            collector.set_stall_watchdog(Duration::from_millis(0), move |_| {
+                *reports.lock().unwrap() += 1;
+            });
+        }
+        collector.clear_stall_watchdog();
+
+        let stalled = collector.register();
+        let _g = stalled.pin();
+        churn(&collector.register());
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(*reports.lock().unwrap(), 0);
+    }
+}
@@ -0,0 +1,339 @@
+//! Hazard-pointer-based memory management
+//!
+//! This module provides an alternative to the `epoch` scheme, based on Maged
+//! Michael's [*hazard
+//! pointers*](http://www.research.ibm.com/people/m/michael/ieeetpds-2004.pdf).
+//!
+//! Epoch-based reclamation is cheap for readers, but a single thread that
+//! stays pinned holds back *all* garbage, which can then grow without bound.
+//! With hazard pointers, a thread instead announces the individual pointers it
+//! is about to dereference. Retired data is freed as soon as no announcement
+//! refers to it, so the amount of unreclaimed garbage stays bounded by the
+//! number of hazard pointers in use, whatever readers do.
+//!
+//! The price is paid by readers: every pointer read from a shared location
+//! must be protected with `protect`, which publishes it and then validates
+//! that it is still current, requiring a full memory barrier each time.
+//!
+//! Using the scheme takes two functions:
+//!
+//! - `protect` reads an `AtomicPtr` and returns a `Protected` pointer, which
+//!   keeps the data it points to from being freed until it is dropped. A
+//!   thread may hold up to eight protected pointers at a time.
+//!
+//! - `retire` and `retire_drop` hand over data that has been unlinked from a
+//!   data structure; it is freed once no protected pointer refers to it.
+//!
+//! `sync::HazardTreiberStack` is built this way, and can be compared with the
+//! epoch-based `sync::TreiberStack`.
+//!
+//! # Example
+//!
+//! ```
+//! use std::ptr;
+//! use std::sync::atomic::AtomicPtr;
+//! use std::sync::atomic::Ordering::{Relaxed, Release};
+//!
+//! use crossbeam::mem::hazard;
+//!
+//! struct TreiberStack<T> {
+//!     head: AtomicPtr<Node<T>>,
+//! }
+//!
+//! struct Node<T> {
+//!     data: T,
+//!     next: *mut Node<T>,
+//! }
+//!
+//! impl<T> TreiberStack<T> {
+//!     fn new() -> TreiberStack<T> {
+//!         TreiberStack { head: AtomicPtr::new(ptr::null_mut()) }
+//!     }
+//!
+//!     fn push(&self, t: T) {
+//!         let n = Box::into_raw(Box::new(Node { data: t, next: ptr::null_mut() }));
+//!         loop {
+//!             // no protection needed: we never dereference `head`
+//!             let head = self.head.load(Relaxed);
+//!             unsafe { (*n).next = head; }
+//!             if self.head.compare_exchange(head, n, Release, Relaxed).is_ok() {
+//!                 return;
+//!             }
+//!         }
+//!     }
+//!
+//!     fn pop(&self) -> Option<T> {
+//!         loop {
+//!             // protect the head before reading through it
+//!             let head = hazard::protect(&self.head);
+//!             match head.as_ref() {
+//!                 None => return None,
+//!                 Some(node) => {
+//!                     let raw = head.as_raw();
+//!                     if self.head.compare_exchange(raw, node.next, Release, Relaxed).is_ok() {
+//!                         unsafe {
+//!                             let data = ptr::read(&node.data);
+//!                             drop(head);
+//!                             // freed once no other thread protects it
+//!                             hazard::retire(raw);
+//!                             return Some(data);
+//!                         }
+//!                     }
+//!                 }
+//!             }
+//!         }
+//!     }
+//! }
+//! ```
+
+mod records;
+
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::atomic::AtomicPtr;
+use std::sync::atomic::Ordering::SeqCst;
+
+use self::records::Record;
+
+pub use self::records::SLOTS;
+
+/// Thread-local ownership of a hazard record.
+struct LocalRecord(&'static Record);
+
+impl Drop for LocalRecord {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+thread_local!(static LOCAL_RECORD: LocalRecord = LocalRecord(records::acquire()) );
+
+fn with_record<F, T>(f: F) -> T where F: FnOnce(&'static Record) -> T {
+    LOCAL_RECORD.with(|r| f(r.0))
+}
+
+/// A pointer read with `protect`.
+///
+/// As long as this value lives, the data it points to will not be freed by
+/// `retire` or `retire_drop`, even if it is unlinked from its data structure.
+pub struct Protected<T> {
+    ptr: *mut T,
+    record: &'static Record,
+    slot: usize,
+    _marker: PhantomData<*mut ()>, // !Send and !Sync
+}
+
+impl<T> fmt::Debug for Protected<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Protected {{ ptr: {:?} }}", self.ptr)
+    }
+}
+
+/// Load the pointer stored in `src`, protecting it from reclamation.
+///
+/// This publishes the pointer as hazardous and re-reads `src` until the two
+/// agree, which guarantees that the pointer was not retired before it became
+/// protected (provided, as usual, that data is only retired after being
+/// unlinked).
+///
+/// # Panics
+///
+/// Panics if the current thread already holds `SLOTS` protected pointers.
+pub fn protect<T>(src: &AtomicPtr<T>) -> Protected<T> {
+    with_record(|record| {
+        let slot = record.acquire_slot();
+        let mut ptr = src.load(SeqCst);
+        loop {
+            record.set_hazard(slot, ptr as *mut u8);
+            let cur = src.load(SeqCst);
+            if cur == ptr { break }
+            ptr = cur;
+        }
+        Protected {
+            ptr: ptr,
+            record: record,
+            slot: slot,
+            _marker: PhantomData,
+        }
+    })
+}
+
+impl<T> Protected<T> {
+    /// The protected raw pointer, which may be null.
+    pub fn as_raw(&self) -> *mut T {
+        self.ptr
+    }
+
+    /// Is the protected pointer null?
+    pub fn is_null(&self) -> bool {
+        self.ptr.is_null()
+    }
+
+    /// Borrow the data behind the pointer, if it is not null.
+    pub fn as_ref(&self) -> Option<&T> {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> Drop for Protected<T> {
+    fn drop(&mut self) {
+        self.record.release_slot(self.slot);
+    }
+}
+
+/// Free `ptr` once it is no longer protected by any thread.
+///
+/// Like `epoch::Guard::unlinked`, only the memory is freed; the value's
+/// destructor is *not* run.
+///
+/// # Safety
+///
+/// `ptr` must have been allocated with `Box`, must have been unlinked from any
+/// shared data structure (so that no new protected pointer to it can be
+/// created), and must not be retired twice.
+pub unsafe fn retire<T>(ptr: *mut T) {
+    unsafe fn free<T>(t: *mut u8) {
+        drop(Vec::from_raw_parts(t as *mut T, 0, 1));
+    }
+    with_record(|r| r.retire(ptr as *mut u8, free::<T>))
+}
+
+/// Drop and free `ptr` once it is no longer protected by any thread.
+///
+/// # Safety
+///
+/// As for `retire`. Additionally, the value must not have been moved out of,
+/// and its destructor may run on any thread.
+pub unsafe fn retire_drop<T>(ptr: *mut T) {
+    unsafe fn free_drop<T>(t: *mut u8) {
+        drop(Box::from_raw(t as *mut T));
+    }
+    with_record(|r| r.retire(ptr as *mut u8, free_drop::<T>))
+}
+
+/// Try to free the current thread's retired data right away, rather than
+/// waiting for enough of it to accumulate.
+pub fn scan() {
+    with_record(|r| unsafe { r.scan() })
+}
+
+#[cfg(test)]
+mod test {
+    use std::ptr;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicPtr, AtomicUsize};
+    use std::sync::atomic::Ordering::{Relaxed, SeqCst};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    struct Counted(Arc<AtomicUsize>);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, SeqCst);
+        }
+    }
+
+    #[test]
+    fn protected_survives_retire() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let raw = Box::into_raw(Box::new(Counted(drops.clone())));
+        let src = AtomicPtr::new(raw);
+
+        let p = protect(&src);
+        src.store(ptr::null_mut(), SeqCst);
+        unsafe { retire_drop(raw); }
+        scan();
+        assert_eq!(drops.load(SeqCst), 0);
+        assert!(p.as_ref().is_some());
+
+        drop(p);
+        scan();
+        assert_eq!(drops.load(SeqCst), 1);
+    }
+
+    #[test]
+    fn protected_by_other_thread() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let raw = Box::into_raw(Box::new(Counted(drops.clone())));
+        let src = Arc::new(AtomicPtr::new(raw));
+
+        let (tx, rx) = ::std::sync::mpsc::channel();
+        let (done_tx, done_rx) = ::std::sync::mpsc::channel();
+        let t = {
+            let src = src.clone();
+            thread::spawn(move || {
+                let p = protect(&src);
+                tx.send(()).unwrap();
+                done_rx.recv().unwrap();
+                drop(p);
+            })
+        };
+
+        rx.recv().unwrap();
+        src.store(ptr::null_mut(), SeqCst);
+        unsafe { retire_drop(raw); }
+        scan();
+        assert_eq!(drops.load(SeqCst), 0);
+
+        done_tx.send(()).unwrap();
+        t.join().unwrap();
+        scan();
+        assert_eq!(drops.load(SeqCst), 1);
+    }
+
+    #[test]
+    fn null() {
+        let src: AtomicPtr<u64> = AtomicPtr::new(ptr::null_mut());
+        let p = protect(&src);
+        assert!(p.is_null());
+        assert!(p.as_ref().is_none());
+    }
+
+    #[test]
+    #[should_panic]
+    fn too_many_slots() {
+        let src = AtomicPtr::new(Box::into_raw(Box::new(0u64)));
+        let _ps = (0..SLOTS + 1).map(|_| protect(&src)).collect::<Vec<_>>();
+    }
+
+    #[test]
+    fn slots_reused() {
+        let src = AtomicPtr::new(Box::into_raw(Box::new(0u64)));
+        for _ in 0..10 * SLOTS {
+            let _p = protect(&src);
+        }
+        unsafe { retire_drop(src.load(Relaxed)); }
+    }
+
+    #[test]
+    fn adopt_garbage_of_exited_thread() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let raw = Box::into_raw(Box::new(Counted(drops.clone())));
+        let src = Arc::new(AtomicPtr::new(raw));
+
+        // Still protected here when the retiring thread exits, so the item is
+        // left behind on its released record.
+        let p = protect(&src);
+        {
+            let src = src.clone();
+            thread::spawn(move || unsafe {
+                let raw = src.swap(ptr::null_mut(), SeqCst);
+                retire_drop(raw);
+            }).join().unwrap();
+        }
+        assert_eq!(drops.load(SeqCst), 0);
+
+        // Another thread may have reused the record in the meantime, in which
+        // case it frees the item itself.
+        drop(p);
+        for _ in 0..1000 {
+            scan();
+            if drops.load(SeqCst) == 1 { break }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(drops.load(SeqCst), 1);
+    }
+}
@@ -0,0 +1,221 @@
+// Manages the global list of hazard records, one per thread. Like the epoch
+// participant list, this is an intrusive list to which records are only ever
+// pushed at the head. Records of exited threads are never freed; instead they
+// become inactive and are handed to the next thread that needs one. Garbage
+// they still hold is adopted by the next thread to scan, so it doesn't wait
+// for the record to be reused.
+
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{self, AtomicBool, AtomicPtr, AtomicUsize};
+use std::sync::atomic::Ordering::{Relaxed, Acquire, Release, SeqCst};
+
+use mem::CachePadded;
+
+/// Number of hazard pointers a single thread may hold at once.
+pub const SLOTS: usize = 8;
+
+/// Minimum number of retired items a thread accumulates before scanning.
+const SCAN_THRESH: usize = 64;
+
+/// One retired item, waiting for no hazard pointer to refer to it.
+#[derive(Debug)]
+struct Retired {
+    ptr: *mut u8,
+    free: unsafe fn(*mut u8),
+}
+
+/// Per-thread hazard pointers and retired items.
+pub struct Record {
+    /// The published hazard pointers; null when unused.
+    hazards: [AtomicPtr<u8>; SLOTS],
+
+    /// Bitmask of the slots currently handed out. Only accessed by the owner.
+    used: AtomicUsize,
+
+    /// Items retired by the owner. Only accessed by the owner.
+    retired: UnsafeCell<Vec<Retired>>,
+
+    /// Is the record owned by a live thread?
+    active: AtomicBool,
+
+    /// The record list is coded intrusively; here's the `next` pointer.
+    next: AtomicPtr<Node>,
+}
+
+impl fmt::Debug for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Record {{ ... }}")
+    }
+}
+
+unsafe impl Sync for Record {}
+
+#[derive(Debug)]
+struct Node(CachePadded<Record>);
+
+impl Record {
+    fn new() -> Record {
+        Record {
+            hazards: [AtomicPtr::new(ptr::null_mut()), AtomicPtr::new(ptr::null_mut()),
+                      AtomicPtr::new(ptr::null_mut()), AtomicPtr::new(ptr::null_mut()),
+                      AtomicPtr::new(ptr::null_mut()), AtomicPtr::new(ptr::null_mut()),
+                      AtomicPtr::new(ptr::null_mut()), AtomicPtr::new(ptr::null_mut())],
+            used: AtomicUsize::new(0),
+            retired: UnsafeCell::new(Vec::new()),
+            active: AtomicBool::new(true),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Claim a free hazard slot.
+    ///
+    /// # Panics
+    ///
+    /// Panics if all `SLOTS` slots are in use.
+    pub fn acquire_slot(&self) -> usize {
+        let used = self.used.load(Relaxed);
+        let slot = (!used).trailing_zeros() as usize;
+        assert!(slot < SLOTS, "a thread may hold at most {} hazard pointers at once", SLOTS);
+        self.used.store(used | (1 << slot), Relaxed);
+        slot
+    }
+
+    /// Clear a hazard slot and make it available again.
+    pub fn release_slot(&self, slot: usize) {
+        self.hazards[slot].store(ptr::null_mut(), Release);
+        self.used.store(self.used.load(Relaxed) & !(1 << slot), Relaxed);
+    }
+
+    /// Publish `ptr` in a previously acquired slot.
+    pub fn set_hazard(&self, slot: usize, ptr: *mut u8) {
+        self.hazards[slot].store(ptr, SeqCst);
+    }
+
+    /// Retire an item, scanning for reclaimable items if enough have piled
+    /// up.
+    pub unsafe fn retire(&self, ptr: *mut u8, free: unsafe fn(*mut u8)) {
+        let len = {
+            let retired = &mut *self.retired.get();
+            retired.push(Retired { ptr: ptr, free: free });
+            retired.len()
+        };
+        if len >= scan_threshold() {
+            self.scan();
+        }
+    }
+
+    /// Free every retired item that no thread currently protects, including
+    /// those left behind by exited threads.
+    pub unsafe fn scan(&self) {
+        // pairs with the `SeqCst` publication of hazards: any thread that
+        // published a pointer before we unlinked it is visible here.
+        atomic::fence(SeqCst);
+
+        let mut hazards = vec![];
+        for r in records() {
+            if r.try_claim() {
+                (*self.retired.get()).append(&mut *r.retired.get());
+                r.active.store(false, Release);
+            }
+            for h in r.hazards.iter() {
+                let h = h.load(Acquire);
+                if !h.is_null() {
+                    hazards.push(h);
+                }
+            }
+        }
+        hazards.sort();
+
+        let retired = mem::replace(&mut *self.retired.get(), Vec::new());
+        let mut still_hazardous = Vec::with_capacity(retired.len());
+        for item in retired {
+            if hazards.binary_search(&item.ptr).is_ok() {
+                still_hazardous.push(item);
+            } else {
+                (item.free)(item.ptr);
+            }
+        }
+        *self.retired.get() = still_hazardous;
+    }
+
+    /// Take ownership of the record if it is inactive.
+    fn try_claim(&self) -> bool {
+        !self.active.load(Relaxed) &&
+            self.active.compare_exchange(false, true, Acquire, Relaxed).is_ok()
+    }
+
+    /// Give up ownership of the record, e.g. when the owning thread exits.
+    ///
+    /// Whatever garbage cannot be freed right away stays with the record, to
+    /// be adopted by the next thread that scans or reuses it.
+    pub fn release(&self) {
+        for slot in 0..SLOTS {
+            self.hazards[slot].store(ptr::null_mut(), Release);
+        }
+        self.used.store(0, Relaxed);
+        unsafe { self.scan(); }
+        self.active.store(false, Release);
+    }
+}
+
+// The head of the record list, stored as a `usize` so that it can be
+// statically initialized.
+static HEAD: AtomicUsize = AtomicUsize::new(0);
+
+// Number of records in the list, used to scale the scanning threshold.
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+fn scan_threshold() -> usize {
+    let hazards = COUNT.load(Relaxed) * SLOTS;
+    if 2 * hazards > SCAN_THRESH { 2 * hazards } else { SCAN_THRESH }
+}
+
+/// Iterate over all records, whether active or not.
+pub fn records() -> Records {
+    Records { cur: HEAD.load(Acquire) as *const Node }
+}
+
+#[derive(Debug)]
+pub struct Records {
+    cur: *const Node,
+}
+
+impl Iterator for Records {
+    type Item = &'static Record;
+    fn next(&mut self) -> Option<&'static Record> {
+        if self.cur.is_null() {
+            None
+        } else {
+            // records are never freed, so the reference is good forever
+            let r: &'static Record = unsafe { &(*self.cur).0 };
+            self.cur = r.next.load(Acquire);
+            Some(r)
+        }
+    }
+}
+
+/// Acquire a record for the current thread, reusing an inactive one if
+/// possible.
+pub fn acquire() -> &'static Record {
+    for r in records() {
+        if r.try_claim() {
+            return r;
+        }
+    }
+
+    let node: &'static Node = unsafe {
+        &*Box::into_raw(Box::new(Node(CachePadded::new(Record::new()))))
+    };
+    COUNT.fetch_add(1, Relaxed);
+    let mut head = HEAD.load(Relaxed);
+    loop {
+        node.0.next.store(head as *mut Node, Relaxed);
+        match HEAD.compare_exchange_weak(head, node as *const Node as usize, Release, Relaxed) {
+            Ok(_) => return &node.0,
+            Err(h) => head = h,
+        }
+    }
+}
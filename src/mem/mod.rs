@@ -1,9 +1,13 @@
 //! Memory management for concurrent data structures
 //!
-//! At the moment, the only memory management scheme is epoch-based reclamation,
-//! found in the `epoch` submodule.
+//! Two memory management schemes are provided: epoch-based reclamation, found
+//! in the `epoch` submodule, and hazard pointers, found in the `hazard`
+//! submodule. Epochs are cheaper for readers and should be the default choice;
+//! hazard pointers bound the amount of unreclaimed memory even when threads
+//! stall.
 
 pub use self::cache_padded::{CachePadded, ZerosValid};
 
 pub mod epoch;
+pub mod hazard;
 mod cache_padded;
@@ -0,0 +1,367 @@
+//! Parallel operations over slices.
+//!
+//! `par_iter` and `par_chunks_mut` turn a slice into a `Producer`, which can be
+//! split in two recursively. The `par_*` methods of `Producer` split the work
+//! into pieces and run them with an `Executor`: either plain scoped threads
+//! (`Threads`) or a work-stealing `ThreadPool`. Results always come back in
+//! the order of the slice.
+//!
+//! # Example
+//!
+//! ```
+//! use crossbeam::par::{ParallelSlice, Producer, Threads};
+//! use crossbeam::pool::ThreadPool;
+//!
+//! let v = (0..1000).collect::<Vec<u64>>();
+//!
+//! let squares = v.par_iter().par_map(&Threads::new(4), |x| x * x);
+//! assert_eq!(squares[10], 100);
+//!
+//! let pool = ThreadPool::new(4);
+//! let sum = v.par_iter().par_reduce(&pool, 0, |x| *x, |a, b| a + b);
+//! assert_eq!(sum, 999 * 1000 / 2);
+//! ```
+
+use std::cmp;
+use std::iter;
+use std::ptr;
+use std::slice;
+
+use pool::ThreadPool;
+use scope;
+
+/// Something that can run two closures in parallel.
+pub trait Executor: Sync {
+    /// Number of pieces work should be split into.
+    fn parallelism(&self) -> usize;
+
+    /// Run `a` and `b`, potentially in parallel, and return both results.
+    fn join<A, B, RA, RB>(&self, a: A, b: B) -> (RA, RB)
+        where A: FnOnce() -> RA + Send,
+              B: FnOnce() -> RB + Send,
+              RA: Send,
+              RB: Send;
+}
+
+/// An executor that runs each piece of work on its own scoped thread.
+#[derive(Clone, Copy, Debug)]
+pub struct Threads {
+    num_threads: usize,
+}
+
+impl Threads {
+    /// Split work across at most `num_threads` threads, counting the calling
+    /// thread.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `num_threads` is zero.
+    pub fn new(num_threads: usize) -> Threads {
+        assert!(num_threads > 0, "need at least one thread");
+        Threads { num_threads: num_threads }
+    }
+}
+
+impl Executor for Threads {
+    fn parallelism(&self) -> usize {
+        self.num_threads
+    }
+
+    fn join<A, B, RA, RB>(&self, a: A, b: B) -> (RA, RB)
+        where A: FnOnce() -> RA + Send,
+              B: FnOnce() -> RB + Send,
+              RA: Send,
+              RB: Send
+    {
+        scope(|scope| {
+            let b = scope.spawn(b);
+            let ra = a();
+            (ra, b.join())
+        })
+    }
+}
+
+impl Executor for ThreadPool {
+    fn parallelism(&self) -> usize {
+        // a few pieces per thread, so that stealing can even out the load
+        4 * self.num_threads()
+    }
+
+    fn join<A, B, RA, RB>(&self, a: A, b: B) -> (RA, RB)
+        where A: FnOnce() -> RA + Send,
+              B: FnOnce() -> RB + Send,
+              RA: Send,
+              RB: Send
+    {
+        ThreadPool::join(self, a, b)
+    }
+}
+
+/// A sequence of items that can be split for parallel processing.
+pub trait Producer: Sized + Send {
+    /// The items produced.
+    type Item;
+
+    /// Sequential iterator over the items.
+    type IntoIter: Iterator<Item = Self::Item>;
+
+    /// Number of items.
+    fn len(&self) -> usize;
+
+    /// Whether there are no items at all.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Split into the first `index` items and the rest.
+    fn split_at(self, index: usize) -> (Self, Self);
+
+    /// Iterate over the items sequentially.
+    fn into_iter(self) -> Self::IntoIter;
+
+    /// Call `f` on every item in parallel.
+    fn par_for_each<E, F>(self, exec: &E, f: F)
+        where E: Executor,
+              F: Fn(Self::Item) + Sync
+    {
+        split(exec, self, exec.parallelism(),
+              &|p: Self| for item in p.into_iter() { f(item) },
+              &|(), ()| ())
+    }
+
+    /// Map every item through `f` in parallel, collecting the results in order.
+    fn par_map<E, F, R>(self, exec: &E, f: F) -> Vec<R>
+        where E: Executor,
+              F: Fn(Self::Item) -> R + Sync,
+              R: Send
+    {
+        let len = self.len();
+        let mut out = Vec::with_capacity(len);
+        {
+            // each piece writes its results straight into its own part of
+            // `out`; if `f` panics, the results so far are leaked, not dropped
+            let dest = unsafe { slice::from_raw_parts_mut(out.as_mut_ptr(), len) };
+            let p = WriteInto { producer: self, dest: dest };
+            p.par_for_each(exec, |(item, slot)| unsafe { ptr::write(slot, f(item)) });
+        }
+        unsafe { out.set_len(len); }
+        out
+    }
+
+    /// Map every item through `map` and combine the results with `op` in
+    /// parallel, starting each piece from a clone of `identity`.
+    ///
+    /// Results are combined in order, so `op` only needs to be associative.
+    fn par_reduce<E, R, M, F>(self, exec: &E, identity: R, map: M, op: F) -> R
+        where E: Executor,
+              R: Clone + Send + Sync,
+              M: Fn(Self::Item) -> R + Sync,
+              F: Fn(R, R) -> R + Sync
+    {
+        split(exec, self, exec.parallelism(),
+              &|p: Self| p.into_iter().map(&map).fold(identity.clone(), &op),
+              &op)
+    }
+}
+
+// Split `p` into (at most) `pieces` parts of about equal length, run `leaf` on
+// the parts and `combine` the results, left to right.
+//
+// Every split hands one half to `exec.join`, so a split into `pieces` parts
+// runs at most `pieces` closures at the same time.
+fn split<E, P, R, L, C>(exec: &E, p: P, pieces: usize, leaf: &L, combine: &C) -> R
+    where E: Executor,
+          P: Producer,
+          R: Send,
+          L: Fn(P) -> R + Sync,
+          C: Fn(R, R) -> R + Sync
+{
+    let len = p.len();
+    if pieces <= 1 || len <= 1 {
+        return leaf(p);
+    }
+    let left_pieces = pieces / 2;
+    // `len * left_pieces / pieces`, without overflowing
+    let mid = len / pieces * left_pieces + len % pieces * left_pieces / pieces;
+    let (left, right) = p.split_at(mid);
+    let (a, b) = exec.join(|| split(exec, left, left_pieces, leaf, combine),
+                           || split(exec, right, pieces - left_pieces, leaf, combine));
+    combine(a, b)
+}
+
+// Pairs every item of `producer` with the slot of `dest` its result goes to.
+struct WriteInto<'a, P, R: 'a> {
+    producer: P,
+    dest: &'a mut [R],
+}
+
+impl<'a, P: Producer, R: Send + 'a> Producer for WriteInto<'a, P, R> {
+    type Item = (P::Item, &'a mut R);
+    type IntoIter = iter::Zip<P::IntoIter, slice::IterMut<'a, R>>;
+
+    fn len(&self) -> usize {
+        self.producer.len()
+    }
+
+    fn split_at(self, index: usize) -> (Self, Self) {
+        let (p_left, p_right) = self.producer.split_at(index);
+        let (d_left, d_right) = self.dest.split_at_mut(index);
+        (WriteInto { producer: p_left, dest: d_left },
+         WriteInto { producer: p_right, dest: d_right })
+    }
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.producer.into_iter().zip(self.dest.iter_mut())
+    }
+}
+
+/// Parallel operations on slices.
+pub trait ParallelSlice<T> {
+    /// A producer of references to the elements.
+    fn par_iter<'a>(&'a self) -> ParIter<'a, T>;
+
+    /// A producer of mutable chunks of `size` elements (the last one may be
+    /// shorter).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is zero.
+    fn par_chunks_mut<'a>(&'a mut self, size: usize) -> ParChunksMut<'a, T>;
+}
+
+impl<T> ParallelSlice<T> for [T] {
+    fn par_iter<'a>(&'a self) -> ParIter<'a, T> {
+        ParIter { slice: self }
+    }
+
+    fn par_chunks_mut<'a>(&'a mut self, size: usize) -> ParChunksMut<'a, T> {
+        assert!(size > 0, "chunk size must be nonzero");
+        ParChunksMut {
+            slice: self,
+            size: size,
+        }
+    }
+}
+
+/// Producer of references to the elements of a slice.
+#[derive(Debug)]
+pub struct ParIter<'a, T: 'a> {
+    slice: &'a [T],
+}
+
+impl<'a, T: Sync + 'a> Producer for ParIter<'a, T> {
+    type Item = &'a T;
+    type IntoIter = slice::Iter<'a, T>;
+
+    fn len(&self) -> usize {
+        self.slice.len()
+    }
+
+    fn split_at(self, index: usize) -> (Self, Self) {
+        let (left, right) = self.slice.split_at(index);
+        (ParIter { slice: left }, ParIter { slice: right })
+    }
+
+    fn into_iter(self) -> slice::Iter<'a, T> {
+        self.slice.iter()
+    }
+}
+
+/// Producer of mutable chunks of a slice.
+#[derive(Debug)]
+pub struct ParChunksMut<'a, T: 'a> {
+    slice: &'a mut [T],
+    size: usize,
+}
+
+impl<'a, T: Send + 'a> Producer for ParChunksMut<'a, T> {
+    type Item = &'a mut [T];
+    type IntoIter = slice::ChunksMut<'a, T>;
+
+    fn len(&self) -> usize {
+        (self.slice.len() + self.size - 1) / self.size
+    }
+
+    fn split_at(self, index: usize) -> (Self, Self) {
+        let mid = cmp::min(index * self.size, self.slice.len());
+        let (left, right) = self.slice.split_at_mut(mid);
+        (ParChunksMut { slice: left, size: self.size },
+         ParChunksMut { slice: right, size: self.size })
+    }
+
+    fn into_iter(self) -> slice::ChunksMut<'a, T> {
+        self.slice.chunks_mut(self.size)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::sync::Mutex;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::thread;
+
+    use pool::ThreadPool;
+    use super::*;
+
+    fn check<E: Executor>(exec: &E) {
+        let v = (0..10000).collect::<Vec<usize>>();
+
+        let doubled = v.par_iter().par_map(exec, |x| 2 * x);
+        assert_eq!(doubled, v.iter().map(|x| 2 * x).collect::<Vec<_>>());
+
+        let sum = v.par_iter().par_reduce(exec, 0, |x| *x, |a, b| a + b);
+        assert_eq!(sum, 9999 * 10000 / 2);
+
+        // not commutative, so this checks that pieces are combined in order
+        let s = v[..100].par_iter().par_reduce(exec, String::new(), |x| x.to_string(),
+                                                |a, b| a + &b);
+        assert_eq!(s, v[..100].iter().map(|x| x.to_string()).collect::<String>());
+
+        let count = AtomicUsize::new(0);
+        v.par_iter().par_for_each(exec, |x| { count.fetch_add(*x, SeqCst); });
+        assert_eq!(count.load(SeqCst), sum);
+
+        let mut w = vec![0; 1003];
+        w.par_chunks_mut(10).par_for_each(exec, |chunk| {
+            let len = chunk.len();
+            for x in chunk.iter_mut() {
+                *x = len;
+            }
+        });
+        assert!(w[..1000].iter().all(|&x| x == 10));
+        assert!(w[1000..].iter().all(|&x| x == 3));
+
+        let lens = w.par_chunks_mut(100).par_map(exec, |chunk| chunk.len());
+        assert_eq!(lens, [100, 100, 100, 100, 100, 100, 100, 100, 100, 100, 3]);
+
+        let empty: Vec<usize> = vec![];
+        assert_eq!(empty.par_iter().par_map(exec, |x| *x), empty);
+        assert_eq!(empty.par_iter().par_reduce(exec, 7, |x| *x, |a, b| a + b), 7);
+    }
+
+    #[test]
+    fn threads() {
+        check(&Threads::new(4));
+        check(&Threads::new(1));
+    }
+
+    #[test]
+    fn threads_limit() {
+        for &n in &[1, 3, 4, 7] {
+            let v = (0..1000).collect::<Vec<usize>>();
+            let ids = Mutex::new(HashSet::new());
+            v.par_iter().par_for_each(&Threads::new(n), |_| {
+                ids.lock().unwrap().insert(thread::current().id());
+            });
+            assert!(ids.into_inner().unwrap().len() <= n);
+        }
+    }
+
+    #[test]
+    fn pool() {
+        check(&ThreadPool::new(4));
+        check(&ThreadPool::new(1));
+    }
+}
@@ -0,0 +1,697 @@
+//! A work-stealing thread pool.
+//!
+//! Every thread of a `ThreadPool` owns a `chase_lev` deque. Jobs created on a
+//! pool thread go onto its own deque, while jobs coming from the outside go
+//! through a shared `Injector`. Threads that run out of work steal from the
+//! injector first and then from randomly chosen siblings, and go to sleep when
+//! there is nothing left to steal.
+//!
+//! # Example
+//!
+//! ```
+//! use crossbeam::pool::ThreadPool;
+//!
+//! fn sum(pool: &ThreadPool, v: &[u64]) -> u64 {
+//!     if v.len() <= 1000 {
+//!         return v.iter().sum();
+//!     }
+//!     let (left, right) = v.split_at(v.len() / 2);
+//!     let (a, b) = pool.join(|| sum(pool, left), || sum(pool, right));
+//!     a + b
+//! }
+//!
+//! let pool = ThreadPool::new(4);
+//! let v = (0..100000).collect::<Vec<u64>>();
+//! assert_eq!(sum(&pool, &v), 99999 * 100000 / 2);
+//! ```
+
+use std::any::Any;
+use std::cell::{Cell, UnsafeCell};
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{self, AtomicBool, AtomicUsize};
+use std::sync::atomic::Ordering::{Acquire, Release, Relaxed, SeqCst};
+use std::thread;
+
+use FnBox;
+use sync::chase_lev::{self, Injector, Steal, Stealer, Worker};
+use util::random;
+
+// Number of fruitless rounds of looking for work before a thread goes to sleep.
+const SPIN_ROUNDS: usize = 32;
+
+type Job = Box<dyn FnBox + Send>;
+
+// Erase the lifetime of a job. The caller must make sure the job has run
+// before anything it borrows goes away.
+unsafe fn job<'a, F>(f: F) -> Job where F: FnOnce() + Send + 'a {
+    let job: Box<dyn FnBox + Send + 'a> = Box::new(f);
+    mem::transmute(job)
+}
+
+/// A pool of threads executing jobs with work stealing.
+///
+/// Dropping the pool waits until all jobs have run and the threads have
+/// exited.
+pub struct ThreadPool {
+    registry: Arc<Registry>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+/// A scope for spawning jobs on a pool that borrow from the stack.
+///
+/// See [`ThreadPool::scope`](struct.ThreadPool.html#method.scope).
+pub struct Scope<'a> {
+    registry: Arc<Registry>,
+
+    /// Number of spawned jobs that have not completed yet.
+    pending: AtomicUsize,
+
+    /// The first panic raised by a spawned job.
+    panic: Mutex<Option<Box<dyn Any + Send>>>,
+
+    marker: PhantomData<fn(&'a ()) -> &'a ()>,
+}
+
+// The state shared by all threads of a pool.
+struct Registry {
+    stealers: Vec<Stealer<Job>>,
+    injector: Injector<Job>,
+
+    // Threads sleep on `wake`, after announcing themselves in `sleepers`. Job
+    // submission only reads `sleepers` as long as nobody is asleep.
+    sleepers: AtomicUsize,
+    lock: Mutex<()>,
+    wake: Condvar,
+
+    terminate: AtomicBool,
+}
+
+// The state of a pool thread, living on that thread's stack.
+struct WorkerThread {
+    worker: UnsafeCell<Worker<Job>>,
+    index: usize,
+    registry: Arc<Registry>,
+}
+
+thread_local!(static CURRENT: Cell<*const WorkerThread> = Cell::new(ptr::null()));
+
+// The outcome of a job whose caller is waiting for it.
+struct Latch<R> {
+    done: AtomicBool,
+    result: Mutex<Option<thread::Result<R>>>,
+    cvar: Condvar,
+}
+
+struct ScopePtr<'a>(*const Scope<'a>);
+
+unsafe impl<'a> Send for ScopePtr<'a> {}
+
+impl ThreadPool {
+    /// Create a pool with `num_threads` threads.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `num_threads` is zero.
+    pub fn new(num_threads: usize) -> ThreadPool {
+        assert!(num_threads > 0, "a thread pool needs at least one thread");
+
+        let (workers, stealers): (Vec<_>, Vec<_>) = (0..num_threads)
+            .map(|_| chase_lev::deque())
+            .unzip();
+        let registry = Arc::new(Registry {
+            stealers: stealers,
+            injector: Injector::new(),
+            sleepers: AtomicUsize::new(0),
+            lock: Mutex::new(()),
+            wake: Condvar::new(),
+            terminate: AtomicBool::new(false),
+        });
+
+        let threads = workers.into_iter().enumerate().map(|(i, worker)| {
+            let registry = registry.clone();
+            thread::Builder::new().name(format!("crossbeam-pool-{}", i)).spawn(move || {
+                let thread = WorkerThread {
+                    worker: UnsafeCell::new(worker),
+                    index: i,
+                    registry: registry,
+                };
+                thread.run();
+            }).unwrap()
+        }).collect();
+
+        ThreadPool {
+            registry: registry,
+            threads: threads,
+        }
+    }
+
+    /// Number of threads in the pool.
+    pub fn num_threads(&self) -> usize {
+        self.threads.len()
+    }
+
+    /// Run `f` on the pool in the background.
+    ///
+    /// Nobody waits for the job, so if it panics the panic is simply
+    /// swallowed; use `install` or `scope` to observe panics.
+    pub fn spawn<F>(&self, f: F) where F: FnOnce() + Send + 'static {
+        self.registry.push(Box::new(move || {
+            let _ = panic::catch_unwind(AssertUnwindSafe(f));
+        }));
+    }
+
+    /// Run `f` on the pool and wait for its result.
+    ///
+    /// If `f` panics, the panic is propagated to the caller.
+    pub fn install<F, R>(&self, f: F) -> R where F: FnOnce() -> R + Send, R: Send {
+        if self.registry.current_worker().is_some() {
+            return f();
+        }
+
+        let latch = Arc::new(Latch::new());
+        let their_latch = latch.clone();
+        unsafe {
+            self.registry.push(job(move || {
+                their_latch.set(panic::catch_unwind(AssertUnwindSafe(f)));
+            }));
+        }
+        unwrap_or_resume(latch.wait())
+    }
+
+    /// Run `a` and `b`, potentially in parallel, and return both results.
+    ///
+    /// `b` is made available for stealing while the current thread runs `a`.
+    /// If either closure panics, the panic is propagated once both have
+    /// finished.
+    pub fn join<A, B, RA, RB>(&self, a: A, b: B) -> (RA, RB)
+        where A: FnOnce() -> RA + Send,
+              B: FnOnce() -> RB + Send,
+              RA: Send,
+              RB: Send
+    {
+        let worker = match self.registry.current_worker() {
+            Some(worker) => worker,
+            None => return self.install(|| self.join(a, b)),
+        };
+
+        let latch = Arc::new(Latch::new());
+        let their_latch = latch.clone();
+        let registry = &*self.registry;
+        unsafe {
+            // `registry` outlives the job, as the thread running it holds on
+            // to the registry too.
+            worker.push(job(move || {
+                their_latch.set(panic::catch_unwind(AssertUnwindSafe(b)));
+                // we may be asleep in `wait_until`
+                registry.notify_all();
+            }));
+        }
+        self.registry.notify();
+
+        let ra = panic::catch_unwind(AssertUnwindSafe(a));
+        // `b` may borrow from this stack frame, so it must finish even if `a`
+        // panicked. Most of the time it is still on our own deque.
+        worker.wait_until(|| latch.probe());
+        let rb = latch.take();
+        (unwrap_or_resume(ra), unwrap_or_resume(rb))
+    }
+
+    /// Create a scope for spawning jobs that borrow from the stack.
+    ///
+    /// This is the pool counterpart of [`crossbeam::scope`](../fn.scope.html):
+    /// `scope` only returns once every job spawned in it has completed. The
+    /// first panic raised by `f` or a spawned job is propagated to the caller.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use crossbeam::pool::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(4);
+    /// let mut v = vec![1, 2, 3, 4];
+    /// pool.scope(|s| {
+    ///     for x in &mut v {
+    ///         s.spawn(move |_| *x *= 2);
+    ///     }
+    /// });
+    /// assert_eq!(v, [2, 4, 6, 8]);
+    /// ```
+    pub fn scope<'a, F, R>(&self, f: F) -> R where F: FnOnce(&Scope<'a>) -> R + Send, R: Send {
+        self.install(|| {
+            let scope = Scope {
+                registry: self.registry.clone(),
+                pending: AtomicUsize::new(0),
+                panic: Mutex::new(None),
+                marker: PhantomData,
+            };
+            let res = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+
+            let worker = self.registry.current_worker().unwrap();
+            worker.wait_until(|| scope.pending.load(Acquire) == 0);
+
+            if let Some(err) = scope.panic.lock().unwrap().take() {
+                panic::resume_unwind(err);
+            }
+            unwrap_or_resume(res)
+        })
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.registry.terminate();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl fmt::Debug for ThreadPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ThreadPool {{ num_threads: {} }}", self.num_threads())
+    }
+}
+
+impl<'a> Scope<'a> {
+    /// Spawn a job on the pool that may borrow data living for `'a`.
+    ///
+    /// The job receives the scope, so that it can spawn more jobs.
+    pub fn spawn<F>(&self, f: F) where F: FnOnce(&Scope<'a>) + Send + 'a {
+        self.pending.fetch_add(1, Relaxed);
+        let scope = ScopePtr(self);
+        unsafe {
+            // the scope outlives the job, since it waits for `pending` to
+            // drop to zero, which is the last thing the job does.
+            self.registry.push(job(move || {
+                let scope = &*scope.0;
+                if let Err(err) = panic::catch_unwind(AssertUnwindSafe(|| f(scope))) {
+                    let mut panic = scope.panic.lock().unwrap();
+                    if panic.is_none() {
+                        *panic = Some(err);
+                    }
+                }
+                // the scope may be gone as soon as `pending` drops to zero
+                let registry = scope.registry.clone();
+                if scope.pending.fetch_sub(1, Release) == 1 {
+                    // its owner may be asleep in `wait_until`
+                    registry.notify_all();
+                }
+            }));
+        }
+    }
+}
+
+impl<'a> fmt::Debug for Scope<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Scope {{ ... }}")
+    }
+}
+
+impl Registry {
+    // The current thread, if it belongs to this pool.
+    fn current_worker(&self) -> Option<&WorkerThread> {
+        unsafe {
+            let worker = CURRENT.with(|c| c.get());
+            if !worker.is_null() && &*(*worker).registry as *const Registry == self {
+                Some(&*worker)
+            } else {
+                None
+            }
+        }
+    }
+
+    // Submit a job, onto the current thread's deque if it is a pool thread.
+    fn push(&self, job: Job) {
+        match self.current_worker() {
+            Some(worker) => worker.push(job),
+            None => self.injector.push(job),
+        }
+        self.notify();
+    }
+
+    // Wake up a sleeping thread, if any, after submitting a job.
+    fn notify(&self) {
+        // pairs with the fence in `sleep`: either the sleeper sees the new
+        // job, or we see the sleeper
+        atomic::fence(SeqCst);
+        if self.sleepers.load(Relaxed) > 0 {
+            let _lock = self.lock.lock().unwrap();
+            self.wake.notify_one();
+        }
+    }
+
+    // Wake up every sleeping thread, after making the condition some thread
+    // may be waiting for in `wait_until` hold.
+    fn notify_all(&self) {
+        // pairs with the fence in `sleep_until`, like in `notify`
+        atomic::fence(SeqCst);
+        if self.sleepers.load(Relaxed) > 0 {
+            let _lock = self.lock.lock().unwrap();
+            self.wake.notify_all();
+        }
+    }
+
+    fn has_work(&self) -> bool {
+        !self.injector.is_empty() || self.stealers.iter().any(|s| !s.is_empty())
+    }
+
+    // Sleep until there is work to do. Returns `false` if the pool is shutting
+    // down and no work is left.
+    fn sleep(&self) -> bool {
+        self.sleep_until(|| self.terminate.load(SeqCst));
+        !self.terminate.load(SeqCst) || self.has_work()
+    }
+
+    // Sleep until there is work to do or `done` holds. Whoever makes `done`
+    // hold must call `notify_all` afterwards.
+    fn sleep_until<F: Fn() -> bool>(&self, done: F) {
+        self.sleepers.fetch_add(1, SeqCst);
+        atomic::fence(SeqCst);
+
+        // checking under the lock means a submission can't slip its
+        // notification in between the check and the wait.
+        let mut lock = self.lock.lock().unwrap();
+        while !self.has_work() && !done() {
+            lock = self.wake.wait(lock).unwrap();
+        }
+        drop(lock);
+
+        self.sleepers.fetch_sub(1, Relaxed);
+    }
+
+    fn terminate(&self) {
+        self.terminate.store(true, SeqCst);
+        self.notify_all();
+    }
+}
+
+impl WorkerThread {
+    fn run(&self) {
+        CURRENT.with(|c| c.set(self));
+
+        let mut idle = 0;
+        loop {
+            match self.find_work() {
+                Some(job) => {
+                    idle = 0;
+                    job.call_box();
+                }
+                None if idle < SPIN_ROUNDS => {
+                    idle += 1;
+                    thread::yield_now();
+                }
+                None => {
+                    idle = 0;
+                    if !self.registry.sleep() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        CURRENT.with(|c| c.set(ptr::null()));
+    }
+
+    fn push(&self, job: Job) {
+        unsafe { (*self.worker.get()).push(job) }
+    }
+
+    // Look for a job: on our own deque first, then in the injector, and then
+    // on the other threads' deques, starting from a random one.
+    fn find_work(&self) -> Option<Job> {
+        let worker = unsafe { &mut *self.worker.get() };
+        if let Some(job) = worker.try_pop() {
+            return Some(job);
+        }
+
+        loop {
+            match self.registry.injector.steal_batch_and_pop(worker) {
+                Steal::Data(job) => return Some(job),
+                Steal::Empty => break,
+                Steal::Abort => {}
+            }
+        }
+
+        let stealers = &self.registry.stealers;
+        let start = random(stealers.len());
+        for i in 0..stealers.len() {
+            let victim = (start + i) % stealers.len();
+            if victim == self.index {
+                continue;
+            }
+            loop {
+                match stealers[victim].steal_half_into(worker) {
+                    Steal::Data(job) => return Some(job),
+                    Steal::Empty => break,
+                    Steal::Abort => {}
+                }
+            }
+        }
+        None
+    }
+
+    // Run other jobs until `done` holds, going to sleep when there are none
+    // to be found.
+    fn wait_until<F: Fn() -> bool>(&self, done: F) {
+        let mut idle = 0;
+        while !done() {
+            match self.find_work() {
+                Some(job) => {
+                    idle = 0;
+                    job.call_box();
+                }
+                None if idle < SPIN_ROUNDS => {
+                    idle += 1;
+                    thread::yield_now();
+                }
+                None => {
+                    idle = 0;
+                    self.registry.sleep_until(&done);
+                }
+            }
+        }
+    }
+}
+
+impl<R> Latch<R> {
+    fn new() -> Latch<R> {
+        Latch {
+            done: AtomicBool::new(false),
+            result: Mutex::new(None),
+            cvar: Condvar::new(),
+        }
+    }
+
+    fn set(&self, res: thread::Result<R>) {
+        let mut result = self.result.lock().unwrap();
+        *result = Some(res);
+        self.done.store(true, Release);
+        self.cvar.notify_all();
+    }
+
+    fn probe(&self) -> bool {
+        self.done.load(Acquire)
+    }
+
+    // Block until the result is set, and take it.
+    fn wait(&self) -> thread::Result<R> {
+        let mut result = self.result.lock().unwrap();
+        while result.is_none() {
+            result = self.cvar.wait(result).unwrap();
+        }
+        result.take().unwrap()
+    }
+
+    // Take the result, which must be set.
+    fn take(&self) -> thread::Result<R> {
+        self.result.lock().unwrap().take().unwrap()
+    }
+}
+
+fn unwrap_or_resume<R>(res: thread::Result<R>) -> R {
+    match res {
+        Ok(r) => r,
+        Err(err) => panic::resume_unwind(err),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize};
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn fib(pool: &ThreadPool, n: u64) -> u64 {
+        if n < 2 {
+            return n;
+        }
+        let (a, b) = pool.join(|| fib(pool, n - 1), || fib(pool, n - 2));
+        a + b
+    }
+
+    #[test]
+    fn spawn() {
+        let pool = ThreadPool::new(4);
+        let (tx, rx) = mpsc::channel();
+        for i in 0..100 {
+            let tx = tx.clone();
+            pool.spawn(move || tx.send(i).unwrap());
+        }
+        let mut got = rx.iter().take(100).collect::<Vec<_>>();
+        got.sort();
+        assert_eq!(got, (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn drop_runs_pending_jobs() {
+        static RUN: AtomicUsize = AtomicUsize::new(0);
+        let pool = ThreadPool::new(2);
+        for _ in 0..1000 {
+            pool.spawn(|| { RUN.fetch_add(1, SeqCst); });
+        }
+        drop(pool);
+        assert_eq!(RUN.load(SeqCst), 1000);
+    }
+
+    #[test]
+    fn install() {
+        let pool = ThreadPool::new(2);
+        let name = pool.install(|| thread::current().name().unwrap().to_string());
+        assert!(name.starts_with("crossbeam-pool-"));
+        // nested installs run in place
+        assert_eq!(pool.install(|| pool.install(|| 7)), 7);
+    }
+
+    #[test]
+    fn install_propagates_panic() {
+        let pool = ThreadPool::new(2);
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.install(|| panic!("boom"))
+        }));
+        assert!(res.is_err());
+        // the pool keeps working afterwards
+        assert_eq!(pool.install(|| 1 + 1), 2);
+    }
+
+    #[test]
+    fn join() {
+        let pool = ThreadPool::new(4);
+        assert_eq!(fib(&pool, 20), 6765);
+        assert_eq!(pool.join(|| 1, || "two"), (1, "two"));
+    }
+
+    #[test]
+    fn join_sleeps_until_stolen_job_completes() {
+        let pool = ThreadPool::new(2);
+        let started = AtomicBool::new(false);
+        let (a, b) = pool.join(|| {
+            // make sure the other thread steals `b`, leaving us with nothing
+            // to do but wait for it
+            while !started.load(SeqCst) {
+                thread::yield_now();
+            }
+            1
+        }, || {
+            started.store(true, SeqCst);
+            thread::sleep(Duration::from_millis(50));
+            2
+        });
+        assert_eq!((a, b), (1, 2));
+    }
+
+    #[test]
+    fn join_waits_for_both_on_panic() {
+        let pool = ThreadPool::new(2);
+        let done = AtomicUsize::new(0);
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.join(|| panic!("boom"), || {
+                thread::yield_now();
+                done.fetch_add(1, SeqCst);
+            })
+        }));
+        assert!(res.is_err());
+        assert_eq!(done.load(SeqCst), 1);
+    }
+
+    #[test]
+    fn scope_borrows() {
+        let pool = ThreadPool::new(4);
+        let mut v = vec![0; 1000];
+        let sum = AtomicUsize::new(0);
+        pool.scope(|s| {
+            for (i, chunk) in v.chunks_mut(10).enumerate() {
+                let sum = &sum;
+                s.spawn(move |_| {
+                    for x in chunk.iter_mut() {
+                        *x = i;
+                    }
+                    sum.fetch_add(i, SeqCst);
+                });
+            }
+        });
+        assert_eq!(sum.load(SeqCst), (0..100).sum());
+        for (i, x) in v.iter().enumerate() {
+            assert_eq!(*x, i / 10);
+        }
+    }
+
+    #[test]
+    fn scope_nested_spawn() {
+        let pool = ThreadPool::new(4);
+        let count = AtomicUsize::new(0);
+
+        fn spawn_tree<'a>(s: &Scope<'a>, count: &'a AtomicUsize, depth: usize) {
+            count.fetch_add(1, SeqCst);
+            if depth > 0 {
+                s.spawn(move |s| spawn_tree(s, count, depth - 1));
+                s.spawn(move |s| spawn_tree(s, count, depth - 1));
+            }
+        }
+
+        pool.scope(|s| spawn_tree(s, &count, 10));
+        assert_eq!(count.load(SeqCst), (1 << 11) - 1);
+    }
+
+    #[test]
+    fn scope_propagates_panic() {
+        let pool = ThreadPool::new(2);
+        let done = AtomicUsize::new(0);
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|s| {
+                s.spawn(|_| panic!("boom"));
+                for _ in 0..10 {
+                    s.spawn(|_| { done.fetch_add(1, SeqCst); });
+                }
+            })
+        }));
+        assert!(res.is_err());
+        assert_eq!(done.load(SeqCst), 10);
+    }
+
+    #[test]
+    fn many_callers() {
+        let pool = Arc::new(ThreadPool::new(3));
+        let threads = (0..4).map(|_| {
+            let pool = pool.clone();
+            thread::spawn(move || {
+                for _ in 0..20 {
+                    assert_eq!(fib(&pool, 12), 144);
+                }
+            })
+        }).collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+    }
+}
@@ -14,7 +14,7 @@ pub struct Scope<'a> {
 }
 
 struct DtorChain<'a> {
-    dtor: Box<FnBox + 'a>,
+    dtor: Box<dyn FnBox + 'a>,
     next: Option<Box<DtorChain<'a>>>
 }
 
@@ -54,7 +54,7 @@ impl<T> ArcCell<T> {
 #[cfg(test)]
 mod test {
     use std::sync::Arc;
-    use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
     use super::*;
 
@@ -68,7 +68,7 @@ mod test {
 
     #[test]
     fn drop_runs() {
-        static DROPS: AtomicUsize = ATOMIC_USIZE_INIT;
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
 
         struct Foo;
 
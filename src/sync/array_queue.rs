@@ -0,0 +1,331 @@
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::atomic::Ordering::{Acquire, Release, Relaxed, SeqCst};
+
+use mem::CachePadded;
+
+/// A bounded, lock-free queue backed by a fixed-size array.
+///
+/// Usable with any number of producers and consumers. All memory is allocated
+/// up front by `new`; pushing into a full queue fails, handing the element
+/// back to the caller, which provides backpressure.
+// This is Dmitry Vyukov's bounded MPMC queue. `head` and `tail` are positions
+// made of a slot index in the low bits and a "lap" counter above them, laps
+// being `one_lap` apart. Each slot carries a stamp in the same format: a slot
+// is ready to be written by the push at position `pos` when its stamp equals
+// `pos`, and ready to be read by the pop at `pos` when its stamp is `pos + 1`.
+// A pop then moves the stamp on to the same index one lap later.
+pub struct ArrayQueue<T> {
+    head: CachePadded<AtomicUsize>,
+    tail: CachePadded<AtomicUsize>,
+    buffer: Box<[Slot<T>]>,
+
+    /// Distance between the same index in two consecutive laps; a power of
+    /// two strictly greater than the capacity.
+    one_lap: usize,
+}
+
+struct Slot<T> {
+    stamp: AtomicUsize,
+    value: UnsafeCell<Option<T>>,
+}
+
+unsafe impl<T: Send> Sync for ArrayQueue<T> {}
+unsafe impl<T: Send> Send for ArrayQueue<T> {}
+
+impl<T> fmt::Debug for ArrayQueue<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ArrayQueue {{ ... }}")
+    }
+}
+
+impl<T> ArrayQueue<T> {
+    /// Create a new, empty queue holding at most `cap` elements.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `cap` is zero.
+    pub fn new(cap: usize) -> ArrayQueue<T> {
+        assert!(cap > 0, "capacity must be non-zero");
+        let buffer = (0..cap).map(|i| {
+            Slot {
+                stamp: AtomicUsize::new(i),
+                value: UnsafeCell::new(None),
+            }
+        }).collect::<Vec<_>>().into_boxed_slice();
+
+        ArrayQueue {
+            head: CachePadded::zeroed(),
+            tail: CachePadded::zeroed(),
+            buffer: buffer,
+            one_lap: (cap + 1).next_power_of_two(),
+        }
+    }
+
+    /// The position following `pos`, wrapping into the next lap after the
+    /// last slot.
+    fn next_pos(&self, pos: usize) -> usize {
+        let index = pos & (self.one_lap - 1);
+        if index + 1 < self.buffer.len() {
+            pos + 1
+        } else {
+            (pos & !(self.one_lap - 1)).wrapping_add(self.one_lap)
+        }
+    }
+
+    /// Attempt to add `t` to the back of the queue.
+    ///
+    /// Returns `Err(t)` if the queue is observed to be full.
+    pub fn try_push(&self, t: T) -> Result<(), T> {
+        let mut tail = self.tail.load(Relaxed);
+        loop {
+            let slot = &self.buffer[tail & (self.one_lap - 1)];
+            let stamp = slot.stamp.load(Acquire);
+
+            if stamp == tail {
+                // the slot is ready for this lap; try to claim it
+                match self.tail.compare_exchange(tail, self.next_pos(tail), Relaxed, Relaxed) {
+                    Ok(_) => {
+                        unsafe { *slot.value.get() = Some(t); }
+                        slot.stamp.store(tail + 1, Release);
+                        return Ok(());
+                    }
+                    Err(prev) => tail = prev,
+                }
+            } else if stamp.wrapping_add(self.one_lap) == tail + 1 {
+                // the slot still holds an element from the previous lap, so
+                // the queue is full unless a pop has moved `head` meanwhile
+                atomic::fence(SeqCst);
+                if self.head.load(Relaxed).wrapping_add(self.one_lap) == tail {
+                    return Err(t);
+                }
+                tail = self.tail.load(Relaxed);
+            } else {
+                // another push or pop is midway through this slot; catch up
+                tail = self.tail.load(Relaxed);
+            }
+        }
+    }
+
+    /// Attempt to dequeue from the front.
+    ///
+    /// Returns `None` if the queue is observed to be empty.
+    pub fn try_pop(&self) -> Option<T> {
+        let mut head = self.head.load(Relaxed);
+        loop {
+            let slot = &self.buffer[head & (self.one_lap - 1)];
+            let stamp = slot.stamp.load(Acquire);
+
+            if stamp == head + 1 {
+                // the slot holds the element for this position; try to claim it
+                match self.head.compare_exchange(head, self.next_pos(head), Relaxed, Relaxed) {
+                    Ok(_) => {
+                        let t = unsafe { (*slot.value.get()).take() };
+                        slot.stamp.store(head.wrapping_add(self.one_lap), Release);
+                        return t;
+                    }
+                    Err(prev) => head = prev,
+                }
+            } else if stamp == head {
+                // the slot has not been written for this lap, so the queue
+                // is empty unless a push has moved `tail` meanwhile
+                atomic::fence(SeqCst);
+                if self.tail.load(Relaxed) == head {
+                    return None;
+                }
+                head = self.head.load(Relaxed);
+            } else {
+                // another push or pop is midway through this slot; catch up
+                head = self.head.load(Relaxed);
+            }
+        }
+    }
+
+    /// The maximum number of elements the queue can hold.
+    pub fn capacity(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// The number of elements in the queue.
+    ///
+    /// With concurrent pushes and pops, this is only a snapshot.
+    pub fn len(&self) -> usize {
+        loop {
+            let tail = self.tail.load(SeqCst);
+            let head = self.head.load(SeqCst);
+
+            // make sure `tail` and `head` were observed together
+            if self.tail.load(SeqCst) == tail {
+                let hix = head & (self.one_lap - 1);
+                let tix = tail & (self.one_lap - 1);
+
+                return if hix < tix {
+                    tix - hix
+                } else if hix > tix {
+                    self.capacity() - hix + tix
+                } else if tail == head {
+                    0
+                } else {
+                    self.capacity()
+                };
+            }
+        }
+    }
+
+    /// Check if this queue is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Check if this queue is full.
+    pub fn is_full(&self) -> bool {
+        self.len() == self.capacity()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    const CONC_COUNT: i64 = 1000000;
+
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::thread;
+
+    use scope;
+    use super::*;
+
+    #[test]
+    fn push_pop_1() {
+        let q: ArrayQueue<i64> = ArrayQueue::new(1);
+        assert!(q.is_empty());
+        assert_eq!(q.try_push(37), Ok(()));
+        assert!(q.is_full());
+        assert_eq!(q.try_push(48), Err(48));
+        assert_eq!(q.try_pop(), Some(37));
+        assert_eq!(q.try_pop(), None);
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn capacity() {
+        for cap in 1..10 {
+            let q: ArrayQueue<usize> = ArrayQueue::new(cap);
+            assert_eq!(q.capacity(), cap);
+            for i in 0..cap {
+                assert_eq!(q.len(), i);
+                assert_eq!(q.try_push(i), Ok(()));
+            }
+            assert!(q.is_full());
+            assert_eq!(q.try_push(cap), Err(cap));
+            assert_eq!(q.len(), cap);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn zero_capacity() {
+        let _q: ArrayQueue<i64> = ArrayQueue::new(0);
+    }
+
+    #[test]
+    fn wrap_around() {
+        let q: ArrayQueue<i64> = ArrayQueue::new(3);
+        for i in 0..100 {
+            assert_eq!(q.try_push(i), Ok(()));
+            assert_eq!(q.try_push(i + 1000), Ok(()));
+            assert_eq!(q.try_pop(), Some(i));
+            assert_eq!(q.try_pop(), Some(i + 1000));
+            assert!(q.is_empty());
+        }
+    }
+
+    #[test]
+    fn push_pop_many_spsc() {
+        let q: ArrayQueue<i64> = ArrayQueue::new(16);
+
+        scope(|scope| {
+            scope.spawn(|| {
+                let mut next = 0;
+
+                while next < CONC_COUNT {
+                    if let Some(elem) = q.try_pop() {
+                        assert_eq!(elem, next);
+                        next += 1;
+                    } else {
+                        thread::yield_now();
+                    }
+                }
+            });
+
+            for i in 0..CONC_COUNT {
+                let mut t = i;
+                while let Err(back) = q.try_push(t) {
+                    t = back;
+                    thread::yield_now();
+                }
+            }
+        });
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn push_pop_many_mpmc() {
+        const THREADS: usize = 4;
+        const COUNT: usize = 100000;
+
+        let q: ArrayQueue<usize> = ArrayQueue::new(32);
+        let popped = AtomicUsize::new(0);
+        let sum = AtomicUsize::new(0);
+
+        scope(|scope| {
+            for _ in 0..THREADS {
+                scope.spawn(|| {
+                    for i in 0..COUNT {
+                        let mut t = i;
+                        while let Err(back) = q.try_push(t) {
+                            t = back;
+                            thread::yield_now();
+                        }
+                    }
+                });
+                scope.spawn(|| {
+                    while popped.load(SeqCst) < THREADS * COUNT {
+                        if let Some(x) = q.try_pop() {
+                            assert!(q.len() <= q.capacity());
+                            sum.fetch_add(x, SeqCst);
+                            popped.fetch_add(1, SeqCst);
+                        } else {
+                            thread::yield_now();
+                        }
+                    }
+                });
+            }
+        });
+
+        assert_eq!(sum.load(SeqCst), THREADS * COUNT * (COUNT - 1) / 2);
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn drop_remaining() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        struct Elem;
+        impl Drop for Elem {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, SeqCst);
+            }
+        }
+
+        let q = ArrayQueue::new(10);
+        for _ in 0..10 {
+            assert!(q.try_push(Elem).is_ok());
+        }
+        for _ in 0..4 {
+            drop(q.try_pop().unwrap());
+        }
+        assert_eq!(DROPS.load(SeqCst), 4);
+        drop(q);
+        assert_eq!(DROPS.load(SeqCst), 10);
+    }
+}
@@ -35,18 +35,24 @@
 //! stealer2.steal();
 //! ```
 //!
+//! Deques created with `deque_fifo` instead let the worker pop from the same
+//! end as the stealers, so that it sees data in the order it was pushed.
+//!
 //! [chase_lev]: http://neteril.org/~jeremie/Dynamic_Circular_Work_Queue.pdf
 //! [weak_chase_lev]: http://www.di.ens.fr/~zappa/readings/ppopp13.pdf
 
 use std::cell::UnsafeCell;
+use std::cmp;
 use std::fmt;
 use std::mem;
 use std::ptr;
 use std::sync::atomic::Ordering::{Acquire, Relaxed, Release, SeqCst};
 use std::sync::atomic::{AtomicIsize, fence};
 use std::sync::Arc;
+use std::usize;
 
 use mem::epoch::{self, Atomic, Shared, Owned};
+use sync::SegQueue;
 
 // Once the queue is less than 1/K full, then it will be downsized. Note that
 // the deque requires that this number be less than 2.
@@ -64,6 +70,17 @@ struct Deque<T> {
     bottom: AtomicIsize,
     top: AtomicIsize,
     array: Atomic<Buffer<T>>,
+
+    flavor: Flavor,
+}
+
+// Which end of the deque the worker pops from.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Flavor {
+    // the worker pops the element it pushed last
+    Lifo,
+    // the worker pops from the top, just like the stealers
+    Fifo,
 }
 
 // FIXME: can these constraints be relaxed?
@@ -90,6 +107,16 @@ pub struct Stealer<T> {
     deque: Arc<Deque<T>>,
 }
 
+/// A global FIFO queue feeding a set of workers.
+///
+/// Any thread may push onto the injector, and any number of threads may steal
+/// from it, either one element at a time or in batches that land in a
+/// `Worker`. It is a `SegQueue` underneath.
+#[derive(Debug)]
+pub struct Injector<T> {
+    queue: SegQueue<T>,
+}
+
 /// When stealing some data, this is an enumeration of the possible outcomes.
 #[derive(PartialEq, Eq, Debug)]
 pub enum Steal<T> {
@@ -130,6 +157,25 @@ impl<T> Worker<T> {
     pub fn try_pop(&mut self) -> Option<T> {
         unsafe { self.deque.try_pop() }
     }
+
+    /// Returns the number of elements in the queue.
+    ///
+    /// Stealers may take elements concurrently, so this is only an upper
+    /// bound by the time it returns.
+    pub fn len(&self) -> usize {
+        self.deque.len()
+    }
+
+    /// Checks whether the queue is empty; as racy as `len`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of elements the queue can hold before it has to
+    /// grow its buffer.
+    pub fn capacity(&self) -> usize {
+        self.deque.capacity()
+    }
 }
 
 impl<T> Stealer<T> {
@@ -137,6 +183,49 @@ impl<T> Stealer<T> {
     pub fn steal(&self) -> Steal<T> {
         self.deque.steal()
     }
+
+    /// Returns a snapshot of the number of elements in the queue.
+    ///
+    /// The worker and other stealers may change the queue at any time, so the
+    /// result is only a hint, e.g. for picking a victim.
+    pub fn len(&self) -> usize {
+        self.deque.len()
+    }
+
+    /// Checks whether the queue is empty; as racy as `len`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns a snapshot of the number of elements the queue can hold
+    /// before it has to grow its buffer.
+    pub fn capacity(&self) -> usize {
+        self.deque.capacity()
+    }
+
+    /// Steals about half of the data in the queue, moving it into `dest`.
+    ///
+    /// The first element is returned directly and the rest are pushed onto
+    /// `dest`, oldest first. If the queue was created with `deque_fifo`, the
+    /// whole batch is claimed with a single successful CAS; otherwise the
+    /// worker may be popping the very same elements without a CAS, so they are
+    /// claimed one by one, and the batch may come out smaller.
+    pub fn steal_half_into(&self, dest: &mut Worker<T>) -> Steal<T> {
+        self.deque.steal_batch(&dest.deque, None)
+    }
+
+    /// Steals at most `n` elements from the queue, moving them into `dest`.
+    ///
+    /// Like `steal_half_into`, the first element is returned directly and the
+    /// rest are pushed onto `dest`, oldest first.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is zero.
+    pub fn steal_many(&self, n: usize, dest: &mut Worker<T>) -> Steal<T> {
+        assert!(n > 0, "cannot steal zero elements");
+        self.deque.steal_batch(&dest.deque, Some(n))
+    }
 }
 
 impl<T> Clone for Stealer<T> {
@@ -147,22 +236,71 @@ impl<T> Clone for Stealer<T> {
 
 /// Creates a new empty deque
 pub fn deque<T>() -> (Worker<T>, Stealer<T>) {
-    let a = Arc::new(Deque::new());
+    with_flavor(Flavor::Lifo)
+}
+
+/// Creates a new empty deque whose worker pops from the same end as the
+/// stealers, so that data is processed in the order it was pushed.
+pub fn deque_fifo<T>() -> (Worker<T>, Stealer<T>) {
+    with_flavor(Flavor::Fifo)
+}
+
+fn with_flavor<T>(flavor: Flavor) -> (Worker<T>, Stealer<T>) {
+    let a = Arc::new(Deque::new(flavor));
     let b = a.clone();
     (Worker { deque: a }, Stealer { deque: b })
 }
 
+impl<T> Injector<T> {
+    /// Creates a new empty injector.
+    pub fn new() -> Injector<T> {
+        Injector { queue: SegQueue::new() }
+    }
+
+    /// Pushes data onto the back of the injector.
+    pub fn push(&self, t: T) {
+        self.queue.push(t)
+    }
+
+    /// Checks if the injector is empty.
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Steals the element at the front of the injector.
+    pub fn steal(&self) -> Steal<T> {
+        match self.queue.try_pop() {
+            Some(t) => Steal::Data(t),
+            None => Steal::Empty,
+        }
+    }
+
+    /// Steals a batch of elements from the front of the injector, returning
+    /// the first one and pushing the rest onto `dest`, oldest first.
+    ///
+    /// The batch is whatever is available in the injector's current segment,
+    /// so it holds at most 32 elements.
+    pub fn steal_batch_and_pop(&self, dest: &mut Worker<T>) -> Steal<T> {
+        match self.queue.try_pop_batch(usize::MAX, |t| unsafe { dest.deque.push(t) }) {
+            Ok(Some(t)) => Steal::Data(t),
+            Ok(None) => Steal::Empty,
+            Err(()) => Steal::Abort,
+        }
+    }
+}
+
 // Almost all of this code can be found directly in the paper so I'm not
 // personally going to heavily comment what's going on here.
 
 impl<T> Deque<T> {
-    fn new() -> Deque<T> {
+    fn new(flavor: Flavor) -> Deque<T> {
         let array = Atomic::null();
         array.store(Some(Owned::new(Buffer::new(MIN_BITS))), SeqCst);
         Deque {
             bottom: AtomicIsize::new(0),
             top: AtomicIsize::new(0),
             array: array,
+            flavor: flavor,
         }
     }
 
@@ -189,6 +327,10 @@ impl<T> Deque<T> {
     }
 
     unsafe fn try_pop(&self) -> Option<T> {
+        if self.flavor == Flavor::Fifo {
+            return self.try_pop_fifo();
+        }
+
         let guard = epoch::pin();
 
         let b = self.bottom.load(Relaxed) - 1;
@@ -203,7 +345,7 @@ impl<T> Deque<T> {
             let mut data = Some(a.get(b));
             if size == 0 {
                 // last element in queue, check for races.
-                if self.top.compare_and_swap(t, t + 1, SeqCst) != t {
+                if self.top.compare_exchange(t, t + 1, SeqCst, SeqCst).is_err() {
                     // lost the race.
                     mem::forget(data.take());
                 }
@@ -227,6 +369,24 @@ impl<T> Deque<T> {
         }
     }
 
+    // In FIFO mode the worker competes with the stealers for the top, so
+    // unlike the LIFO pop it always has to go through a CAS.
+    unsafe fn try_pop_fifo(&self) -> Option<T> {
+        loop {
+            match self.steal() {
+                Steal::Data(data) => {
+                    let guard = epoch::pin();
+                    let t = self.top.load(Relaxed);
+                    let b = self.bottom.load(Relaxed);
+                    self.maybe_shrink(b, t, &guard);
+                    return Some(data)
+                }
+                Steal::Empty => return None,
+                Steal::Abort => {}
+            }
+        }
+    }
+
     fn steal(&self) -> Steal<T> {
         let guard = epoch::pin();
 
@@ -245,7 +405,7 @@ impl<T> Deque<T> {
             let a = self.array.load(Acquire, &guard).unwrap();
             let data = a.get(t);
             // we may be racing against other steals and a pop.
-            if self.top.compare_and_swap(t, t + 1, SeqCst) == t {
+            if self.top.compare_exchange(t, t + 1, SeqCst, SeqCst).is_ok() {
                 Steal::Data(data)
             } else {
                 mem::forget(data); // someone else stole this value
@@ -254,6 +414,87 @@ impl<T> Deque<T> {
         }
     }
 
+    // Steal a batch of elements (half of them, or at most `limit`).
+    //
+    // In FIFO mode every element leaves through a CAS on top, so the whole
+    // batch can be claimed at once. In LIFO mode, the worker pops from the
+    // bottom without a CAS for as long as it sees more than one element, and
+    // may have done so any number of times since we read bottom; claiming
+    // several elements at once could then hand out some of them twice. So
+    // there we claim one element per CAS, re-reading bottom in between just
+    // like `steal` does.
+    fn steal_batch(&self, dest: &Deque<T>, limit: Option<usize>) -> Steal<T> {
+        let guard = epoch::pin();
+
+        let mut t = self.top.load(Acquire);
+        fence(SeqCst); // top must be loaded before bottom.
+        let b = self.bottom.load(Acquire);
+
+        let size = b - t;
+        if size <= 0 {
+            return Steal::Empty
+        }
+        let n = match limit {
+            Some(n) if (n as isize) < size => n as isize,
+            Some(_) => size,
+            None => (size + 1) / 2,
+        };
+
+        unsafe {
+            if self.flavor == Flavor::Fifo {
+                let a = self.array.load(Acquire, &guard).unwrap();
+                let mut batch = (t..t + n).map(|i| a.get(i)).collect::<Vec<_>>();
+                if self.top.compare_exchange(t, t + n, SeqCst, Relaxed).is_err() {
+                    batch.set_len(0); // someone else stole some of these values
+                    return Steal::Abort
+                }
+                let mut batch = batch.into_iter();
+                let first = batch.next().unwrap();
+                for data in batch {
+                    dest.push(data);
+                }
+                return Steal::Data(first)
+            }
+
+            let a = self.array.load(Acquire, &guard).unwrap();
+            let first = a.get(t);
+            if self.top.compare_exchange(t, t + 1, SeqCst, Relaxed).is_err() {
+                mem::forget(first); // someone else stole this value
+                return Steal::Abort
+            }
+            for _ in 1..n {
+                t += 1;
+                fence(SeqCst); // our CAS on top must come before loading bottom.
+                let b = self.bottom.load(Acquire);
+                if b - t <= 0 { break }
+
+                let a = self.array.load(Acquire, &guard).unwrap();
+                let data = a.get(t);
+                if self.top.compare_exchange(t, t + 1, SeqCst, Relaxed).is_err() {
+                    mem::forget(data);
+                    break
+                }
+                dest.push(data);
+            }
+            Steal::Data(first)
+        }
+    }
+
+    fn len(&self) -> usize {
+        let t = self.top.load(Acquire);
+        fence(SeqCst); // top must be loaded before bottom.
+        let b = self.bottom.load(Acquire);
+        // a pop in progress may have bottom one below top for a moment
+        cmp::max(b - t, 0) as usize
+    }
+
+    fn capacity(&self) -> usize {
+        let guard = epoch::pin();
+        let a = self.array.load(Acquire, &guard).unwrap();
+        // `push` grows the buffer while one slot is still free
+        a.size() - 1
+    }
+
     // potentially shrink the array. This can be called only from the worker.
     unsafe fn maybe_shrink(&self, b: isize, t: isize, guard: &epoch::Guard) {
         let a = self.array.load(SeqCst, guard).unwrap();
@@ -360,12 +601,11 @@ impl<T> Buffer<T> {
 mod tests {
     extern crate rand;
 
-    use super::{deque, Worker, Stealer, Steal};
+    use super::{deque, deque_fifo, Injector, Worker, Stealer, Steal};
 
     use std::thread;
     use std::sync::Arc;
-    use std::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT,
-                            AtomicUsize, ATOMIC_USIZE_INIT};
+    use std::sync::atomic::{AtomicBool, AtomicUsize};
     use std::sync::atomic::Ordering::SeqCst;
 
     use self::rand::Rng;
@@ -478,6 +718,12 @@ mod tests {
         stampede(w, s, 8, 10000);
     }
 
+    #[test]
+    fn run_stampede_fifo() {
+        let (w, s) = deque_fifo();
+        stampede(w, s, 8, 10000);
+    }
+
     #[test]
     fn many_stampede() {
         static AMT: usize = 4;
@@ -497,8 +743,8 @@ mod tests {
     fn stress() {
         static AMT: isize = 100000;
         static NTHREADS: isize = 8;
-        static DONE: AtomicBool = ATOMIC_BOOL_INIT;
-        static HITS: AtomicUsize = ATOMIC_USIZE_INIT;
+        static DONE: AtomicBool = AtomicBool::new(false);
+        static HITS: AtomicUsize = AtomicUsize::new(0);
         let (mut w, s) = deque();
 
         let threads = (0..NTHREADS).map(|_| {
@@ -550,7 +796,7 @@ mod tests {
     fn no_starvation() {
         static AMT: isize = 10000;
         static NTHREADS: isize = 4;
-        static DONE: AtomicBool = ATOMIC_BOOL_INIT;
+        static DONE: AtomicBool = AtomicBool::new(false);
         let (mut w, s) = deque();
 
         let (threads, hits): (Vec<_>, Vec<_>) = (0..NTHREADS).map(|_| {
@@ -599,4 +845,365 @@ mod tests {
             thread.join().unwrap();
         }
     }
+
+    #[test]
+    fn steal_half_into() {
+        let (mut w, s) = deque();
+        let (mut w2, s2) = deque();
+        assert_eq!(s.steal_half_into(&mut w2), Steal::Empty);
+        for i in 0..10 {
+            w.push(i);
+        }
+        assert_eq!(s.steal_half_into(&mut w2), Steal::Data(0));
+        assert_eq!(s2.steal(), Steal::Data(1));
+        assert_eq!(w2.try_pop(), Some(4));
+        assert_eq!(w2.try_pop(), Some(3));
+        assert_eq!(w2.try_pop(), Some(2));
+        assert_eq!(w2.try_pop(), None);
+        assert_eq!(s.steal(), Steal::Data(5));
+        assert_eq!(w.try_pop(), Some(9));
+
+        // a single element is taken whole
+        let (mut w, s) = deque();
+        w.push(1);
+        assert_eq!(s.steal_half_into(&mut w2), Steal::Data(1));
+        assert_eq!(w.try_pop(), None);
+        assert_eq!(w2.try_pop(), None);
+    }
+
+    #[test]
+    fn steal_many() {
+        let (mut w, s) = deque();
+        let (mut w2, _s2) = deque();
+        for i in 0..5 {
+            w.push(i);
+        }
+        assert_eq!(s.steal_many(3, &mut w2), Steal::Data(0));
+        assert_eq!(w2.try_pop(), Some(2));
+        assert_eq!(w2.try_pop(), Some(1));
+        assert_eq!(w2.try_pop(), None);
+        assert_eq!(s.steal_many(10, &mut w2), Steal::Data(3));
+        assert_eq!(w2.try_pop(), Some(4));
+        assert_eq!(w.try_pop(), None);
+        assert_eq!(s.steal_many(1, &mut w2), Steal::Empty);
+    }
+
+    #[test]
+    fn steal_batch_stress() {
+        const AMT: usize = 100000;
+        const NTHREADS: usize = 4;
+        let (mut w, s) = deque();
+        let hits = Arc::new(AtomicUsize::new(0));
+        let sum = Arc::new(AtomicUsize::new(0));
+
+        let threads = (0..NTHREADS).map(|i| {
+            let s = s.clone();
+            let hits = hits.clone();
+            let sum = sum.clone();
+            thread::spawn(move || {
+                let (mut mine, _) = deque();
+                while hits.load(SeqCst) < AMT {
+                    let res = if i % 2 == 0 {
+                        s.steal_half_into(&mut mine)
+                    } else {
+                        s.steal_many(3, &mut mine)
+                    };
+                    let take = |x: Box<usize>| {
+                        sum.fetch_add(*x, SeqCst);
+                        hits.fetch_add(1, SeqCst);
+                    };
+                    match res {
+                        Steal::Data(x) => {
+                            take(x);
+                            while let Some(x) = mine.try_pop() {
+                                take(x);
+                            }
+                        }
+                        Steal::Abort | Steal::Empty => thread::yield_now(),
+                    }
+                }
+            })
+        }).collect::<Vec<_>>();
+
+        let mut rng = rand::thread_rng();
+        let mut pushed = 0;
+        let mut mysum = 0;
+        while hits.load(SeqCst) < AMT {
+            if pushed < AMT && rng.gen_range(0, 3) != 2 {
+                pushed += 1;
+                w.push(Box::new(pushed));
+            } else if let Some(x) = w.try_pop() {
+                mysum += *x;
+                hits.fetch_add(1, SeqCst);
+            }
+        }
+
+        for thread in threads.into_iter() {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(hits.load(SeqCst), AMT);
+        assert_eq!(sum.load(SeqCst) + mysum, AMT * (AMT + 1) / 2);
+    }
+
+    #[test]
+    fn owner_pops_lifo_during_steal_half_into() {
+        const AMT: usize = 10000;
+        const NTHREADS: usize = 3;
+
+        for _ in 0..10 {
+            let (mut w, s) = deque();
+            for i in 0..AMT {
+                w.push(i);
+            }
+
+            let threads = (0..NTHREADS).map(|_| {
+                let s = s.clone();
+                thread::spawn(move || {
+                    let (mut mine, _) = deque();
+                    let mut stolen = vec![];
+                    loop {
+                        match s.steal_half_into(&mut mine) {
+                            Steal::Data(x) => {
+                                stolen.push(x);
+                                while let Some(x) = mine.try_pop() {
+                                    stolen.push(x);
+                                }
+                            }
+                            Steal::Abort => thread::yield_now(),
+                            Steal::Empty => return stolen,
+                        }
+                    }
+                })
+            }).collect::<Vec<_>>();
+
+            // the owner always takes the most recently pushed element left
+            let mut popped = vec![];
+            while let Some(x) = w.try_pop() {
+                if let Some(&last) = popped.last() {
+                    assert!(x < last, "popped {} after {}", x, last);
+                }
+                popped.push(x);
+            }
+
+            let mut all = popped;
+            for thread in threads.into_iter() {
+                all.extend(thread.join().unwrap());
+            }
+            all.sort();
+            assert_eq!(all, (0..AMT).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn injector_smoke() {
+        let q = Injector::new();
+        assert!(q.is_empty());
+        assert_eq!(q.steal(), Steal::Empty);
+        q.push(1);
+        q.push(2);
+        assert!(!q.is_empty());
+        assert_eq!(q.steal(), Steal::Data(1));
+        assert_eq!(q.steal(), Steal::Data(2));
+        assert_eq!(q.steal(), Steal::Empty);
+    }
+
+    #[test]
+    fn injector_steal_batch_and_pop() {
+        let q = Injector::new();
+        let (mut w, s) = deque();
+        assert_eq!(q.steal_batch_and_pop(&mut w), Steal::Empty);
+        for i in 0..100 {
+            q.push(i);
+        }
+
+        let mut got = vec![];
+        loop {
+            match q.steal_batch_and_pop(&mut w) {
+                Steal::Data(i) => got.push(i),
+                Steal::Abort => {}
+                Steal::Empty => break,
+            }
+            while let Steal::Data(i) = s.steal() {
+                got.push(i);
+            }
+        }
+        assert_eq!(got, (0..100).collect::<Vec<_>>());
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn injector_drop_remaining() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        struct Elem;
+        impl Drop for Elem {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, SeqCst);
+            }
+        }
+
+        let q = Injector::new();
+        for _ in 0..100 {
+            q.push(Elem);
+        }
+        for _ in 0..40 {
+            match q.steal() {
+                Steal::Data(e) => drop(e),
+                _ => panic!(),
+            }
+        }
+        assert_eq!(DROPS.load(SeqCst), 40);
+        drop(q);
+        assert_eq!(DROPS.load(SeqCst), 100);
+    }
+
+    #[test]
+    fn injector_mpmc() {
+        const AMT: usize = 100000;
+        const NTHREADS: usize = 4;
+        let q = Arc::new(Injector::new());
+        let hits = Arc::new(AtomicUsize::new(0));
+        let sum = Arc::new(AtomicUsize::new(0));
+
+        let producers = (0..2).map(|p| {
+            let q = q.clone();
+            thread::spawn(move || {
+                for i in 0..AMT / 2 {
+                    q.push(2 * i + p + 1);
+                }
+            })
+        }).collect::<Vec<_>>();
+
+        let consumers = (0..NTHREADS).map(|c| {
+            let q = q.clone();
+            let hits = hits.clone();
+            let sum = sum.clone();
+            thread::spawn(move || {
+                let (mut w, _) = deque();
+                while hits.load(SeqCst) < AMT {
+                    let res = if c % 2 == 0 {
+                        q.steal_batch_and_pop(&mut w)
+                    } else {
+                        q.steal()
+                    };
+                    match res {
+                        Steal::Data(i) => {
+                            sum.fetch_add(i, SeqCst);
+                            hits.fetch_add(1, SeqCst);
+                            while let Some(i) = w.try_pop() {
+                                sum.fetch_add(i, SeqCst);
+                                hits.fetch_add(1, SeqCst);
+                            }
+                        }
+                        Steal::Abort | Steal::Empty => thread::yield_now(),
+                    }
+                }
+            })
+        }).collect::<Vec<_>>();
+
+        for t in producers.into_iter().chain(consumers) {
+            t.join().unwrap();
+        }
+        assert_eq!(hits.load(SeqCst), AMT);
+        assert_eq!(sum.load(SeqCst), AMT * (AMT + 1) / 2);
+    }
+
+    #[test]
+    fn fifo_smoke() {
+        let (mut w, s) = deque_fifo();
+        assert_eq!(w.try_pop(), None);
+        w.push(1);
+        w.push(2);
+        w.push(3);
+        assert_eq!(w.try_pop(), Some(1));
+        assert_eq!(s.steal(), Steal::Data(2));
+        assert_eq!(w.try_pop(), Some(3));
+        assert_eq!(w.try_pop(), None);
+        assert_eq!(s.steal(), Steal::Empty);
+    }
+
+    #[test]
+    fn fifo_order_across_resizes() {
+        let (mut w, _s) = deque_fifo();
+        let mut next = 0;
+        for round in 1..4 {
+            // grow well past the initial buffer, then drain enough to shrink
+            for i in 0..1000 * round {
+                w.push(next + i);
+            }
+            for i in 0..1000 * round {
+                assert_eq!(w.try_pop(), Some(next + i));
+            }
+            next += 1000 * round;
+        }
+        assert_eq!(w.try_pop(), None);
+    }
+
+    #[test]
+    fn fifo_steal_half_into() {
+        let (mut w, s) = deque();
+        let (mut w2, _s2) = deque_fifo();
+        for i in 0..10 {
+            w.push(i);
+        }
+        assert_eq!(s.steal_half_into(&mut w2), Steal::Data(0));
+        assert_eq!(w2.try_pop(), Some(1));
+        assert_eq!(w2.try_pop(), Some(2));
+        assert_eq!(w2.try_pop(), Some(3));
+        assert_eq!(w2.try_pop(), Some(4));
+        assert_eq!(w2.try_pop(), None);
+    }
+
+    #[test]
+    fn len_and_capacity() {
+        let (mut w, s) = deque();
+        assert_eq!(w.len(), 0);
+        assert!(w.is_empty() && s.is_empty());
+        let initial = w.capacity();
+        assert_eq!(s.capacity(), initial);
+
+        for i in 0..initial {
+            w.push(i);
+        }
+        assert_eq!(w.len(), initial);
+        assert_eq!(w.capacity(), initial);
+
+        // growing
+        for i in initial..10000 {
+            w.push(i);
+            assert_eq!(s.len(), i + 1);
+        }
+        assert!(w.capacity() >= 10000);
+        assert_eq!(s.capacity(), w.capacity());
+
+        // shrinking through pops and steals
+        let grown = w.capacity();
+        let mut len = 10000;
+        while len > 0 {
+            if len % 2 == 0 {
+                assert!(w.try_pop().is_some());
+            } else {
+                assert!(s.steal() != Steal::Empty);
+            }
+            len -= 1;
+            assert_eq!(w.len(), len);
+            assert_eq!(s.is_empty(), len == 0);
+        }
+        assert!(w.capacity() < grown);
+        assert!(w.capacity() >= initial);
+    }
+
+    #[test]
+    fn len_fifo() {
+        let (mut w, s) = deque_fifo();
+        for i in 0..1000 {
+            w.push(i);
+        }
+        assert_eq!(s.len(), 1000);
+        for i in 0..1000 {
+            assert_eq!(w.try_pop(), Some(i));
+            assert_eq!(w.len(), 999 - i);
+        }
+        assert!(w.is_empty());
+    }
 }
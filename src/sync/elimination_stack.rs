@@ -0,0 +1,238 @@
+use std::ptr;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::{Acquire, Release, Relaxed};
+
+use mem::CachePadded;
+use mem::epoch::{self, Atomic, Guard, Owned, Shared};
+use sync::treiber_stack::Node;
+use util::random;
+
+// Number of slots in the elimination array; only the first `width` of them are
+// in use at any time.
+const SLOTS: usize = 16;
+
+// Number of times a push parked in a slot checks whether a pop took its node.
+const PATIENCE: usize = 128;
+
+/// Treiber's lock-free stack with an elimination-backoff array.
+///
+/// When a `push` or `try_pop` loses the race on the top of the stack, it goes
+/// to a random slot of the elimination array instead, where a push and a pop
+/// can exchange the value directly and leave the stack alone. The part of the
+/// array in use grows when threads collide there and shrinks when they wait in
+/// vain.
+///
+/// Usable with any number of producers and consumers.
+#[derive(Debug)]
+pub struct EliminationStack<T> {
+    head: Atomic<Node<T>>,
+    slots: Box<[CachePadded<Atomic<Node<T>>>]>,
+    width: AtomicUsize,
+}
+
+impl<T> EliminationStack<T> {
+    /// Create a new, empty stack.
+    pub fn new() -> EliminationStack<T> {
+        EliminationStack {
+            head: Atomic::null(),
+            slots: (0..SLOTS).map(|_| CachePadded::new(Atomic::null())).collect::<Vec<_>>()
+                                                                         .into_boxed_slice(),
+            width: AtomicUsize::new(1),
+        }
+    }
+
+    /// Push `t` on top of the stack.
+    pub fn push(&self, t: T) {
+        let guard = epoch::pin();
+        let n = Owned::new(Node {
+            data: t,
+            next: Atomic::null(),
+        }).into_shared(&guard);
+        loop {
+            let head = self.head.load(Relaxed, &guard);
+            n.next.store_shared(head, Relaxed);
+            if self.head.cas_shared(head, Some(n), Release) {
+                return;
+            }
+            if self.eliminate_push(n, &guard) {
+                return;
+            }
+        }
+    }
+
+    /// Attempt to pop the top element of the stack.
+    ///
+    /// Returns `None` if the stack is observed to be empty.
+    pub fn try_pop(&self) -> Option<T> {
+        let guard = epoch::pin();
+        loop {
+            let n = match self.head.load(Acquire, &guard) {
+                Some(head) => {
+                    let next = head.next.load(Relaxed, &guard);
+                    if self.head.cas_shared(Some(head), next, Release) {
+                        head
+                    } else if let Some(n) = self.eliminate_pop(&guard) {
+                        n
+                    } else {
+                        continue;
+                    }
+                }
+                None => return None,
+            };
+            unsafe {
+                guard.unlinked(n);
+                return Some(ptr::read(&(*n).data));
+            }
+        }
+    }
+
+    /// Check if this stack is empty.
+    pub fn is_empty(&self) -> bool {
+        let guard = epoch::pin();
+        self.head.load(Acquire, &guard).is_none()
+    }
+
+    // Park `n` in a slot for a while, hoping for a pop to take it. Returns
+    // `true` if one did.
+    fn eliminate_push(&self, n: Shared<Node<T>>, guard: &Guard) -> bool {
+        let slot = &self.slots[random(self.width.load(Relaxed))];
+        if !slot.cas_shared(None, Some(n), Release) {
+            // somebody else is waiting here already
+            self.grow();
+            return false;
+        }
+
+        for _ in 0..PATIENCE {
+            if slot.load(Relaxed, guard).map(|p| p.as_raw()) != Some(n.as_raw()) {
+                return true;
+            }
+        }
+        if slot.cas_shared(Some(n), None, Relaxed) {
+            // nobody came by
+            self.shrink();
+            false
+        } else {
+            true
+        }
+    }
+
+    // Take a node parked by a push, if the slot we pick has one.
+    fn eliminate_pop<'a>(&self, guard: &'a Guard) -> Option<Shared<'a, Node<T>>> {
+        let slot = &self.slots[random(self.width.load(Relaxed))];
+        match slot.load(Acquire, guard) {
+            Some(n) if slot.cas_shared(Some(n), None, Acquire) => Some(n),
+            _ => None,
+        }
+    }
+
+    fn grow(&self) {
+        let width = self.width.load(Relaxed);
+        if width < SLOTS {
+            let _ = self.width.compare_exchange(width, width + 1, Relaxed, Relaxed);
+        }
+    }
+
+    fn shrink(&self) {
+        let width = self.width.load(Relaxed);
+        if width > 1 {
+            let _ = self.width.compare_exchange(width, width - 1, Relaxed, Relaxed);
+        }
+    }
+}
+
+impl<T> Drop for EliminationStack<T> {
+    fn drop(&mut self) {
+        // We have exclusive access, so no other thread can be looking at the
+        // nodes still in the stack, and every push has left the elimination
+        // array: free the nodes right away, along with their data.
+        let guard = epoch::pin();
+        let mut cur = self.head.swap(None, Relaxed, &guard);
+        while let Some(node) = cur {
+            cur = node.next.load(Relaxed, &guard);
+            unsafe { drop(Box::from_raw(node.as_raw())); }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::thread;
+
+    use scope;
+    use super::*;
+
+    #[test]
+    fn push_pop() {
+        let s = EliminationStack::new();
+        assert!(s.is_empty());
+        assert_eq!(s.try_pop(), None);
+        s.push(1);
+        s.push(2);
+        assert!(!s.is_empty());
+        assert_eq!(s.try_pop(), Some(2));
+        assert_eq!(s.try_pop(), Some(1));
+        assert_eq!(s.try_pop(), None);
+        assert!(s.is_empty());
+    }
+
+    #[test]
+    fn push_pop_many_mpmc() {
+        const COUNT: usize = 100000;
+        const THREADS: usize = 8;
+        let s = EliminationStack::new();
+        let popped = AtomicUsize::new(0);
+        let sum = AtomicUsize::new(0);
+
+        scope(|scope| {
+            for t in 0..THREADS {
+                let s = &s;
+                let popped = &popped;
+                let sum = &sum;
+                scope.spawn(move || {
+                    for i in 0..COUNT / THREADS {
+                        s.push(t * (COUNT / THREADS) + i + 1);
+                    }
+                });
+                scope.spawn(move || {
+                    while popped.load(SeqCst) < COUNT {
+                        match s.try_pop() {
+                            Some(x) => {
+                                sum.fetch_add(x, SeqCst);
+                                popped.fetch_add(1, SeqCst);
+                            }
+                            None => thread::yield_now(),
+                        }
+                    }
+                });
+            }
+        });
+
+        assert!(s.is_empty());
+        assert_eq!(sum.load(SeqCst), COUNT * (COUNT + 1) / 2);
+        assert!(s.width.load(SeqCst) >= 1 && s.width.load(SeqCst) <= SLOTS);
+    }
+
+    #[test]
+    fn drop_remaining() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        struct Elem;
+        impl Drop for Elem {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, SeqCst);
+            }
+        }
+
+        let s = EliminationStack::new();
+        for _ in 0..100 {
+            s.push(Elem);
+        }
+        for _ in 0..40 {
+            drop(s.try_pop().unwrap());
+        }
+        assert_eq!(DROPS.load(SeqCst), 40);
+        drop(s);
+        assert_eq!(DROPS.load(SeqCst), 100);
+    }
+}
@@ -0,0 +1,684 @@
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::mem;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::{Acquire, AcqRel, Release, Relaxed};
+
+use mem::epoch::{self, Atomic, Guard, Owned, Shared};
+
+// Number of buckets of a new map.
+const INITIAL_BUCKETS: usize = 2;
+
+// Average number of entries per bucket above which the bucket count doubles.
+const LOAD_FACTOR: usize = 2;
+
+/// A lock-free hash map, based on split-ordered lists.
+///
+/// All entries live in a single lock-free linked list, sorted by their
+/// bit-reversed hash. A bucket is a pointer to a sentinel node in that list,
+/// and every bucket splits into two when the map doubles its bucket count:
+/// growing only means bumping the count, and new buckets are wired into the
+/// list lazily, the first time they are used. Nothing ever moves, so the map
+/// never stops the world to grow.
+///
+/// Lookups and iteration happen under a `Guard`, and return references which
+/// remain valid for as long as the guard does, even if the entry is removed
+/// from the map meanwhile. Replaced and removed entries are dropped later by
+/// the epoch garbage collector, possibly on another thread, so keys and values
+/// must be `Send + 'static`.
+///
+/// Any number of threads may insert, remove and look up entries at once; none
+/// of them ever waits for the map to grow.
+pub struct HashMap<K, V, S = RandomState> {
+    // Segment `i` holds buckets `2^(i-1)..2^i`, segment 0 just bucket 0;
+    // segments are allocated on first use.
+    segments: Box<[Atomic<Segment<K, V>>]>,
+    buckets: AtomicUsize,
+    len: AtomicUsize,
+    hasher: S,
+}
+
+type Segment<K, V> = Box<[Atomic<Node<K, V>>]>;
+
+// A node is removed by tagging its `next` pointer, after which whoever comes
+// across it unlinks it.
+struct Node<K, V> {
+    // The bit-reversed hash: even for bucket sentinels, odd for entries.
+    hash: usize,
+    entry: Option<(K, V)>,
+    next: Atomic<Node<K, V>>,
+}
+
+/// Iterator over the entries of a `HashMap`, in no particular order.
+pub struct Iter<'a, K: 'a, V: 'a> {
+    guard: &'a Guard,
+    next: Option<Shared<'a, Node<K, V>>>,
+}
+
+impl<K, V> HashMap<K, V>
+    where K: Hash + Eq + Send + 'static,
+          V: Send + 'static
+{
+    /// Create a new, empty map.
+    pub fn new() -> HashMap<K, V> {
+        HashMap::with_hasher(RandomState::new())
+    }
+}
+
+impl<K, V, S> HashMap<K, V, S>
+    where K: Hash + Eq + Send + 'static,
+          V: Send + 'static,
+          S: BuildHasher
+{
+    /// Create a new, empty map which will use `hasher` to hash keys.
+    pub fn with_hasher(hasher: S) -> HashMap<K, V, S> {
+        let map = HashMap {
+            segments: (0..usize_bits() + 1).map(|_| Atomic::null()).collect::<Vec<_>>()
+                                            .into_boxed_slice(),
+            buckets: AtomicUsize::new(INITIAL_BUCKETS),
+            len: AtomicUsize::new(0),
+            hasher: hasher,
+        };
+        let guard = epoch::pin();
+        map.slot(0, &guard).store(Some(Owned::new(Node {
+            hash: 0,
+            entry: None,
+            next: Atomic::null(),
+        })), Release);
+        map
+    }
+
+    /// Insert `value` under `key`, replacing any existing entry.
+    pub fn insert(&self, key: K, value: V) {
+        let guard = epoch::pin();
+        let hash = self.hash(&key);
+        let head = self.bucket(hash, &guard);
+        let mut node = Owned::new(Node {
+            hash: entry_hash(hash),
+            entry: Some((key, value)),
+            next: Atomic::null(),
+        });
+
+        loop {
+            let (pred, curr) = self.find(head, node.hash, |n| n.has_key(node.key()), &guard);
+            match curr {
+                Some(old) if old.hash == node.hash => {
+                    // Link ours right behind the old entry and tag the old one
+                    // in the same CAS: lookups go from one straight to the
+                    // other, and never find the key missing in between.
+                    let (succ, tag) = old.next.load_tagged(Acquire, &guard);
+                    if tag != 0 {
+                        continue;
+                    }
+                    node.next.store_shared(succ, Relaxed);
+                    match old.next.cas_and_ref(succ, node.with_tag(1), AcqRel, &guard) {
+                        Ok(new) => {
+                            if pred.cas_shared(curr, Some(new.with_tag(0)), AcqRel) {
+                                unsafe { guard.defer_drop(old) }
+                            }
+                            // otherwise the next `find` over it unlinks it
+                            return;
+                        }
+                        Err(n) => node = n.with_tag(0),
+                    }
+                }
+                _ => {
+                    node.next.store_shared(curr, Relaxed);
+                    match pred.cas_and_ref(curr, node, Release, &guard) {
+                        Ok(_) => {
+                            self.grow();
+                            return;
+                        }
+                        Err(n) => node = n,
+                    }
+                }
+            }
+        }
+    }
+
+    /// Look up the value under `key`.
+    pub fn get<'a, Q>(&'a self, key: &Q, guard: &'a Guard) -> Option<&'a V>
+        where K: Borrow<Q>, Q: ?Sized + Hash + Eq
+    {
+        let hash = self.hash(key);
+        let head = self.bucket(hash, guard);
+        let (_, curr) = self.find(head, entry_hash(hash), |n| n.has_key(key), guard);
+        match curr {
+            Some(node) if node.hash == entry_hash(hash) => {
+                let node: &'a Node<K, V> = node.as_ref();
+                Some(node.value())
+            }
+            _ => None,
+        }
+    }
+
+    /// Check whether there is an entry under `key`.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+        where K: Borrow<Q>, Q: ?Sized + Hash + Eq
+    {
+        let guard = epoch::pin();
+        self.get(key, &guard).is_some()
+    }
+
+    /// Look up the value under `key`, inserting the result of `f` if there is
+    /// none.
+    ///
+    /// `f` is called at most once, but its result may be discarded if another
+    /// thread inserts under `key` first.
+    pub fn compute_if_absent<'a, F>(&'a self, key: K, f: F, guard: &'a Guard) -> &'a V
+        where F: FnOnce(&K) -> V
+    {
+        let hash = self.hash(&key);
+        let head = self.bucket(hash, guard);
+        let (mut pred, mut curr) = self.find(head, entry_hash(hash), |n| n.has_key(&key), guard);
+        if let Some(old) = curr {
+            if old.hash == entry_hash(hash) {
+                let old: &'a Node<K, V> = old.as_ref();
+                return old.value();
+            }
+        }
+
+        let value = f(&key);
+        let mut node = Owned::new(Node {
+            hash: entry_hash(hash),
+            entry: Some((key, value)),
+            next: Atomic::null(),
+        });
+        loop {
+            node.next.store_shared(curr, Relaxed);
+            match pred.cas_and_ref(curr, node, Release, guard) {
+                Ok(new) => {
+                    self.grow();
+                    let new: &'a Node<K, V> = new.as_ref();
+                    return new.value();
+                }
+                Err(n) => node = n,
+            }
+
+            let (p, c) = self.find(head, node.hash, |n| n.has_key(node.key()), guard);
+            if let Some(old) = c {
+                if old.hash == node.hash {
+                    // lost the race, drop our value
+                    let old: &'a Node<K, V> = old.as_ref();
+                    return old.value();
+                }
+            }
+            pred = p;
+            curr = c;
+        }
+    }
+
+    /// Remove the entry under `key`.
+    ///
+    /// Returns `true` if this call removed an entry.
+    pub fn remove<Q>(&self, key: &Q) -> bool where K: Borrow<Q>, Q: ?Sized + Hash + Eq {
+        let guard = epoch::pin();
+        let hash = self.hash(key);
+        let head = self.bucket(hash, &guard);
+        loop {
+            let (pred, curr) = self.find(head, entry_hash(hash), |n| n.has_key(key), &guard);
+            let node = match curr {
+                Some(node) if node.hash == entry_hash(hash) => node,
+                _ => return false,
+            };
+            let (succ, tag) = node.next.fetch_or(1, AcqRel, &guard);
+            if tag != 0 {
+                continue;
+            }
+            self.len.fetch_sub(1, Relaxed);
+            if pred.cas_shared(curr, succ.map(|s| s.with_tag(0)), AcqRel) {
+                unsafe { guard.defer_drop(node) }
+            } else {
+                // leave it to `find`
+                self.find(head, entry_hash(hash), |n| n.has_key(key), &guard);
+            }
+            return true;
+        }
+    }
+
+    /// The number of entries in the map.
+    ///
+    /// Only a snapshot while other threads are modifying the map.
+    pub fn len(&self) -> usize {
+        self.len.load(Relaxed)
+    }
+
+    /// Check if this map is empty.
+    pub fn is_empty(&self) -> bool {
+        let guard = epoch::pin();
+        self.iter(&guard).next().is_none()
+    }
+
+    /// Iterate over all entries, in no particular order.
+    ///
+    /// Entries inserted or removed during the iteration may or may not be
+    /// seen, but no entry is seen twice.
+    pub fn iter<'a>(&'a self, guard: &'a Guard) -> Iter<'a, K, V> {
+        Iter {
+            guard: guard,
+            next: self.slot(0, guard).load(Acquire, guard),
+        }
+    }
+
+    fn hash<Q: ?Sized + Hash>(&self, key: &Q) -> usize {
+        let mut hasher = self.hasher.build_hasher();
+        key.hash(&mut hasher);
+        hasher.finish() as usize
+    }
+
+    // Count a new entry, doubling the bucket count if there are too many.
+    fn grow(&self) {
+        let len = self.len.fetch_add(1, Relaxed) + 1;
+        let buckets = self.buckets.load(Relaxed);
+        if len > buckets * LOAD_FACTOR && buckets < 1 << (usize_bits() - 1) {
+            let _ = self.buckets.compare_exchange(buckets, buckets * 2, Relaxed, Relaxed);
+        }
+    }
+
+    // The slot holding the sentinel of bucket `index`.
+    fn slot<'a>(&'a self, index: usize, guard: &'a Guard) -> &'a Atomic<Node<K, V>> {
+        let seg = usize_bits() - index.leading_zeros() as usize;
+        let (len, offset) = if seg == 0 { (1, 0) } else { (1 << (seg - 1), index - (1 << (seg - 1))) };
+
+        let segment = match self.segments[seg].load(Acquire, guard) {
+            Some(segment) => segment,
+            None => {
+                let new = Owned::new((0..len).map(|_| Atomic::null()).collect::<Vec<_>>()
+                                             .into_boxed_slice());
+                match self.segments[seg].cas_and_ref(None, new, AcqRel, guard) {
+                    Ok(segment) => segment,
+                    Err(_) => self.segments[seg].load(Acquire, guard).unwrap(),
+                }
+            }
+        };
+        let segment: &'a Segment<K, V> = segment.as_ref();
+        &segment[offset]
+    }
+
+    // The sentinel of the bucket for `hash`, wiring it into the list first if
+    // needed.
+    fn bucket<'a>(&'a self, hash: usize, guard: &'a Guard) -> &'a Node<K, V> {
+        let index = hash & (self.buckets.load(Acquire) - 1);
+        let slot = self.slot(index, guard);
+        if let Some(sentinel) = slot.load(Acquire, guard) {
+            return sentinel.as_ref();
+        }
+
+        // The parent bucket, with the top bit cleared, is the one this bucket
+        // splits off from: the sentinel goes somewhere after the parent's.
+        let parent = self.bucket(index & !(1 << (usize_bits() - 1 - index.leading_zeros() as usize)),
+                                 guard);
+        let mut node = Owned::new(Node {
+            hash: reverse(index),
+            entry: None,
+            next: Atomic::null(),
+        });
+        let sentinel;
+        loop {
+            let (pred, curr) = self.find(parent, node.hash, |_| true, guard);
+            if let Some(curr) = curr {
+                if curr.hash == node.hash {
+                    // somebody else got there first
+                    sentinel = curr;
+                    break;
+                }
+            }
+            node.next.store_shared(curr, Relaxed);
+            match pred.cas_and_ref(curr, node, Release, guard) {
+                Ok(s) => {
+                    sentinel = s;
+                    break;
+                }
+                Err(n) => node = n,
+            }
+        }
+        slot.store_shared(Some(sentinel), Release);
+        sentinel.as_ref()
+    }
+
+    // Search the list after `head` for the first node with a hash greater
+    // than `hash`, or equal and accepted by `matches`, unlinking removed nodes
+    // along the way. Returns that node, and the pointer to it.
+    fn find<'a, F>(&self, head: &'a Node<K, V>, hash: usize, matches: F, guard: &'a Guard)
+                   -> (&'a Atomic<Node<K, V>>, Option<Shared<'a, Node<K, V>>>)
+        where F: Fn(&Node<K, V>) -> bool
+    {
+        'retry: loop {
+            // sentinels are never removed, so `head.next` has no tag
+            let mut pred = &head.next;
+            let mut curr = pred.load(Acquire, guard);
+            while let Some(c) = curr {
+                let (succ, tag) = c.next.load_tagged(Acquire, guard);
+                let succ = succ.map(|s| s.with_tag(0));
+                if tag != 0 {
+                    if !pred.cas_shared(curr, succ, AcqRel) {
+                        continue 'retry;
+                    }
+                    unsafe { guard.defer_drop(c) }
+                    curr = succ;
+                    continue;
+                }
+                if c.hash > hash || c.hash == hash && matches(&c) {
+                    break;
+                }
+                let c: &'a Node<K, V> = c.as_ref();
+                pred = &c.next;
+                curr = succ;
+            }
+            return (pred, curr);
+        }
+    }
+}
+
+impl<K, V, S> Drop for HashMap<K, V, S> {
+    fn drop(&mut self) {
+        // Nodes already unlinked were handed to the epoch garbage; everything
+        // still in the list is ours, sentinels and tagged entries included.
+        // The bucket slots only point into the list, so segments go as is.
+        let guard = epoch::pin();
+        let mut cur = self.segments[0].load(Relaxed, &guard)
+                                      .and_then(|s| s[0].load(Relaxed, &guard));
+        while let Some(node) = cur {
+            cur = node.next.load(Relaxed, &guard);
+            unsafe { drop(Box::from_raw(node.as_raw())); }
+        }
+        for segment in self.segments.iter() {
+            if let Some(segment) = segment.load(Relaxed, &guard) {
+                unsafe { drop(Box::from_raw(segment.as_raw())); }
+            }
+        }
+    }
+}
+
+impl<K, V, S> fmt::Debug for HashMap<K, V, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HashMap {{ ... }}")
+    }
+}
+
+impl<K, V> Node<K, V> {
+    fn has_key<Q: ?Sized + Eq>(&self, key: &Q) -> bool where K: Borrow<Q> {
+        match self.entry {
+            Some((ref k, _)) => k.borrow() == key,
+            None => false,
+        }
+    }
+
+    fn key(&self) -> &K {
+        &self.entry.as_ref().unwrap().0
+    }
+
+    fn value(&self) -> &V {
+        &self.entry.as_ref().unwrap().1
+    }
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        while let Some(node) = self.next {
+            let (succ, tag) = node.next.load_tagged(Acquire, self.guard);
+            self.next = succ;
+            let node: &'a Node<K, V> = node.as_ref();
+            if let Some((ref k, ref v)) = node.entry {
+                if tag == 0 {
+                    return Some((k, v));
+                }
+            }
+        }
+        None
+    }
+}
+
+impl<'a, K, V> fmt::Debug for Iter<'a, K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Iter {{ ... }}")
+    }
+}
+
+fn usize_bits() -> usize {
+    mem::size_of::<usize>() * 8
+}
+
+fn reverse(mut x: usize) -> usize {
+    let mut r = 0;
+    for _ in 0..usize_bits() {
+        r = (r << 1) | (x & 1);
+        x >>= 1;
+    }
+    r
+}
+
+// Entries sort after the sentinel of their bucket, which has the same
+// reversed bits but for the lowest one.
+fn entry_hash(hash: usize) -> usize {
+    reverse(hash) | 1
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::hash::{BuildHasherDefault, Hash, Hasher};
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::thread;
+
+    use mem::epoch;
+    use scope;
+    use super::*;
+
+    // Walk the underlying list, checking that it is in split order, that
+    // every initialized bucket points at its own sentinel, and that no key
+    // shows up twice. Returns the number of live entries.
+    fn check_list<K, V, S>(m: &HashMap<K, V, S>) -> usize
+        where K: Hash + Eq + Send + 'static, V: Send + 'static, S: BuildHasher
+    {
+        let guard = epoch::pin();
+        let buckets = m.buckets.load(SeqCst);
+        for index in 0..buckets {
+            if let Some(sentinel) = m.slot(index, &guard).load(SeqCst, &guard) {
+                assert_eq!(sentinel.hash, reverse(index));
+                assert!(sentinel.entry.is_none());
+            }
+        }
+
+        let mut live = 0;
+        let mut last = 0;
+        let mut cur = m.slot(0, &guard).load(SeqCst, &guard);
+        let mut seen = Vec::new();
+        while let Some(node) = cur {
+            let (succ, tag) = node.next.load_tagged(SeqCst, &guard);
+            let node = node.as_ref();
+            assert!(node.hash >= last);
+            if node.hash != last {
+                seen.clear();
+            }
+            last = node.hash;
+            if tag == 0 && node.entry.is_some() {
+                assert!(!seen.iter().any(|k| *k == node.key()));
+                seen.push(node.key());
+                live += 1;
+            }
+            cur = succ.map(|s| s.with_tag(0));
+        }
+        live
+    }
+
+    #[test]
+    fn replace() {
+        let m = HashMap::new();
+        m.insert("a", 1);
+        m.insert("b", 2);
+        m.insert("a", 10);
+        m.insert("a", 100);
+
+        let guard = epoch::pin();
+        assert_eq!(m.get("a", &guard), Some(&100));
+        assert_eq!(m.get("b", &guard), Some(&2));
+        assert_eq!(m.len(), 2);
+        assert_eq!(check_list(&m), 2);
+
+        assert!(m.remove("a"));
+        assert!(!m.contains_key("a"));
+        assert_eq!(m.len(), 1);
+        assert_eq!(check_list(&m), 1);
+    }
+
+    #[test]
+    fn replace_never_hides_key() {
+        const THREADS: usize = 2;
+        const COUNT: usize = 20000;
+        let m = HashMap::new();
+        for k in 0..4 {
+            m.insert(k, 0);
+        }
+        let done = AtomicUsize::new(0);
+
+        scope(|scope| {
+            for _ in 0..THREADS {
+                let m = &m;
+                let done = &done;
+                scope.spawn(move || {
+                    for i in 0..COUNT {
+                        m.insert(i % 4, i);
+                    }
+                    done.fetch_add(1, SeqCst);
+                });
+            }
+            let m = &m;
+            let done = &done;
+            scope.spawn(move || {
+                while done.load(SeqCst) < THREADS {
+                    let guard = epoch::pin();
+                    for k in 0..4 {
+                        assert!(m.get(&k, &guard).is_some());
+                    }
+                    assert_eq!(m.iter(&guard).count(), 4);
+                    thread::yield_now();
+                }
+            });
+        });
+
+        assert_eq!(m.len(), 4);
+        assert_eq!(check_list(&m), 4);
+    }
+
+    #[test]
+    fn compute_if_absent() {
+        let m = HashMap::new();
+        let guard = epoch::pin();
+        assert_eq!(*m.compute_if_absent(1, |k| k * 10, &guard), 10);
+        assert_eq!(*m.compute_if_absent(1, |_| panic!("computed twice"), &guard), 10);
+        assert_eq!(m.len(), 1);
+    }
+
+    #[test]
+    fn compute_if_absent_races_remove() {
+        const KEYS: usize = 8;
+        const ROUNDS: usize = 5000;
+        let m = HashMap::new();
+
+        scope(|scope| {
+            for _ in 0..2 {
+                let m = &m;
+                scope.spawn(move || {
+                    for i in 0..ROUNDS {
+                        let guard = epoch::pin();
+                        let k = i % KEYS;
+                        assert_eq!(*m.compute_if_absent(k, |k| k * 10, &guard), k * 10);
+                    }
+                });
+                scope.spawn(move || {
+                    for i in 0..ROUNDS {
+                        m.remove(&(i % KEYS));
+                    }
+                });
+            }
+        });
+
+        let live = check_list(&m);
+        assert_eq!(m.len(), live);
+        let guard = epoch::pin();
+        assert_eq!(m.iter(&guard).count(), live);
+        for (k, v) in m.iter(&guard) {
+            assert_eq!(*v, *k * 10);
+        }
+    }
+
+    #[test]
+    fn grow() {
+        let m = HashMap::new();
+        for i in 0..10000 {
+            m.insert(i, i * 2);
+        }
+        assert!(m.buckets.load(SeqCst) >= 10000 / LOAD_FACTOR);
+
+        let guard = epoch::pin();
+        for i in 0..10000 {
+            assert_eq!(m.get(&i, &guard), Some(&(i * 2)));
+        }
+        let mut keys = m.iter(&guard).map(|(k, _)| *k).collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, (0..10000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn split_buckets_concurrently() {
+        const THREADS: usize = 4;
+        const COUNT: usize = 5000;
+        let m = HashMap::new();
+
+        // every thread keeps wiring in new buckets while the others insert
+        // into the ones being split
+        scope(|scope| {
+            for t in 0..THREADS {
+                let m = &m;
+                scope.spawn(move || {
+                    for i in 0..COUNT {
+                        m.insert(i * THREADS + t, t);
+                    }
+                });
+            }
+        });
+
+        assert_eq!(m.len(), THREADS * COUNT);
+        assert!(m.buckets.load(SeqCst) >= THREADS * COUNT / LOAD_FACTOR);
+        assert_eq!(check_list(&m), THREADS * COUNT);
+
+        let guard = epoch::pin();
+        for k in 0..THREADS * COUNT {
+            assert_eq!(m.get(&k, &guard), Some(&(k % THREADS)));
+        }
+        let keys = m.iter(&guard).map(|(k, _)| *k).collect::<HashSet<_>>();
+        assert_eq!(keys.len(), THREADS * COUNT);
+    }
+
+    #[test]
+    fn collisions() {
+        // every key lands in the same bucket, with the same hash
+        #[derive(Default)]
+        struct Constant;
+        impl Hasher for Constant {
+            fn finish(&self) -> u64 { 42 }
+            fn write(&mut self, _: &[u8]) {}
+        }
+
+        let m = HashMap::with_hasher(BuildHasherDefault::<Constant>::default());
+        for i in 0..100 {
+            m.insert(i, i);
+        }
+        for i in 0..50 {
+            assert!(m.remove(&(i * 2)));
+        }
+
+        let guard = epoch::pin();
+        for i in 0..100 {
+            assert_eq!(m.get(&i, &guard), if i % 2 == 0 { None } else { Some(&i) });
+        }
+        assert_eq!(m.iter(&guard).count(), 50);
+        assert_eq!(check_list(&m), 50);
+    }
+}
@@ -0,0 +1,165 @@
+use std::marker::PhantomData;
+use std::ptr;
+use std::sync::atomic::AtomicPtr;
+use std::sync::atomic::Ordering::{Relaxed, Release};
+
+use mem::hazard;
+
+/// Treiber's lock-free stack, with memory reclaimed through hazard pointers.
+///
+/// This is the same algorithm as `TreiberStack`, for comparing the two
+/// reclamation schemes. Popped nodes are freed as soon as no `try_pop` is
+/// looking at them, so a thread stalled in the middle of an operation holds
+/// back at most one node, instead of all garbage of its epoch. In exchange,
+/// each `try_pop` needs a full fence to protect the top of the stack.
+///
+/// Usable with any number of producers and consumers.
+#[derive(Debug)]
+pub struct HazardTreiberStack<T> {
+    head: AtomicPtr<Node<T>>,
+    _marker: PhantomData<T>,
+}
+
+#[derive(Debug)]
+struct Node<T> {
+    data: T,
+    next: *mut Node<T>,
+}
+
+unsafe impl<T: Send> Sync for HazardTreiberStack<T> {}
+
+impl<T> HazardTreiberStack<T> {
+    /// Create a new, empty stack.
+    pub fn new() -> HazardTreiberStack<T> {
+        HazardTreiberStack {
+            head: AtomicPtr::new(ptr::null_mut()),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Push `t` on top of the stack.
+    pub fn push(&self, t: T) {
+        let n = Box::into_raw(Box::new(Node {
+            data: t,
+            next: ptr::null_mut(),
+        }));
+        // no protection needed: the head is never dereferenced here
+        let mut head = self.head.load(Relaxed);
+        loop {
+            unsafe { (*n).next = head; }
+            match self.head.compare_exchange_weak(head, n, Release, Relaxed) {
+                Ok(_) => return,
+                Err(h) => head = h,
+            }
+        }
+    }
+
+    /// Attempt to pop the top element of the stack.
+    ///
+    /// Returns `None` if the stack is observed to be empty.
+    pub fn try_pop(&self) -> Option<T> {
+        loop {
+            let head = hazard::protect(&self.head);
+            let next = match head.as_ref() {
+                None => return None,
+                Some(node) => node.next,
+            };
+            let raw = head.as_raw();
+            if self.head.compare_exchange(raw, next, Release, Relaxed).is_ok() {
+                unsafe {
+                    let data = ptr::read(&(*raw).data);
+                    drop(head);
+                    hazard::retire(raw);
+                    return Some(data);
+                }
+            }
+        }
+    }
+
+    /// Check if this stack is empty.
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Relaxed).is_null()
+    }
+}
+
+impl<T> Drop for HazardTreiberStack<T> {
+    fn drop(&mut self) {
+        let mut cur = *self.head.get_mut();
+        while !cur.is_null() {
+            let node = unsafe { Box::from_raw(cur) };
+            cur = node.next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::SeqCst;
+
+    use scope;
+    use super::*;
+
+    #[test]
+    fn push_try_pop() {
+        let s = HazardTreiberStack::new();
+        assert!(s.is_empty());
+        s.push(1);
+        s.push(2);
+        assert!(!s.is_empty());
+        assert_eq!(s.try_pop(), Some(2));
+        assert_eq!(s.try_pop(), Some(1));
+        assert_eq!(s.try_pop(), None);
+        assert!(s.is_empty());
+    }
+
+    #[test]
+    fn drop_remaining() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        struct Elem;
+        impl Drop for Elem {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, SeqCst);
+            }
+        }
+
+        let s = HazardTreiberStack::new();
+        for _ in 0..10 {
+            s.push(Elem);
+        }
+        drop(s.try_pop());
+        drop(s);
+        assert_eq!(DROPS.load(SeqCst), 10);
+    }
+
+    #[test]
+    fn mpmc() {
+        const COUNT: usize = 100000;
+        const THREADS: usize = 4;
+
+        let stack = HazardTreiberStack::new();
+        let popped = AtomicUsize::new(0);
+        let sum = AtomicUsize::new(0);
+
+        scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for i in 0..COUNT {
+                        stack.push(i);
+                    }
+                });
+                s.spawn(|| {
+                    while popped.load(SeqCst) < COUNT * THREADS {
+                        if let Some(x) = stack.try_pop() {
+                            sum.fetch_add(x, SeqCst);
+                            popped.fetch_add(1, SeqCst);
+                        }
+                    }
+                });
+            }
+        });
+
+        assert!(stack.try_pop().is_none());
+        assert_eq!(sum.load(SeqCst), THREADS * COUNT * (COUNT - 1) / 2);
+    }
+}
@@ -0,0 +1,393 @@
+use std::borrow::Borrow;
+use std::fmt;
+use std::sync::atomic::Ordering::{Acquire, AcqRel, Release, Relaxed};
+
+use mem::epoch::{self, Atomic, Guard, Owned, Shared};
+
+/// Harris's lock-free ordered linked list, used as a set.
+///
+/// Removing an element takes two steps. First the element is deleted
+/// logically, by tagging the `next` pointer of its node: from then on the node
+/// is out of the set and its successor can no longer change. Then the node is
+/// unlinked physically, by pointing its predecessor past it. Any thread which
+/// comes across a tagged node during a search helps with the second step, so
+/// a remover stalled halfway never blocks the others.
+///
+/// Every operation is linear in the size of the set, so this is meant for
+/// small sets; see `SkipMap` for large ones. `insert`, `remove` and
+/// `contains` may all be called from many threads at once.
+pub struct ListSet<T> {
+    head: Atomic<Node<T>>,
+}
+
+struct Node<T> {
+    data: T,
+    next: Atomic<Node<T>>,
+}
+
+/// Iterator over the elements of a `ListSet`, in ascending order.
+pub struct Iter<'a, T: 'a> {
+    guard: &'a Guard,
+    next: Option<Shared<'a, Node<T>>>,
+}
+
+impl<T: Ord + Send + 'static> ListSet<T> {
+    /// Create a new, empty set.
+    pub fn new() -> ListSet<T> {
+        ListSet { head: Atomic::null() }
+    }
+
+    /// Add `t` to the set.
+    ///
+    /// Returns `false` if it was already there.
+    pub fn insert(&self, t: T) -> bool {
+        let guard = epoch::pin();
+        let mut node = Owned::new(Node {
+            data: t,
+            next: Atomic::null(),
+        });
+        loop {
+            let (pred, curr) = self.find(&node.data, &guard);
+            if let Some(curr) = curr {
+                if curr.data == node.data {
+                    return false;
+                }
+            }
+            node.next.store_shared(curr, Relaxed);
+            match pred.cas(curr, Some(node), Release) {
+                Ok(()) => return true,
+                Err(n) => node = n.unwrap(),
+            }
+        }
+    }
+
+    /// Remove `t` from the set.
+    ///
+    /// Returns `true` if this call removed it.
+    pub fn remove<Q: ?Sized + Ord>(&self, t: &Q) -> bool where T: Borrow<Q> {
+        let guard = epoch::pin();
+        loop {
+            let (pred, curr) = self.find(t, &guard);
+            let node = match curr {
+                Some(node) if node.data.borrow() == t => node,
+                _ => return false,
+            };
+
+            // logical deletion; only one remover gets to tag the pointer
+            let (succ, tag) = node.next.fetch_or(1, AcqRel, &guard);
+            if tag != 0 {
+                continue;
+            }
+
+            // physical unlinking, or leave it to the next search
+            if pred.cas_shared(curr, succ.map(|s| s.with_tag(0)), AcqRel) {
+                unsafe { guard.defer_drop(node) }
+            } else {
+                self.find(t, &guard);
+            }
+            return true;
+        }
+    }
+
+    /// Check whether `t` is in the set.
+    pub fn contains<Q: ?Sized + Ord>(&self, t: &Q) -> bool where T: Borrow<Q> {
+        // Unlike `find`, this doesn't help: skipping tagged nodes is enough,
+        // as their successors are still reachable through them.
+        let guard = epoch::pin();
+        let mut curr = self.head.load(Acquire, &guard);
+        while let Some(node) = curr {
+            let (succ, tag) = node.next.load_tagged(Acquire, &guard);
+            if node.data.borrow() >= t {
+                return tag == 0 && node.data.borrow() == t;
+            }
+            curr = succ;
+        }
+        false
+    }
+
+    /// Check if this set is empty.
+    pub fn is_empty(&self) -> bool {
+        let guard = epoch::pin();
+        self.iter(&guard).next().is_none()
+    }
+
+    /// Iterate over the elements in ascending order.
+    ///
+    /// Elements inserted or removed during the iteration may or may not be
+    /// seen.
+    pub fn iter<'a>(&'a self, guard: &'a Guard) -> Iter<'a, T> {
+        Iter {
+            guard: guard,
+            next: self.head.load(Acquire, guard),
+        }
+    }
+
+    // Find the first node whose element is not less than `t`, unlinking
+    // tagged nodes along the way. Returns that node, and the pointer to it.
+    fn find<'a, Q: ?Sized + Ord>(&'a self, t: &Q, guard: &'a Guard)
+                                 -> (&'a Atomic<Node<T>>, Option<Shared<'a, Node<T>>>)
+        where T: Borrow<Q>
+    {
+        'retry: loop {
+            let mut pred = &self.head;
+            let mut curr = pred.load(Acquire, guard);
+            while let Some(c) = curr {
+                let (succ, tag) = c.next.load_tagged(Acquire, guard);
+                let succ = succ.map(|s| s.with_tag(0));
+                if tag != 0 {
+                    // fails if `pred` got tagged or changed meanwhile
+                    if !pred.cas_shared(curr, succ, AcqRel) {
+                        continue 'retry;
+                    }
+                    unsafe { guard.defer_drop(c) }
+                    curr = succ;
+                    continue;
+                }
+                if c.data.borrow() >= t {
+                    break;
+                }
+                let c: &'a Node<T> = c.as_ref();
+                pred = &c.next;
+                curr = succ;
+            }
+            return (pred, curr);
+        }
+    }
+}
+
+impl<T> Drop for ListSet<T> {
+    fn drop(&mut self) {
+        // A remover may have tagged a node and left the unlinking to a search
+        // that never came; such a node was never retired, so it goes along
+        // with the live ones.
+        let guard = epoch::pin();
+        let mut cur = self.head.load(Relaxed, &guard);
+        while let Some(node) = cur {
+            cur = node.next.load(Relaxed, &guard);
+            unsafe { drop(Box::from_raw(node.as_raw())); }
+        }
+    }
+}
+
+impl<T> fmt::Debug for ListSet<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ListSet {{ ... }}")
+    }
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        while let Some(node) = self.next {
+            let (succ, tag) = node.next.load_tagged(Acquire, self.guard);
+            self.next = succ;
+            if tag == 0 {
+                let node: &'a Node<T> = node.as_ref();
+                return Some(&node.data);
+            }
+        }
+        None
+    }
+}
+
+impl<'a, T> fmt::Debug for Iter<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Iter {{ ... }}")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::SeqCst;
+
+    use mem::epoch;
+    use scope;
+    use super::*;
+
+    // Number of nodes linked into the list, tagged or not.
+    fn linked<T>(s: &ListSet<T>) -> usize {
+        let guard = epoch::pin();
+        let mut n = 0;
+        let mut cur = s.head.load(SeqCst, &guard);
+        while let Some(node) = cur {
+            n += 1;
+            cur = node.next.load(SeqCst, &guard).map(|s| s.with_tag(0));
+        }
+        n
+    }
+
+    // Tag the node holding `t`, like a remover stalled right after the
+    // logical deletion would.
+    fn tag<T: Ord>(s: &ListSet<T>, t: &T) {
+        let guard = epoch::pin();
+        let mut cur = s.head.load(SeqCst, &guard);
+        while let Some(node) = cur {
+            if node.data == *t {
+                node.next.fetch_or(1, SeqCst, &guard);
+                return;
+            }
+            cur = node.next.load(SeqCst, &guard);
+        }
+        panic!("not in the set");
+    }
+
+    #[test]
+    fn insert_remove_contains() {
+        let s = ListSet::new();
+        assert!(s.is_empty());
+        assert!(s.insert(2));
+        assert!(s.insert(1));
+        assert!(s.insert(3));
+        assert!(!s.insert(2));
+        assert!(!s.is_empty());
+
+        assert!(s.contains(&1));
+        assert!(!s.contains(&4));
+        assert!(s.remove(&2));
+        assert!(!s.remove(&2));
+        assert!(!s.contains(&2));
+
+        let guard = epoch::pin();
+        assert_eq!(s.iter(&guard).cloned().collect::<Vec<_>>(), [1, 3]);
+    }
+
+    #[test]
+    fn borrowed_lookup() {
+        let s = ListSet::new();
+        s.insert(String::from("b"));
+        s.insert(String::from("a"));
+        assert!(s.contains("a"));
+        assert!(s.remove("b"));
+        assert!(!s.contains("b"));
+    }
+
+    #[test]
+    fn search_unlinks_tagged_nodes() {
+        let s = ListSet::new();
+        for i in 1..4 {
+            s.insert(i);
+        }
+        tag(&s, &2);
+
+        // out of the set already, but still linked
+        assert!(!s.contains(&2));
+        assert_eq!(linked(&s), 3);
+        {
+            let guard = epoch::pin();
+            assert_eq!(s.iter(&guard).cloned().collect::<Vec<_>>(), [1, 3]);
+        }
+
+        // the search done by `remove` unlinks it
+        assert!(!s.remove(&2));
+        assert_eq!(linked(&s), 2);
+
+        // so does the one done by `insert`
+        tag(&s, &3);
+        assert_eq!(linked(&s), 2);
+        assert!(s.insert(4));
+        assert_eq!(linked(&s), 2);
+        assert!(s.insert(2));
+        let guard = epoch::pin();
+        assert_eq!(s.iter(&guard).cloned().collect::<Vec<_>>(), [1, 2, 4]);
+    }
+
+    #[test]
+    fn drop_frees_tagged_nodes() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        #[derive(PartialEq, Eq, PartialOrd, Ord)]
+        struct Elem(usize);
+        impl Drop for Elem {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, SeqCst);
+            }
+        }
+
+        let s = ListSet::new();
+        for i in 0..10 {
+            s.insert(Elem(i));
+        }
+        for i in 0..5 {
+            tag(&s, &Elem(i * 2));
+        }
+        // the probes above were dropped too
+        assert_eq!(DROPS.load(SeqCst), 5);
+        assert_eq!(linked(&s), 10);
+        drop(s);
+        assert_eq!(DROPS.load(SeqCst), 15);
+    }
+
+    #[test]
+    fn insert_next_to_removed() {
+        const ROUNDS: usize = 200;
+        const COUNT: usize = 64;
+        let s = ListSet::new();
+
+        // An insert whose predecessor gets removed under it must retry rather
+        // than link its node behind a deleted one, where it would be lost.
+        for _ in 0..ROUNDS {
+            for i in 0..COUNT {
+                s.insert(2 * i);
+            }
+            scope(|scope| {
+                let s = &s;
+                scope.spawn(move || {
+                    for i in 0..COUNT {
+                        assert!(s.remove(&(2 * i)));
+                    }
+                });
+                scope.spawn(move || {
+                    for i in 0..COUNT {
+                        assert!(s.insert(2 * i + 1));
+                    }
+                });
+            });
+
+            let guard = epoch::pin();
+            assert_eq!(s.iter(&guard).cloned().collect::<Vec<_>>(),
+                       (0..COUNT).map(|i| 2 * i + 1).collect::<Vec<_>>());
+            for i in 0..COUNT {
+                assert!(s.remove(&(2 * i + 1)));
+            }
+            assert!(s.is_empty());
+        }
+    }
+
+    #[test]
+    fn concurrent_same_elements() {
+        const THREADS: usize = 4;
+        const COUNT: usize = 200;
+        let s = ListSet::new();
+        let inserted = AtomicUsize::new(0);
+        let removed = AtomicUsize::new(0);
+
+        scope(|scope| {
+            for _ in 0..THREADS {
+                let s = &s;
+                let inserted = &inserted;
+                let removed = &removed;
+                scope.spawn(move || {
+                    for round in 0..10 {
+                        for i in 0..COUNT {
+                            if s.insert(i) {
+                                inserted.fetch_add(1, SeqCst);
+                            }
+                        }
+                        for i in 0..COUNT {
+                            if (i + round) % 3 == 0 && s.remove(&i) {
+                                removed.fetch_add(1, SeqCst);
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        let guard = epoch::pin();
+        let left = s.iter(&guard).count();
+        assert_eq!(inserted.load(SeqCst) - removed.load(SeqCst), left);
+        let v = s.iter(&guard).cloned().collect::<Vec<_>>();
+        assert!(v.windows(2).all(|w| w[0] < w[1]));
+    }
+}
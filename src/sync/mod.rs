@@ -2,13 +2,25 @@
 
 pub use self::ms_queue::MsQueue;
 pub use self::atomic_option::AtomicOption;
-pub use self::treiber_stack::TreiberStack;
+pub use self::treiber_stack::{TreiberStack, PopAll};
+pub use self::hazard_treiber_stack::HazardTreiberStack;
+pub use self::elimination_stack::EliminationStack;
 pub use self::seg_queue::SegQueue;
 pub use self::arc_cell::ArcCell;
+pub use self::array_queue::ArrayQueue;
+pub use self::skip_map::{SkipMap, Entry};
+pub use self::hash_map::HashMap;
+pub use self::list_set::ListSet;
 
 mod atomic_option;
-mod ms_queue;
+pub(crate) mod ms_queue;
 mod treiber_stack;
+mod hazard_treiber_stack;
+mod elimination_stack;
 mod seg_queue;
 pub mod chase_lev;
 mod arc_cell;
+mod array_queue;
+mod skip_map;
+mod hash_map;
+mod list_set;
@@ -1,8 +1,10 @@
-use std::sync::atomic::Ordering::{Acquire, Release, Relaxed};
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::{Acquire, Release, Relaxed, AcqRel};
+use std::sync::atomic::{AtomicBool, AtomicUsize};
 use std::{ptr, mem};
 use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
 
+use channel::context::Context;
 use mem::epoch::{self, Atomic, Owned, Shared};
 use mem::CachePadded;
 
@@ -34,6 +36,15 @@ enum Payload<T> {
 }
 
 /// A blocked request for data, which includes a slot to write the data.
+///
+/// Signals live on the heap, because a request that timed out may still be in
+/// the queue after its thread has moved on. Whoever dequeues the request first
+/// races the blocked thread on `state`: if the pusher claims it, the blocked
+/// thread frees the signal once the data is in; if the blocked thread cancels
+/// it, the signal belongs to the queue and is freed by whoever dequeues it.
+///
+/// Either way the signal is freed through the epoch, so that a thread which
+/// found the request in the queue may still read its `state`.
 #[derive(Debug)]
 struct Signal<T> {
     /// Thread to unpark when data is ready.
@@ -42,8 +53,31 @@ struct Signal<T> {
     data: Option<T>,
     /// Is the data ready? Needed to cope with spurious wakeups.
     ready: AtomicBool,
+    /// `WAITING`, `CLAIMED` or `CANCELLED`.
+    state: AtomicUsize,
+    /// For a request made by a `Select`, its context and the id of the pop
+    /// operation. A push claiming the request only fills it in if it manages
+    /// to select that operation; otherwise it sets `ready` without data.
+    select: Option<(Context, usize)>,
+}
+
+/// A pop request enqueued on behalf of a `Select`.
+///
+/// Exactly one of `wait` and `cancel` must be called, depending on whether
+/// the operation was selected.
+#[derive(Debug)]
+pub(crate) struct PopRequest<'a, T: 'a> {
+    queue: &'a MsQueue<T>,
+    signal: *mut Signal<T>,
 }
 
+/// The request is still waiting for data.
+const WAITING: usize = 0;
+/// A push is handing data to the request.
+const CLAIMED: usize = 1;
+/// The blocked thread timed out and gave up on the request.
+const CANCELLED: usize = 2;
+
 impl<T> Node<T> {
     fn is_data(&self) -> bool {
         if let Payload::Data(_) = self.payload { true } else { false }
@@ -172,12 +206,28 @@ impl<T> MsQueue<T> {
                     // race to dequeue the node
                     if self.head.cas_shared(Some(head), Some(blocked_node), Release) {
                         unsafe {
-                            // signal the thread
-                            (*signal).data = Some(cache.into_data());
-                            (*signal).ready.store(true, Release);
-                            (*signal).thread.unpark();
                             guard.unlinked(head);
-                            return;
+                            if (*signal).state.compare_exchange(WAITING, CLAIMED, AcqRel, Acquire).is_ok() {
+                                if let Some((ref cx, oper)) = (*signal).select {
+                                    if cx.try_select(oper).is_err() {
+                                        // The select completed something else
+                                        // and is cancelling the request; let
+                                        // it free the signal, and move on.
+                                        (*signal).ready.store(true, Release);
+                                        continue;
+                                    }
+                                }
+                                // signal the thread; once `ready` is set the
+                                // signal may be gone, so take the thread first
+                                let thread = (*signal).thread.clone();
+                                (*signal).data = Some(cache.into_data());
+                                (*signal).ready.store(true, Release);
+                                thread.unpark();
+                                return;
+                            }
+                            // the request was cancelled, and we just made it
+                            // unreachable; free it and try again
+                            guard.defer_drop_raw(signal);
                         }
                     }
                 }
@@ -239,6 +289,26 @@ impl<T> MsQueue<T> {
     /// Dequeue an element from the front of the queue, blocking if the queue is
     /// empty.
     pub fn pop(&self) -> T {
+        self.pop_until(None).unwrap()
+    }
+
+    /// Dequeue an element from the front of the queue, blocking for at most
+    /// `timeout` if the queue is empty.
+    ///
+    /// Returns `None` if no element arrived in time.
+    pub fn pop_timeout(&self, timeout: Duration) -> Option<T> {
+        self.pop_until(Some(Instant::now() + timeout))
+    }
+
+    /// Dequeue an element from the front of the queue, blocking until
+    /// `deadline` at the latest if the queue is empty.
+    ///
+    /// Returns `None` if no element arrived in time.
+    pub fn pop_deadline(&self, deadline: Instant) -> Option<T> {
+        self.pop_until(Some(deadline))
+    }
+
+    fn pop_until(&self, deadline: Option<Instant>) -> Option<T> {
         let guard = epoch::pin();
 
         // Fast path: keep retrying until we observe that the queue has no data,
@@ -246,7 +316,7 @@ impl<T> MsQueue<T> {
         loop {
             match self.pop_internal(&guard) {
                 Ok(Some(r)) => {
-                    return r;
+                    return Some(r);
                 }
                 Ok(None) => {
                     break;
@@ -255,36 +325,63 @@ impl<T> MsQueue<T> {
             }
         }
 
-        // The signal gets to live on the stack, since this stack frame will be
-        // blocked until receiving the signal.
-        let mut signal = Signal {
+        if let Some(deadline) = deadline {
+            if Instant::now() >= deadline {
+                return None;
+            }
+        }
+
+        let signal = Box::into_raw(Box::new(Signal {
             thread: thread::current(),
             data: None,
             ready: AtomicBool::new(false),
-        };
+            state: AtomicUsize::new(WAITING),
+            select: None,
+        }));
 
         // Go ahead and allocate the blocked node; chances are, we'll need it.
         let mut node = Owned::new(Node {
-            payload: Payload::Blocked(&mut signal),
+            payload: Payload::Blocked(signal),
             next: Atomic::null(),
         });
 
         loop {
             // try a normal pop
             if let Ok(Some(r)) = self.pop_internal(&guard) {
-                return r;
+                // the request never made it into the queue
+                unsafe { drop(Box::from_raw(signal)); }
+                return Some(r);
             }
 
-            // At this point, we believe the queue is empty/blocked.
+            match self.push_request(&guard, node) {
+                Ok(()) => {
+                    unsafe { return self.wait_signal(signal, deadline, &guard); }
+                }
+                Err(n) => {
+                    node = n;
+                }
+            }
+        }
+    }
+
+    /// Enqueue the blocked node `node`, unless the queue turns out to be in
+    /// data mode, in which case `node` is handed back.
+    fn push_request(&self, guard: &epoch::Guard, mut node: Owned<Node<T>>)
+                    -> Result<(), Owned<Node<T>>>
+    {
+        // Requests given up while no push came by would pile up behind this one.
+        self.skip_cancelled(guard);
+
+        loop {
             // Snapshot the tail, onto which we want to push a blocked node.
-            let tail = self.tail.load(Relaxed, &guard).unwrap();
+            let tail = self.tail.load(Relaxed, guard).unwrap();
 
             // Double-check that we're in blocking mode
             if tail.is_data() {
                 // The current tail is in data mode, so we probably need to abort.
                 // BUT, it might be the sentinel, so check for that first.
-                let head = self.head.load(Relaxed, &guard).unwrap();
-                if tail.is_data() && tail.as_raw() != head.as_raw() { continue; }
+                let head = self.head.load(Relaxed, guard).unwrap();
+                if tail.is_data() && tail.as_raw() != head.as_raw() { return Err(node); }
             }
 
             // At this point, the tail snapshot is either a blocked node deep in
@@ -293,18 +390,177 @@ impl<T> MsQueue<T> {
             // snapshot, we know we are maintaining the core invariant: all
             // reachable, non-sentinel nodes have the same payload mode, in this
             // case, blocked.
-            match self.push_internal(&guard, tail, node) {
-                Ok(()) => {
-                    while !signal.ready.load(Acquire) {
-                        thread::park();
+            match self.push_internal(guard, tail, node) {
+                Ok(()) => return Ok(()),
+                Err(n) => node = n,
+            }
+        }
+    }
+
+    /// Enqueue a pop request on behalf of the `Select` owning `cx`, to be
+    /// filled in by the push that manages to select `oper`.
+    ///
+    /// Returns `None` if the queue holds data, so that the caller should
+    /// retry `try_pop` instead.
+    pub(crate) fn register_pop<'a>(&'a self, oper: usize, cx: &Context) -> Option<PopRequest<'a, T>> {
+        let guard = epoch::pin();
+        let signal = Box::into_raw(Box::new(Signal {
+            thread: thread::current(),
+            data: None,
+            ready: AtomicBool::new(false),
+            state: AtomicUsize::new(WAITING),
+            select: Some((cx.clone(), oper)),
+        }));
+        let node = Owned::new(Node {
+            payload: Payload::Blocked(signal),
+            next: Atomic::null(),
+        });
+
+        match self.push_request(&guard, node) {
+            Ok(()) => {
+                Some(PopRequest {
+                    queue: self,
+                    signal: signal,
+                })
+            }
+            Err(_) => {
+                unsafe { drop(Box::from_raw(signal)); }
+                None
+            }
+        }
+    }
+
+    /// Number of nodes past the sentinel, data or requests.
+    #[cfg(test)]
+    pub(crate) fn nodes(&self) -> usize {
+        let guard = epoch::pin();
+        let mut n = 0;
+        let mut cur = self.head.load(Acquire, &guard).unwrap().next.load(Acquire, &guard);
+        while let Some(node) = cur {
+            n += 1;
+            cur = node.next.load(Acquire, &guard);
+        }
+        n
+    }
+
+    /// Dequeue the cancelled requests at the front of the queue, if any.
+    fn skip_cancelled(&self, guard: &epoch::Guard) {
+        loop {
+            let head = self.head.load(Acquire, guard).unwrap();
+            let (next, signal) = match head.next.load(Acquire, guard) {
+                Some(next) => {
+                    match next.payload {
+                        Payload::Blocked(signal) => (next, signal),
+                        Payload::Data(_) => return,
                     }
-                    return signal.data.unwrap();
                 }
-                Err(n) => {
-                    node = n;
+                None => return,
+            };
+            unsafe {
+                // Even if the request was dequeued meanwhile, its signal is
+                // only freed once we unpin. A cancelled request stays so.
+                if (*signal).state.load(Acquire) != CANCELLED {
+                    return;
+                }
+                if self.head.cas_shared(Some(head), Some(next), Release) {
+                    guard.unlinked(head);
+                    guard.defer_drop_raw(signal);
+                }
+            }
+        }
+    }
+
+    /// Wait for a push to fill in the enqueued request `signal`, giving up at
+    /// `deadline`.
+    unsafe fn wait_signal(&self, signal: *mut Signal<T>, deadline: Option<Instant>,
+                          guard: &epoch::Guard) -> Option<T> {
+        while !(*signal).ready.load(Acquire) {
+            match deadline {
+                None => thread::park(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now < deadline {
+                        thread::park_timeout(deadline - now);
+                    } else if (*signal).state.compare_exchange(WAITING, CANCELLED, AcqRel, Acquire).is_ok() {
+                        // Take the request out if it is at the front; otherwise
+                        // whoever dequeues it frees it.
+                        self.skip_cancelled(guard);
+                        return None;
+                    } else {
+                        // Too late: a push has claimed the request, and the
+                        // data is on its way.
+                        while !(*signal).ready.load(Acquire) {
+                            thread::yield_now();
+                        }
+                    }
                 }
             }
         }
+
+        let data = (*signal).data.take();
+        guard.defer_drop_raw(signal);
+        data
+    }
+}
+
+impl<'a, T> PopRequest<'a, T> {
+    /// Take the data of a request whose operation was selected.
+    pub fn wait(self) -> T {
+        let guard = epoch::pin();
+        unsafe {
+            // the push selected us before filling in the data
+            while !(*self.signal).ready.load(Acquire) {
+                thread::yield_now();
+            }
+            let data = (*self.signal).data.take();
+            guard.defer_drop_raw(self.signal);
+            data.unwrap()
+        }
+    }
+
+    /// Withdraw a request whose operation was not selected, taking it out of
+    /// the queue if it is at the front.
+    pub fn cancel(self) {
+        let guard = epoch::pin();
+        unsafe {
+            if (*self.signal).state.compare_exchange(WAITING, CANCELLED, AcqRel, Acquire).is_ok() {
+                self.queue.skip_cancelled(&guard);
+            } else {
+                // A push claimed the request, but failed to select it; wait
+                // until it is done with the signal.
+                while !(*self.signal).ready.load(Acquire) {
+                    thread::yield_now();
+                }
+                guard.defer_drop_raw(self.signal);
+            }
+        }
+    }
+}
+
+impl<T> Drop for MsQueue<T> {
+    fn drop(&mut self) {
+        // We have exclusive access, so no other thread can be looking at the
+        // nodes still in the queue, and no thread can be blocked in `pop`:
+        // every node past the sentinel holds either data or a cancelled
+        // request.
+        let guard = epoch::pin();
+        let sentinel = self.head.swap(None, Relaxed, &guard).unwrap();
+        self.tail.store(None, Relaxed);
+        unsafe {
+            let mut cur = sentinel.next.load(Relaxed, &guard);
+
+            // the sentinel's payload has already been moved out (or was never
+            // initialized), so only free its memory
+            drop(Vec::from_raw_parts(sentinel.as_raw(), 0, 1));
+
+            while let Some(node) = cur {
+                cur = node.next.load(Relaxed, &guard);
+                if let Payload::Blocked(signal) = node.payload {
+                    drop(Box::from_raw(signal));
+                }
+                drop(Box::from_raw(node.as_raw()));
+            }
+        }
     }
 }
 
@@ -312,6 +568,11 @@ impl<T> MsQueue<T> {
 mod test {
     const CONC_COUNT: i64 = 1000000;
 
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
     use scope;
     use super::*;
 
@@ -508,4 +769,164 @@ mod test {
         assert!(!q.is_empty());
         assert!(q.try_pop().is_some());
     }
+
+    #[test]
+    fn drop_remaining() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        struct Elem;
+        impl Drop for Elem {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, SeqCst);
+            }
+        }
+
+        let q = MsQueue::new();
+        for _ in 0..100 {
+            q.push(Elem);
+        }
+        for _ in 0..40 {
+            drop(q.try_pop().unwrap());
+        }
+        assert_eq!(DROPS.load(SeqCst), 40);
+        drop(q);
+        assert_eq!(DROPS.load(SeqCst), 100);
+    }
+
+    #[test]
+    fn drop_empty() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        struct Elem;
+        impl Drop for Elem {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, SeqCst);
+            }
+        }
+
+        let q = MsQueue::new();
+        q.push(Elem);
+        drop(q.pop());
+        drop(q);
+        assert_eq!(DROPS.load(SeqCst), 1);
+    }
+
+    #[test]
+    fn pop_timeout_empty() {
+        let q: MsQueue<i64> = MsQueue::new();
+        let start = Instant::now();
+        assert_eq!(q.pop_timeout(Duration::from_millis(50)), None);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(q.pop_deadline(Instant::now()), None);
+
+        // the cancelled requests are skipped by later pushes
+        q.push(37);
+        assert_eq!(q.pop_timeout(Duration::from_millis(50)), Some(37));
+        q.push(48);
+        assert_eq!(q.pop_deadline(Instant::now()), Some(48));
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn pop_timeout_wakes_on_push() {
+        let q: MsQueue<i64> = MsQueue::new();
+
+        scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                q.push(37);
+            });
+            assert_eq!(q.pop_timeout(Duration::from_secs(10)), Some(37));
+        });
+    }
+
+    #[test]
+    fn pop_timeout_race_with_push() {
+        const COUNT: usize = 20000;
+        const THREADS: usize = 4;
+
+        let q: MsQueue<usize> = MsQueue::new();
+        let popped = AtomicUsize::new(0);
+        let sum = AtomicUsize::new(0);
+
+        scope(|scope| {
+            for t in 0..THREADS {
+                let q = &q;
+                let popped = &popped;
+                let sum = &sum;
+                scope.spawn(move || {
+                    // timeouts short enough to expire all the time, so that
+                    // pushes keep running into cancelled requests
+                    let timeout = Duration::new(0, 1000 * t as u32);
+                    while popped.load(SeqCst) < COUNT {
+                        if let Some(x) = q.pop_timeout(timeout) {
+                            sum.fetch_add(x, SeqCst);
+                            popped.fetch_add(1, SeqCst);
+                        }
+                    }
+                });
+            }
+
+            for i in 0..COUNT {
+                q.push(i);
+                if i % 64 == 0 {
+                    // let some requests time out in the queue
+                    thread::sleep(Duration::new(0, 10000));
+                }
+            }
+        });
+
+        assert_eq!(sum.load(SeqCst), COUNT * (COUNT - 1) / 2);
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn repeated_timeouts_dont_grow() {
+        let q: MsQueue<i32> = MsQueue::new();
+        for _ in 0..100 {
+            assert!(q.pop_timeout(Duration::from_millis(1)).is_none());
+        }
+        assert_eq!(q.nodes(), 0);
+    }
+
+    #[test]
+    fn timeouts_out_of_order() {
+        let q: MsQueue<i32> = MsQueue::new();
+        scope(|scope| {
+            // the second request gives up first, while still behind the first
+            scope.spawn(|| assert!(q.pop_timeout(Duration::from_millis(300)).is_none()));
+            thread::sleep(Duration::from_millis(20));
+            scope.spawn(|| assert!(q.pop_timeout(Duration::from_millis(20)).is_none()));
+            thread::sleep(Duration::from_millis(50));
+            assert_eq!(q.nodes(), 2);
+        });
+        // the first one took both out on its way
+        assert_eq!(q.nodes(), 0);
+
+        q.push(1);
+        assert_eq!(q.try_pop(), Some(1));
+    }
+
+    #[test]
+    fn drop_with_cancelled_requests() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        struct Elem;
+        impl Drop for Elem {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, SeqCst);
+            }
+        }
+
+        let q: MsQueue<Elem> = MsQueue::new();
+        for _ in 0..3 {
+            assert!(q.pop_timeout(Duration::from_millis(1)).is_none());
+        }
+        drop(q);
+
+        let q = MsQueue::new();
+        assert!(q.pop_timeout(Duration::from_millis(1)).is_none());
+        q.push(Elem);
+        q.push(Elem);
+        drop(q.pop());
+        drop(q);
+        assert_eq!(DROPS.load(SeqCst), 2);
+    }
 }
@@ -1,22 +1,37 @@
-use std::sync::atomic::Ordering::{Acquire, Release, Relaxed};
-use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::atomic::Ordering::{Acquire, Release, Relaxed, SeqCst};
+use std::sync::atomic::{self, AtomicBool, AtomicUsize};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
 use std::fmt;
 use std::{ptr, mem};
 use std::cmp;
 use std::cell::UnsafeCell;
 
+use channel::context::Context;
+use channel::waker::Waker;
 use mem::epoch::{self, Atomic, Owned};
+use mem::CachePadded;
 
 const SEG_SIZE: usize = 32;
 
 /// A Michael-Scott queue that allocates "segments" (arrays of nodes)
-/// for efficiency.
+/// for efficiency, with support for blocking `pop`s.
 ///
 /// Usable with any number of producers and consumers.
+// Blocked consumers wait on `ready`, and `Select`s on `selectors`, after
+// announcing themselves in `waiters`. A push only reads `waiters`, on its own cache line, so as long as
+// nobody is blocked the fast paths touch no extra shared state.
 #[derive(Debug)]
 pub struct SegQueue<T> {
     head: Atomic<Segment<T>>,
     tail: Atomic<Segment<T>>,
+
+    /// Number of consumers blocked (or about to block) in `pop` or in a
+    /// `Select`.
+    waiters: CachePadded<AtomicUsize>,
+    lock: Mutex<()>,
+    ready: Condvar,
+    selectors: Waker,
 }
 
 struct Segment<T> {
@@ -57,6 +72,10 @@ impl<T> SegQueue<T> {
         let q = SegQueue {
             head: Atomic::null(),
             tail: Atomic::null(),
+            waiters: CachePadded::zeroed(),
+            lock: Mutex::new(()),
+            ready: Condvar::new(),
+            selectors: Waker::new(),
         };
         let sentinel = Owned::new(Segment::new());
         let guard = epoch::pin();
@@ -65,13 +84,29 @@ impl<T> SegQueue<T> {
         q
     }
 
-    /// Add `t` to the back of the queue.
+    /// Add `t` to the back of the queue, possibly waking up a thread blocked
+    /// on `pop`.
     pub fn push(&self, t: T) {
+        self.push_internal(t);
+
+        // The `SeqCst` increment of `high` in `push_internal` pairs with the
+        // fence in `pop_until`: either the consumer's retry sees the new
+        // element, or we see the consumer.
+        if self.waiters.load(SeqCst) > 0 {
+            {
+                let _lock = self.lock.lock().unwrap();
+                self.ready.notify_one();
+            }
+            self.selectors.notify();
+        }
+    }
+
+    fn push_internal(&self, t: T) {
         let guard = epoch::pin();
         loop {
             let tail = self.tail.load(Acquire, &guard).unwrap();
             if tail.high.load(Relaxed) >= SEG_SIZE { continue }
-            let i = tail.high.fetch_add(1, Relaxed);
+            let i = tail.high.fetch_add(1, SeqCst);
             unsafe {
                 if i < SEG_SIZE {
                     let cell = (*tail).data.get_unchecked(i).get();
@@ -89,6 +124,14 @@ impl<T> SegQueue<T> {
         }
     }
 
+    /// Check if this queue is empty.
+    pub fn is_empty(&self) -> bool {
+        let guard = epoch::pin();
+        let head = self.head.load(Acquire, &guard).unwrap();
+        head.low.load(Relaxed) >= cmp::min(head.high.load(Relaxed), SEG_SIZE) &&
+            head.next.load(Relaxed, &guard).is_none()
+    }
+
     /// Attempt to dequeue from the front.
     ///
     /// Returns `None` if the queue is observed to be empty.
@@ -99,7 +142,7 @@ impl<T> SegQueue<T> {
             loop {
                 let low = head.low.load(Relaxed);
                 if low >= cmp::min(head.high.load(Relaxed), SEG_SIZE) { break }
-                if head.low.compare_and_swap(low, low+1, Relaxed) == low {
+                if head.low.compare_exchange(low, low+1, Relaxed, Relaxed).is_ok() {
                     unsafe {
                         let cell = (*head).data.get_unchecked(low).get();
                         loop {
@@ -121,12 +164,169 @@ impl<T> SegQueue<T> {
             if head.next.load(Relaxed, &guard).is_none() { return None }
         }
     }
+
+    /// Attempt to dequeue up to `max` elements from the front at once, for
+    /// `chase_lev::Injector`.
+    ///
+    /// The batch is claimed with a single CAS, and is made of whatever is
+    /// available in the head segment, so it holds at most `SEG_SIZE` elements.
+    /// The first element is returned and the rest are handed to `rest`, in
+    /// order. `Ok(None)` if the queue is observed to be empty; `Err(())` if
+    /// lost a race to pop.
+    pub(crate) fn try_pop_batch<F: FnMut(T)>(&self, max: usize, mut rest: F) -> Result<Option<T>, ()> {
+        let guard = epoch::pin();
+        let head = self.head.load(Acquire, &guard).unwrap();
+        let low = head.low.load(Relaxed);
+        let high = cmp::min(head.high.load(Relaxed), SEG_SIZE);
+        if low >= high {
+            // either the queue is empty, or whoever claimed the last slot of
+            // this segment is about to move the head along.
+            return if low == SEG_SIZE || head.next.load(Relaxed, &guard).is_some() {
+                Err(())
+            } else {
+                Ok(None)
+            }
+        }
+        let n = cmp::min(high - low, max);
+        if head.low.compare_exchange(low, low + n, Relaxed, Relaxed).is_err() {
+            return Err(())
+        }
+
+        let mut first = None;
+        for i in low..low + n {
+            let t = unsafe {
+                let cell = (*head).data.get_unchecked(i).get();
+                loop {
+                    if (*cell).1.load(Acquire) { break }
+                }
+                ptr::read(&(*cell).0)
+            };
+            if first.is_none() {
+                first = Some(t);
+            } else {
+                rest(t);
+            }
+        }
+
+        if low + n == SEG_SIZE {
+            loop {
+                if let Some(next) = head.next.load(Acquire, &guard) {
+                    self.head.store_shared(Some(next), Release);
+                    unsafe { guard.unlinked(head); }
+                    break
+                }
+            }
+        }
+        Ok(first)
+    }
+
+    /// Dequeue an element from the front of the queue, blocking if the queue is
+    /// empty.
+    pub fn pop(&self) -> T {
+        self.pop_until(None).unwrap()
+    }
+
+    /// Dequeue an element from the front of the queue, blocking for at most
+    /// `timeout` if the queue is empty.
+    ///
+    /// Returns `None` if no element arrived in time.
+    pub fn pop_timeout(&self, timeout: Duration) -> Option<T> {
+        self.pop_until(Some(Instant::now() + timeout))
+    }
+
+    fn pop_until(&self, deadline: Option<Instant>) -> Option<T> {
+        if let Some(t) = self.try_pop() {
+            return Some(t);
+        }
+
+        self.waiters.fetch_add(1, SeqCst);
+        atomic::fence(SeqCst);
+        let res = self.wait_pop(deadline);
+        self.waiters.fetch_sub(1, Relaxed);
+        res
+    }
+
+    /// Register the `Select` owning `cx` to be woken by the next push, under
+    /// `oper`. Returns whether the queue may already hold data.
+    ///
+    /// Must be followed by `unregister_pop` if the operation is not selected,
+    /// and by `accept_pop` if it is.
+    pub(crate) fn register_pop(&self, oper: usize, cx: &Context) -> bool {
+        self.waiters.fetch_add(1, SeqCst);
+        self.selectors.register(oper, cx);
+        !self.is_empty()
+    }
+
+    /// Undo `register_pop` for an operation that was not selected.
+    pub(crate) fn unregister_pop(&self, oper: usize, cx: &Context) {
+        self.selectors.unregister(oper, cx);
+        self.waiters.fetch_sub(1, Relaxed);
+    }
+
+    /// Finish a selected pop operation; the element still has to be popped,
+    /// and may have been taken by another thread meanwhile.
+    pub(crate) fn accept_pop(&self) -> Option<T> {
+        self.waiters.fetch_sub(1, Relaxed);
+        self.try_pop()
+    }
+
+    /// Pop an element, waiting on `ready` for as long as the queue is empty.
+    /// The caller must be counted in `waiters`.
+    fn wait_pop(&self, deadline: Option<Instant>) -> Option<T> {
+        // Retrying under the lock means a push can't slip its notification in
+        // between the retry and the wait.
+        let mut lock = self.lock.lock().unwrap();
+        loop {
+            if let Some(t) = self.try_pop() {
+                return Some(t);
+            }
+            match deadline {
+                None => lock = self.ready.wait(lock).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return None;
+                    }
+                    lock = self.ready.wait_timeout(lock, deadline - now).unwrap().0;
+                }
+            }
+        }
+    }
+}
+
+impl<T> Drop for SegQueue<T> {
+    fn drop(&mut self) {
+        // We have exclusive access, so all pushes have completed and no other
+        // thread can be looking at the segments still in the queue.
+        let guard = epoch::pin();
+        let mut cur = self.head.swap(None, Relaxed, &guard);
+        self.tail.store(None, Relaxed);
+        while let Some(seg) = cur {
+            cur = seg.next.load(Relaxed, &guard);
+            unsafe {
+                let low = seg.low.load(Relaxed);
+                let high = cmp::min(seg.high.load(Relaxed), SEG_SIZE);
+                for i in low..high {
+                    ptr::drop_in_place(&mut (*seg.data[i].get()).0);
+                }
+                // the remaining slots are either moved out or uninitialized,
+                // so only free the segment's memory
+                drop(Vec::from_raw_parts(seg.as_raw(), 0, 1));
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
     const CONC_COUNT: i64 = 1000000;
 
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::SeqCst;
+
+    use std::thread;
+    use std::time::{Duration, Instant};
+
     use scope;
     use super::*;
 
@@ -157,6 +357,21 @@ mod test {
         }
     }
 
+    #[test]
+    fn is_empty_across_segments() {
+        let q: SegQueue<i64> = SegQueue::new();
+        assert!(q.is_empty());
+        for i in 0..100 {
+            q.push(i);
+            assert!(!q.is_empty());
+        }
+        for _ in 0..100 {
+            assert!(!q.is_empty());
+            q.try_pop().unwrap();
+        }
+        assert!(q.is_empty());
+    }
+
     #[test]
     fn push_pop_many_spsc() {
         let q: SegQueue<i64> = SegQueue::new();
@@ -248,4 +463,89 @@ mod test {
             }
         });
     }
+
+    #[test]
+    fn drop_remaining() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        struct Elem;
+        impl Drop for Elem {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, SeqCst);
+            }
+        }
+
+        // spans several segments, with a partially popped head segment
+        let q = SegQueue::new();
+        for _ in 0..100 {
+            q.push(Elem);
+        }
+        for _ in 0..40 {
+            drop(q.try_pop().unwrap());
+        }
+        assert_eq!(DROPS.load(SeqCst), 40);
+        drop(q);
+        assert_eq!(DROPS.load(SeqCst), 100);
+    }
+
+    #[test]
+    fn push_blocking_pop_1() {
+        let q: SegQueue<i64> = SegQueue::new();
+        q.push(37);
+        assert_eq!(q.pop(), 37);
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn pop_timeout_empty() {
+        let q: SegQueue<i64> = SegQueue::new();
+        let start = Instant::now();
+        assert_eq!(q.pop_timeout(Duration::from_millis(50)), None);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        q.push(37);
+        assert_eq!(q.pop_timeout(Duration::from_millis(50)), Some(37));
+    }
+
+    #[test]
+    fn pop_wakes_on_push() {
+        let q: SegQueue<i64> = SegQueue::new();
+
+        scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                q.push(37);
+                thread::sleep(Duration::from_millis(50));
+                q.push(48);
+            });
+            assert_eq!(q.pop(), 37);
+            assert_eq!(q.pop_timeout(Duration::from_secs(10)), Some(48));
+        });
+    }
+
+    #[test]
+    fn push_blocking_pop_many_mpmc() {
+        const THREADS: usize = 4;
+        const COUNT: usize = 20000;
+
+        let q: SegQueue<usize> = SegQueue::new();
+        let sum = AtomicUsize::new(0);
+
+        scope(|scope| {
+            for _ in 0..THREADS {
+                scope.spawn(|| {
+                    for i in 0..COUNT {
+                        q.push(i);
+                    }
+                });
+                scope.spawn(|| {
+                    for _ in 0..COUNT {
+                        sum.fetch_add(q.pop(), SeqCst);
+                    }
+                });
+            }
+        });
+
+        assert_eq!(sum.load(SeqCst), THREADS * COUNT * (COUNT - 1) / 2);
+        assert!(q.is_empty());
+        assert_eq!(q.waiters.load(SeqCst), 0);
+    }
 }
@@ -0,0 +1,614 @@
+use std::borrow::Borrow;
+use std::collections::Bound;
+use std::fmt;
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::atomic::Ordering::{Acquire, AcqRel, Release, Relaxed, SeqCst};
+
+use mem::epoch::{self, Atomic, Guard, Owned, Shared};
+use util::random_u32;
+
+// Maximum height of a tower, good for maps with around a million entries.
+const MAX_HEIGHT: usize = 20;
+
+/// A lock-free ordered map, based on a skiplist.
+///
+/// Lookups and iteration happen under a `Guard`, and return `Entry` handles
+/// which remain valid for as long as the guard does, even if the entry is
+/// removed from the map meanwhile.
+///
+/// Removed entries are dropped by the epoch garbage collector, possibly on
+/// another thread and after the map itself is gone, hence the `Send + 'static`
+/// bounds on keys and values.
+///
+/// Usable with any number of concurrent readers and writers.
+pub struct SkipMap<K, V> {
+    head: Box<[Atomic<Node<K, V>>]>,
+}
+
+// A node is removed by tagging all the pointers in its tower, top to bottom;
+// tagging the bottom one is what takes the entry out of the map. Searches
+// unlink tagged nodes they come across.
+struct Node<K, V> {
+    key: K,
+    value: V,
+
+    // Number of levels the node is linked into, plus one while `insert` is
+    // still building the tower. Whoever drops it to zero frees the node.
+    refs: AtomicUsize,
+
+    tower: Box<[Atomic<Node<K, V>>]>,
+}
+
+/// An entry of a `SkipMap`, valid for as long as the guard it was obtained
+/// with.
+pub struct Entry<'a, K: 'a, V: 'a> {
+    node: &'a Node<K, V>,
+}
+
+/// Iterator over the entries of a `SkipMap`, in ascending key order.
+pub struct Iter<'a, K: 'a, V: 'a> {
+    guard: &'a Guard,
+    next: Option<Shared<'a, Node<K, V>>>,
+}
+
+/// Iterator over a range of entries of a `SkipMap`, in ascending key order.
+pub struct Range<'a, Q: ?Sized + 'a, K: 'a, V: 'a> {
+    iter: Iter<'a, K, V>,
+    upper: Bound<&'a Q>,
+}
+
+// Where a key fits at every level: between `preds[i]` (`None` standing for the
+// head) and `succs[i]`.
+struct Position<'a, K: 'a, V: 'a> {
+    preds: [Option<Shared<'a, Node<K, V>>>; MAX_HEIGHT],
+    succs: [Option<Shared<'a, Node<K, V>>>; MAX_HEIGHT],
+}
+
+impl<K, V> SkipMap<K, V>
+    where K: Ord + Send + 'static,
+          V: Send + 'static
+{
+    /// Create a new, empty map.
+    pub fn new() -> SkipMap<K, V> {
+        SkipMap { head: tower(MAX_HEIGHT) }
+    }
+
+    /// Insert `value` under `key`, replacing any existing entry.
+    pub fn insert(&self, key: K, value: V) {
+        let guard = epoch::pin();
+        let height = random_height();
+        let node = Owned::new(Node {
+            key: key,
+            value: value,
+            refs: AtomicUsize::new(1),
+            tower: tower(height),
+        }).into_shared(&guard);
+
+        let mut pos;
+        loop {
+            pos = self.search(|k| k < &node.key, &guard);
+            match pos.succs[0] {
+                Some(old) if old.key == node.key => {
+                    if self.replace_node(old, node, &guard) {
+                        // as in `remove_node`; the search also unlinks the
+                        // old entry at every level before we build above it
+                        atomic::fence(SeqCst);
+                        pos = self.search(|k| k < &node.key, &guard);
+                        break;
+                    }
+                }
+                succ => {
+                    node.tower[0].store_shared(succ, Relaxed);
+                    node.refs.fetch_add(1, Relaxed);
+                    if self.tower(pos.preds[0])[0].cas_shared(succ, Some(node), SeqCst) {
+                        break;
+                    }
+                    node.refs.fetch_sub(1, Relaxed);
+                }
+            }
+        }
+
+        // The entry is in; build the rest of the tower, unless it gets
+        // removed meanwhile.
+        'build: for level in 1..height {
+            loop {
+                let succ = pos.succs[level];
+                let (next, tag) = node.tower[level].load_tagged(Relaxed, &guard);
+                if tag != 0 || !node.tower[level].cas_shared(next, succ, Release) {
+                    break 'build;
+                }
+                node.refs.fetch_add(1, Relaxed);
+                if self.tower(pos.preds[level])[level].cas_shared(succ, Some(node), SeqCst) {
+                    break;
+                }
+                node.refs.fetch_sub(1, Relaxed);
+                pos = self.search(|k| k < &node.key, &guard);
+            }
+        }
+
+        // If the node was removed while we were linking it, the search of
+        // `remove_node` may have missed the levels we linked last; pairs with
+        // the fence there.
+        atomic::fence(SeqCst);
+        if node.tower[0].load_tagged(Relaxed, &guard).1 != 0 {
+            self.search(|k| k < &node.key, &guard);
+        }
+        unsafe { self.release(node, &guard) }
+    }
+
+    /// Remove the entry under `key`.
+    ///
+    /// Returns `true` if this call removed an entry.
+    pub fn remove<Q>(&self, key: &Q) -> bool where K: Borrow<Q>, Q: ?Sized + Ord {
+        let guard = epoch::pin();
+        let pos = self.search(|k| k.borrow() < key, &guard);
+        match pos.succs[0] {
+            Some(node) if node.key.borrow() == key => self.remove_node(node, &guard),
+            _ => false,
+        }
+    }
+
+    /// Look up the entry under `key`.
+    pub fn get<'a, Q>(&'a self, key: &Q, guard: &'a Guard) -> Option<Entry<'a, K, V>>
+        where K: Borrow<Q>, Q: ?Sized + Ord
+    {
+        let pos = self.search(|k| k.borrow() < key, guard);
+        pos.succs[0].and_then(|node| {
+            let node: &'a Node<K, V> = node.as_ref();
+            if node.key.borrow() == key { Some(Entry { node: node }) } else { None }
+        })
+    }
+
+    /// Check whether there is an entry under `key`.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool where K: Borrow<Q>, Q: ?Sized + Ord {
+        let guard = epoch::pin();
+        self.get(key, &guard).is_some()
+    }
+
+    /// The entry with the smallest key.
+    pub fn first<'a>(&'a self, guard: &'a Guard) -> Option<Entry<'a, K, V>> {
+        self.iter(guard).next()
+    }
+
+    /// The entry with the largest key.
+    pub fn last<'a>(&'a self, guard: &'a Guard) -> Option<Entry<'a, K, V>> {
+        let pos = self.search(|_| true, guard);
+        pos.preds[0].map(|node| Entry { node: node.as_ref() })
+    }
+
+    /// Check if this map is empty.
+    pub fn is_empty(&self) -> bool {
+        let guard = epoch::pin();
+        self.first(&guard).is_none()
+    }
+
+    /// Iterate over all entries in ascending key order.
+    ///
+    /// Entries inserted or removed during the iteration may or may not be
+    /// seen.
+    pub fn iter<'a>(&'a self, guard: &'a Guard) -> Iter<'a, K, V> {
+        Iter {
+            guard: guard,
+            next: self.head[0].load(Acquire, guard),
+        }
+    }
+
+    /// Iterate over the entries with keys between `lower` and `upper`, in
+    /// ascending order.
+    pub fn range<'a, Q>(&'a self, lower: Bound<&Q>, upper: Bound<&'a Q>,
+                                guard: &'a Guard) -> Range<'a, Q, K, V>
+        where K: Borrow<Q>, Q: ?Sized + Ord
+    {
+        let next = match lower {
+            Bound::Included(key) => self.search(|k| k.borrow() < key, guard).succs[0],
+            Bound::Excluded(key) => self.search(|k| k.borrow() <= key, guard).succs[0],
+            Bound::Unbounded => self.head[0].load(Acquire, guard),
+        };
+        Range {
+            iter: Iter {
+                guard: guard,
+                next: next,
+            },
+            upper: upper,
+        }
+    }
+
+    fn tower<'a>(&'a self, node: Option<Shared<'a, Node<K, V>>>) -> &'a [Atomic<Node<K, V>>] {
+        match node {
+            Some(node) => {
+                let node: &'a Node<K, V> = node.as_ref();
+                &node.tower
+            }
+            None => &self.head,
+        }
+    }
+
+    // Find where the first key for which `before` is false fits, unlinking
+    // removed nodes along the way.
+    fn search<'a, F>(&'a self, before: F, guard: &'a Guard) -> Position<'a, K, V>
+        where F: Fn(&K) -> bool
+    {
+        'retry: loop {
+            let mut pos = Position {
+                preds: [None; MAX_HEIGHT],
+                succs: [None; MAX_HEIGHT],
+            };
+            let mut pred = None;
+            for level in (0..MAX_HEIGHT).rev() {
+                let (mut curr, tag) = self.tower(pred)[level].load_tagged(Acquire, guard);
+                if tag != 0 {
+                    // `pred` is being removed
+                    continue 'retry;
+                }
+                while let Some(c) = curr {
+                    let (succ, tag) = c.tower[level].load_tagged(Acquire, guard);
+                    let succ = succ.map(|s| s.with_tag(0));
+                    if tag != 0 {
+                        // `c` is being removed; unlink it at this level
+                        if !self.tower(pred)[level].cas_shared(curr, succ, AcqRel) {
+                            continue 'retry;
+                        }
+                        unsafe { self.release(c, guard) }
+                        curr = succ;
+                        continue;
+                    }
+                    if !before(&c.key) {
+                        break;
+                    }
+                    pred = curr;
+                    curr = succ;
+                }
+                pos.preds[level] = pred;
+                pos.succs[level] = curr;
+            }
+            return pos;
+        }
+    }
+
+    // Take `node` out of the map. Returns `false` if somebody else did first.
+    fn remove_node(&self, node: Shared<Node<K, V>>, guard: &Guard) -> bool {
+        for level in (1..node.tower.len()).rev() {
+            node.tower[level].fetch_or(1, AcqRel, guard);
+        }
+        if node.tower[0].fetch_or(1, SeqCst, guard).1 != 0 {
+            return false;
+        }
+
+        // pairs with the fence in `insert`: either we see the levels it has
+        // linked, or it sees our tag and searches again itself
+        atomic::fence(SeqCst);
+        self.search(|k| k < &node.key, guard);
+        true
+    }
+
+    // Take `old` out of the map and put `node` in its place. The bottom level
+    // links `node` right behind `old` and tags `old` in the same CAS, so
+    // lookups go from one straight to the other and never find the key
+    // missing in between. Returns `false` if somebody else removed `old`
+    // first.
+    fn replace_node(&self, old: Shared<Node<K, V>>, node: Shared<Node<K, V>>,
+                    guard: &Guard) -> bool {
+        for level in (1..old.tower.len()).rev() {
+            old.tower[level].fetch_or(1, AcqRel, guard);
+        }
+        node.refs.fetch_add(1, Relaxed);
+        loop {
+            let (succ, tag) = old.tower[0].load_tagged(Acquire, guard);
+            if tag != 0 {
+                node.refs.fetch_sub(1, Relaxed);
+                return false;
+            }
+            node.tower[0].store_shared(succ, Relaxed);
+            if old.tower[0].cas_shared(succ, Some(node.with_tag(1)), SeqCst) {
+                return true;
+            }
+        }
+    }
+
+    // Drop a reference to `node`, freeing it once it is unreachable.
+    unsafe fn release(&self, node: Shared<Node<K, V>>, guard: &Guard) {
+        if node.refs.fetch_sub(1, AcqRel) == 1 {
+            guard.defer_drop(node);
+        }
+    }
+}
+
+impl<K, V> Drop for SkipMap<K, V> {
+    fn drop(&mut self) {
+        // We have exclusive access, so every entry is linked into the bottom
+        // level and removed ones are unlinked everywhere: free whatever is
+        // left right away.
+        let guard = epoch::pin();
+        let mut cur = self.head[0].load(Relaxed, &guard);
+        while let Some(node) = cur {
+            cur = node.tower[0].load(Relaxed, &guard);
+            unsafe { drop(Box::from_raw(node.as_raw())); }
+        }
+    }
+}
+
+impl<K, V> fmt::Debug for SkipMap<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SkipMap {{ ... }}")
+    }
+}
+
+impl<'a, K, V> Entry<'a, K, V> {
+    /// The key of the entry.
+    pub fn key(&self) -> &'a K {
+        &self.node.key
+    }
+
+    /// The value of the entry.
+    pub fn value(&self) -> &'a V {
+        &self.node.value
+    }
+}
+
+impl<'a, K: fmt::Debug, V: fmt::Debug> fmt::Debug for Entry<'a, K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Entry")
+         .field("key", self.key())
+         .field("value", self.value())
+         .finish()
+    }
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = Entry<'a, K, V>;
+
+    fn next(&mut self) -> Option<Entry<'a, K, V>> {
+        while let Some(node) = self.next {
+            let (succ, tag) = node.tower[0].load_tagged(Acquire, self.guard);
+            self.next = succ;
+            if tag == 0 {
+                return Some(Entry { node: node.as_ref() });
+            }
+        }
+        None
+    }
+}
+
+impl<'a, K, V> fmt::Debug for Iter<'a, K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Iter {{ ... }}")
+    }
+}
+
+impl<'a, Q, K, V> Iterator for Range<'a, Q, K, V> where K: Borrow<Q>, Q: ?Sized + Ord {
+    type Item = Entry<'a, K, V>;
+
+    fn next(&mut self) -> Option<Entry<'a, K, V>> {
+        let entry = match self.iter.next() {
+            Some(entry) => entry,
+            None => return None,
+        };
+        let below = match self.upper {
+            Bound::Included(key) => entry.key().borrow() <= key,
+            Bound::Excluded(key) => entry.key().borrow() < key,
+            Bound::Unbounded => true,
+        };
+        if below {
+            Some(entry)
+        } else {
+            self.iter.next = None;
+            None
+        }
+    }
+}
+
+impl<'a, Q: ?Sized, K, V> fmt::Debug for Range<'a, Q, K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Range {{ ... }}")
+    }
+}
+
+fn tower<K, V>(height: usize) -> Box<[Atomic<Node<K, V>>]> {
+    (0..height).map(|_| Atomic::null()).collect::<Vec<_>>().into_boxed_slice()
+}
+
+/// A random tower height, each level being kept with probability 1/2.
+fn random_height() -> usize {
+    let height = random_u32().trailing_zeros() as usize + 1;
+    if height > MAX_HEIGHT { MAX_HEIGHT } else { height }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::Bound::{Included, Excluded, Unbounded};
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::thread;
+
+    use mem::epoch;
+    use scope;
+    use super::*;
+
+    #[test]
+    fn insert_get_remove() {
+        let m = SkipMap::new();
+        assert!(m.is_empty());
+        m.insert(3, "c");
+        m.insert(1, "a");
+        m.insert(2, "b");
+        assert!(!m.is_empty());
+
+        let guard = epoch::pin();
+        assert_eq!(*m.get(&1, &guard).unwrap().value(), "a");
+        assert_eq!(*m.get(&3, &guard).unwrap().value(), "c");
+        assert!(m.get(&4, &guard).is_none());
+
+        m.insert(2, "B");
+        assert_eq!(*m.get(&2, &guard).unwrap().value(), "B");
+
+        assert!(m.remove(&2));
+        assert!(!m.remove(&2));
+        assert!(!m.contains_key(&2));
+        assert!(m.contains_key(&1));
+        assert!(m.remove(&1));
+        assert!(m.remove(&3));
+        assert!(m.is_empty());
+    }
+
+    #[test]
+    fn ordered_iteration() {
+        let m = SkipMap::new();
+        for i in (0..1000).rev() {
+            m.insert(i * 2, i);
+        }
+        for i in 0..500 {
+            m.remove(&(i * 4));
+        }
+
+        let guard = epoch::pin();
+        let keys = m.iter(&guard).map(|e| *e.key()).collect::<Vec<_>>();
+        assert_eq!(keys, (0..500).map(|i| i * 4 + 2).collect::<Vec<_>>());
+        assert_eq!(*m.first(&guard).unwrap().key(), 2);
+        assert_eq!(*m.last(&guard).unwrap().key(), 1998);
+    }
+
+    #[test]
+    fn range() {
+        let m = SkipMap::new();
+        for i in 0..10 {
+            m.insert(i, ());
+        }
+
+        let guard = epoch::pin();
+        let keys = |r: Range<i32, i32, ()>| r.map(|e| *e.key()).collect::<Vec<_>>();
+        assert_eq!(keys(m.range(Included(&3), Excluded(&6), &guard)), [3, 4, 5]);
+        assert_eq!(keys(m.range(Excluded(&3), Included(&6), &guard)), [4, 5, 6]);
+        assert_eq!(keys(m.range(Unbounded, Excluded(&2), &guard)), [0, 1]);
+        assert_eq!(keys(m.range(Included(&8), Unbounded, &guard)), [8, 9]);
+        assert_eq!(keys(m.range(Included(&20), Unbounded, &guard)), []);
+        assert_eq!(keys(m.range(Included(&5), Excluded(&5), &guard)), []);
+    }
+
+    #[test]
+    fn first_last_empty() {
+        let m: SkipMap<i32, i32> = SkipMap::new();
+        let guard = epoch::pin();
+        assert!(m.first(&guard).is_none());
+        assert!(m.last(&guard).is_none());
+        assert_eq!(m.iter(&guard).count(), 0);
+    }
+
+    #[test]
+    fn entry_outlives_removal() {
+        let m = SkipMap::new();
+        m.insert(1, String::from("one"));
+
+        let guard = epoch::pin();
+        let e = m.get(&1, &guard).unwrap();
+        scope(|scope| {
+            scope.spawn(|| {
+                assert!(m.remove(&1));
+                for _ in 0..1000 {
+                    // give the epoch plenty of chances to advance
+                    drop(epoch::pin());
+                }
+            });
+        });
+        assert!(m.get(&1, &guard).is_none());
+        assert_eq!(e.value(), "one");
+    }
+
+    #[test]
+    fn drop_remaining() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        struct Elem;
+        impl Drop for Elem {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, SeqCst);
+            }
+        }
+
+        let m = SkipMap::new();
+        for i in 0..100 {
+            m.insert(i, Elem);
+        }
+        drop(m);
+        assert_eq!(DROPS.load(SeqCst), 100);
+    }
+
+    #[test]
+    fn replace_never_hides_key() {
+        const THREADS: usize = 2;
+        const COUNT: usize = 20000;
+        let m = SkipMap::new();
+        for k in 0..4 {
+            m.insert(k, 0);
+        }
+        let done = AtomicUsize::new(0);
+
+        scope(|scope| {
+            for _ in 0..THREADS {
+                let m = &m;
+                let done = &done;
+                scope.spawn(move || {
+                    for i in 0..COUNT {
+                        m.insert(i % 4, i);
+                    }
+                    done.fetch_add(1, SeqCst);
+                });
+            }
+            let m = &m;
+            let done = &done;
+            scope.spawn(move || {
+                while done.load(SeqCst) < THREADS {
+                    let guard = epoch::pin();
+                    for k in 0..4 {
+                        assert!(m.get(&k, &guard).is_some());
+                    }
+                    assert_eq!(m.iter(&guard).count(), 4);
+                    thread::yield_now();
+                }
+            });
+        });
+
+        let guard = epoch::pin();
+        assert_eq!(m.iter(&guard).map(|e| *e.key()).collect::<Vec<_>>(), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn concurrent_insert_remove() {
+        const THREADS: usize = 4;
+        const COUNT: usize = 5000;
+        let m = SkipMap::new();
+
+        scope(|scope| {
+            for t in 0..THREADS {
+                let m = &m;
+                scope.spawn(move || {
+                    for i in 0..COUNT {
+                        m.insert(i * THREADS + t, t);
+                    }
+                    // remove the odd keys, racing with the other threads
+                    for i in 0..COUNT {
+                        let k = i * THREADS + t;
+                        if k % 2 == 1 {
+                            assert!(m.remove(&k));
+                        }
+                    }
+                });
+                scope.spawn(move || {
+                    // overwrite the even keys with the same value
+                    for i in 0..COUNT {
+                        let k = i * THREADS + t;
+                        if k % 2 == 0 {
+                            m.insert(k, t);
+                        }
+                    }
+                });
+            }
+        });
+
+        let guard = epoch::pin();
+        let entries = m.iter(&guard).map(|e| (*e.key(), *e.value())).collect::<Vec<_>>();
+        let expected = (0..COUNT * THREADS)
+            .filter(|k| k % 2 == 0)
+            .map(|k| (k, k % THREADS))
+            .collect::<Vec<_>>();
+        assert_eq!(entries, expected);
+    }
+}
@@ -1,7 +1,7 @@
 use std::sync::atomic::Ordering::{Acquire, Release, Relaxed};
 use std::ptr;
 
-use mem::epoch::{self, Atomic, Owned};
+use mem::epoch::{self, Atomic, Guard, Owned};
 
 /// Treiber's lock-free stack.
 ///
@@ -11,12 +11,23 @@ pub struct TreiberStack<T> {
     head: Atomic<Node<T>>,
 }
 
+/// An owning iterator over the elements taken off a `TreiberStack` by
+/// `pop_all`, from top to bottom.
+///
+/// The current thread stays pinned for as long as the iterator is alive.
 #[derive(Debug)]
-struct Node<T> {
-    data: T,
+pub struct PopAll<T> {
+    guard: Guard,
     next: Atomic<Node<T>>,
 }
 
+// Also used by `EliminationStack`.
+#[derive(Debug)]
+pub struct Node<T> {
+    pub data: T,
+    pub next: Atomic<Node<T>>,
+}
+
 impl<T> TreiberStack<T> {
     /// Create a new, empty stack.
     pub fn new() -> TreiberStack<T> {
@@ -40,6 +51,41 @@ impl<T> TreiberStack<T> {
         }
     }
 
+    /// Push all elements of `iter` on top of the stack, the last one ending
+    /// up on top.
+    ///
+    /// The elements are linked up privately and published all at once, so
+    /// other threads see either none or all of them.
+    pub fn push_all<I: IntoIterator<Item = T>>(&self, iter: I) {
+        // Nodes stay owned until they are linked up, so that they are freed if
+        // `iter` panics.
+        let nodes = iter.into_iter().map(|t| {
+            Owned::new(Node {
+                data: t,
+                next: Atomic::null(),
+            })
+        }).collect::<Vec<_>>();
+        if nodes.is_empty() {
+            return;
+        }
+
+        let guard = epoch::pin();
+        let mut nodes = nodes.into_iter().map(|n| n.into_shared(&guard));
+        let bottom = nodes.next().unwrap();
+        let top = nodes.fold(bottom, |top, n| {
+            n.next.store_shared(Some(top), Relaxed);
+            n
+        });
+
+        loop {
+            let head = self.head.load(Relaxed, &guard);
+            bottom.next.store_shared(head, Relaxed);
+            if self.head.cas_shared(head, Some(top), Release) {
+                break;
+            }
+        }
+    }
+
     /// Attempt to pop the top element of the stack.
     /// **Deprecated method**, use try_pop
     ///
@@ -70,6 +116,21 @@ impl<T> TreiberStack<T> {
         }
     }
 
+    /// Take all elements off the stack at once.
+    ///
+    /// The returned iterator yields them from top to bottom; whatever it does
+    /// not yield is dropped along with it. It keeps the current thread pinned
+    /// until then, so that the elements can be taken without pinning again.
+    pub fn pop_all(&self) -> PopAll<T> {
+        let guard = epoch::pin();
+        let next = Atomic::null();
+        next.store_shared(self.head.swap_shared(None, Acquire, &guard), Relaxed);
+        PopAll {
+            guard: guard,
+            next: next,
+        }
+    }
+
     /// Check if this queue is empty.
     pub fn is_empty(&self) -> bool {
         let guard = epoch::pin();
@@ -77,8 +138,48 @@ impl<T> TreiberStack<T> {
     }
 }
 
+impl<T> Drop for TreiberStack<T> {
+    fn drop(&mut self) {
+        // We have exclusive access, so no other thread can be looking at the
+        // nodes still in the stack: free them right away, along with their data.
+        let guard = epoch::pin();
+        let mut cur = self.head.swap(None, Relaxed, &guard);
+        while let Some(node) = cur {
+            cur = node.next.load(Relaxed, &guard);
+            unsafe { drop(Box::from_raw(node.as_raw())); }
+        }
+    }
+}
+
+impl<T> Iterator for PopAll<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        // The nodes belong to us now, but concurrent `try_pop`s may still be
+        // reading the ones that were at the top, so free them through the
+        // epoch scheme rather than right away.
+        let guard = &self.guard;
+        self.next.load(Relaxed, guard).map(|node| unsafe {
+            self.next.store_shared(node.next.load(Relaxed, guard), Relaxed);
+            guard.unlinked(node);
+            ptr::read(&(*node).data)
+        })
+    }
+}
+
+impl<T> Drop for PopAll<T> {
+    fn drop(&mut self) {
+        while let Some(_) = self.next() {}
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::SeqCst;
+
+    use scope;
     use super::*;
 
     #[test]
@@ -95,4 +196,119 @@ mod test {
         q.push(25);
         assert!(!q.is_empty());
     }
+
+    #[test]
+    fn drop_remaining() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        struct Elem;
+        impl Drop for Elem {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, SeqCst);
+            }
+        }
+
+        let s = TreiberStack::new();
+        for _ in 0..100 {
+            s.push(Elem);
+        }
+        for _ in 0..40 {
+            drop(s.try_pop().unwrap());
+        }
+        assert_eq!(DROPS.load(SeqCst), 40);
+        drop(s);
+        assert_eq!(DROPS.load(SeqCst), 100);
+    }
+
+    #[test]
+    fn push_all_pop_all() {
+        let s = TreiberStack::new();
+        s.push_all(Vec::new());
+        assert!(s.is_empty());
+        assert_eq!(s.pop_all().next(), None);
+
+        s.push(0);
+        s.push_all(1..4);
+        assert_eq!(s.try_pop(), Some(3));
+        s.push_all(vec![4, 5]);
+        assert_eq!(s.pop_all().collect::<Vec<_>>(), [5, 4, 2, 1, 0]);
+        assert!(s.is_empty());
+    }
+
+    #[test]
+    fn pop_all_drops_rest() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        struct Elem;
+        impl Drop for Elem {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, SeqCst);
+            }
+        }
+
+        let s = TreiberStack::new();
+        s.push_all((0..100).map(|_| Elem));
+        let mut all = s.pop_all();
+        for _ in 0..40 {
+            drop(all.next().unwrap());
+        }
+        assert_eq!(DROPS.load(SeqCst), 40);
+        drop(all);
+        assert_eq!(DROPS.load(SeqCst), 100);
+        assert!(s.is_empty());
+    }
+
+    #[test]
+    fn push_all_panicking_iter() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        struct Elem;
+        impl Drop for Elem {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, SeqCst);
+            }
+        }
+
+        let s = TreiberStack::new();
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            s.push_all((0..10).map(|i| if i < 5 { Elem } else { panic!() }));
+        }));
+        assert!(res.is_err());
+        assert_eq!(DROPS.load(SeqCst), 5);
+        assert!(s.is_empty());
+    }
+
+    #[test]
+    fn push_all_pop_all_mpmc() {
+        const BATCHES: usize = 1000;
+        const BATCH: usize = 10;
+        const THREADS: usize = 4;
+        let s = TreiberStack::new();
+        let popped = AtomicUsize::new(0);
+
+        scope(|scope| {
+            for t in 0..THREADS {
+                let s = &s;
+                let popped = &popped;
+                scope.spawn(move || {
+                    for b in 0..BATCHES {
+                        let first = (t * BATCHES + b) * BATCH;
+                        s.push_all(first..first + BATCH);
+                    }
+                });
+                scope.spawn(move || {
+                    while popped.load(SeqCst) < THREADS * BATCHES * BATCH {
+                        let all = s.pop_all().collect::<Vec<_>>();
+                        // batches are published whole, so they come out
+                        // whole and reversed
+                        for batch in all.chunks(BATCH) {
+                            assert_eq!(batch[0] % BATCH, BATCH - 1);
+                            for w in batch.windows(2) {
+                                assert_eq!(w[0], w[1] + 1);
+                            }
+                        }
+                        popped.fetch_add(all.len(), SeqCst);
+                    }
+                });
+            }
+        });
+        assert!(s.is_empty());
+    }
 }
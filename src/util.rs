@@ -0,0 +1,57 @@
+// Small helpers shared across the crate.
+
+use std::cell::Cell;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+
+/// A pseudo-random number from a per-thread xorshift generator.
+///
+/// Good enough to spread threads over slots or pick tower heights; not for
+/// anything that needs real randomness.
+pub fn random_u32() -> u32 {
+    static SEED: AtomicUsize = AtomicUsize::new(0);
+    thread_local!(static RNG: Cell<u32> = Cell::new({
+        // distinct, non-zero seeds for every thread
+        let seed = SEED.fetch_add(0x9e3779b9, Relaxed) as u32;
+        if seed == 0 { 0x9e3779b9 } else { seed }
+    }));
+
+    RNG.with(|rng| {
+        let mut x = rng.get();
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        rng.set(x);
+        x
+    })
+}
+
+/// A pseudo-random number in `0..n`.
+///
+/// # Panics
+///
+/// Panics if `n` is zero.
+pub fn random(n: usize) -> usize {
+    random_u32() as usize % n
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn in_range() {
+        for n in 1..100 {
+            assert!(random(n) < n);
+        }
+    }
+
+    #[test]
+    fn distinct_seeds() {
+        let a = thread::spawn(|| (0..4).map(|_| random_u32()).collect::<Vec<_>>());
+        let b = thread::spawn(|| (0..4).map(|_| random_u32()).collect::<Vec<_>>());
+        assert!(a.join().unwrap() != b.join().unwrap());
+    }
+}